@@ -18,11 +18,11 @@ async fn main() -> Result<()> {
         match event {
             Event::NewOutput(output) => {
                 let frame_handle = state
-                    .access(|s| {
+                    .access(|s| -> Result<_> {
                         debug!("capture frame on output: {:?}", output);
-                        let res = s.screencopy_state().capture_output(&output, &qh);
+                        let res = s.screencopy_state().capture_output(&output, qh)?;
                         conn.flush()?;
-                        res
+                        Ok(res)
                     })?
                     .await??;
                 debug!("capture complete, getting buffer data");
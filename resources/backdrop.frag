@@ -0,0 +1,47 @@
+#version 450
+
+layout(location=0) in vec2 v_position;
+layout(location=0) out vec4 f_color;
+
+// A copy of the frame drawn so far, sampled when blurring.
+layout(set = 0, binding = 0) uniform texture2D t_frame;
+layout(set = 0, binding = 1) uniform sampler s_frame;
+layout(set = 0, binding = 2) uniform Uniforms {
+    mat4 iTransform;
+    vec2 iHalfSize;
+    vec2 iResolution;
+    float iOpacity;
+    float iRadius;
+    uint iBlur;
+};
+
+const float DIM = 0.5;
+const int BLUR_TAPS = 6;
+const float BLUR_STEP = 3.0;
+const float BLUR_SIGMA = 8.0;
+
+void main() {
+    // Signed distance to the rounded rectangle, antialiased over a pixel.
+    vec2 q = abs(v_position) - iHalfSize + iRadius;
+    float dist = length(max(q, 0.0)) + min(max(q.x, q.y), 0.0) - iRadius;
+    float coverage = clamp(0.5 - dist, 0.0, 1.0) * iOpacity;
+
+    if (iBlur == 0u) {
+        f_color = vec4(0.0, 0.0, 0.0, DIM * coverage);
+        return;
+    }
+
+    vec2 uv = gl_FragCoord.xy / iResolution;
+    vec3 sum = vec3(0.0);
+    float total = 0.0;
+    for (int x = -BLUR_TAPS; x <= BLUR_TAPS; x++) {
+        for (int y = -BLUR_TAPS; y <= BLUR_TAPS; y++) {
+            vec2 offset = vec2(x, y) * BLUR_STEP;
+            float weight = exp(-dot(offset, offset) / (2.0 * BLUR_SIGMA * BLUR_SIGMA));
+            sum += weight * texture(sampler2D(t_frame, s_frame), uv + offset / iResolution).rgb;
+            total += weight;
+        }
+    }
+    // Dim slightly as well, so a light icon stands out from a bright background.
+    f_color = vec4(sum / total * (1.0 - DIM / 2.0), coverage);
+}
@@ -0,0 +1,25 @@
+#version 450
+
+const vec2 positions[4] = vec2[4](
+    vec2(-1.0, -1.0),
+    vec2(-1.0, 1.0),
+    vec2(1.0, -1.0),
+    vec2(1.0, 1.0)
+);
+
+// Offset from the centre of the backdrop, in pixels.
+layout(location=0) out vec2 v_position;
+
+layout(set = 0, binding = 2) uniform Uniforms {
+    mat4 iTransform;
+    vec2 iHalfSize;
+    vec2 iResolution;
+    float iOpacity;
+    float iRadius;
+    uint iBlur;
+};
+
+void main() {
+    gl_Position = iTransform * vec4(positions[gl_VertexIndex], 0.0, 1.0);
+    v_position = positions[gl_VertexIndex] * iHalfSize;
+}
@@ -0,0 +1,9 @@
+#version 450
+
+layout(location=0) out vec2 v_tex_coords;
+
+void main() {
+    // A triangle covering the whole target.
+    v_tex_coords = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    gl_Position = vec4(v_tex_coords.x * 2.0 - 1.0, 1.0 - v_tex_coords.y * 2.0, 0.0, 1.0);
+}
@@ -0,0 +1,14 @@
+#version 450
+
+layout(location=0) out vec4 f_color;
+
+layout(set = 0, binding = 2) uniform Uniforms {
+    mat4 iTransform;
+    vec4 iColors[2];
+    vec2 iResolution;
+};
+
+void main() {
+    float t = gl_FragCoord.y / iResolution.y;
+    f_color = mix(iColors[0], iColors[1], t);
+}
@@ -5,7 +5,15 @@ layout(location=0) out vec4 f_color;
 
 layout(set = 0, binding = 0) uniform texture2D t_screenshot;
 layout(set = 0, binding = 1) uniform sampler s_screenshot;
+layout(set = 0, binding = 2) uniform Uniforms {
+    mat4 iTransform;
+    float iOpacity;
+    float iIntro;
+    vec4 iTint;
+};
 
 void main() {
     f_color = texture(sampler2D(t_screenshot, s_screenshot), v_tex_coords);
+    f_color.rgb *= iTint.rgb;
+    f_color.a *= iOpacity * smoothstep(0.0, 1.0, iIntro);
 }
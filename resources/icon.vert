@@ -15,14 +15,18 @@ const vec2 tex_positions[4] = vec2[4](
 );
 
 const float SCALE = 1.0;
+const float INTRO_SCALE = 0.8;
 
 layout(location=0) out vec2 v_tex_coords;
 
 layout(set = 0, binding = 2) uniform Uniforms {
     mat4 iTransform;
+    float iOpacity;
+    float iIntro;
 };
 
 void main() {
-    gl_Position = SCALE * iTransform * vec4(positions[gl_VertexIndex], 0.0, 1.0);
+    float scale = mix(INTRO_SCALE, 1.0, smoothstep(0.0, 1.0, iIntro));
+    gl_Position = SCALE * iTransform * vec4(scale * positions[gl_VertexIndex], 0.0, 1.0);
     v_tex_coords = tex_positions[gl_VertexIndex];
 }
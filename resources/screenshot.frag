@@ -0,0 +1,14 @@
+#version 450
+
+layout(location=0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_screenshot;
+layout(set = 0, binding = 1) uniform sampler s_screenshot;
+layout(set = 0, binding = 2) uniform Uniforms {
+    mat4 iTransform;
+};
+
+void main() {
+    vec4 ouv = iTransform * vec4(gl_FragCoord.xy, 0.0, 1.0);
+    f_color = texture(sampler2D(t_screenshot, s_screenshot), ouv.xy / ouv.w);
+}
@@ -1,40 +1,351 @@
-use anyhow::*;
+use std::ffi::{CStr, CString};
+use std::future::Future;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use futures::future::LocalBoxFuture;
+
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
 const PAM_SERVICE: &str = env!("PAM_SERVICE");
 const PASSWORD_SIZE: usize = 256;
+/// Where Linux-PAM looks for service configuration, in order.
+const PAM_CONFIG_DIRS: &[&str] = &["/etc/pam.d", "/usr/lib/pam.d", "/usr/share/pam.d"];
+
+/// Failures setting up authentication or checking a password.
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("Failed to get username")]
+    NoUsername,
+    #[error("Failed to parse username {0:?}")]
+    InvalidUsername(std::ffi::OsString),
+    #[error("Failed to initialize PAM")]
+    PamInit(#[source] pam::PamError),
+    #[error("PAM auth failed")]
+    Pam(#[source] pam::PamError),
+    #[error("PAM conversation thread stopped")]
+    ConversationLost,
+    #[error("PAM did not answer in time")]
+    Timeout,
+    #[error("PAM service {0:?} is not configured in any of {dirs:?}", dirs = PAM_CONFIG_DIRS)]
+    NoService(&'static str),
+}
+
+type Result<T, E = AuthError> = std::result::Result<T, E>;
+
+/// Where an authentication attempt got to after a response.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthStep {
+    /// Authentication succeeded.
+    Done,
+    /// PAM asked another question, e.g. for a one-time code, to be answered with the next
+    /// response.
+    Prompt(String),
+}
+
+/// Find the configuration of the PAM service passwords are checked with. Without one, PAM falls
+/// back to the `other` service, which usually denies everything.
+pub fn find_service() -> Result<std::path::PathBuf> {
+    PAM_CONFIG_DIRS
+        .iter()
+        .map(|dir| std::path::Path::new(dir).join(PAM_SERVICE))
+        .find(|path| path.is_file())
+        .ok_or(AuthError::NoService(PAM_SERVICE))
+}
 
 pub trait AuthenticatorBackend {
-    fn authenticate(&mut self, password: &str) -> Result<()>;
+    /// Answer the current question, starting a new attempt with the password if none is under
+    /// way. PAM can take seconds to answer, so the result comes back asynchronously, and only one
+    /// response may be awaited at a time.
+    fn respond(&mut self, response: &str) -> LocalBoxFuture<'static, Result<AuthStep>>;
+
+    /// Abandon an attempt waiting on another prompt, so the next response starts a new one.
+    fn cancel(&mut self) {}
+}
+
+/// Messages from the PAM conversation thread.
+enum ConvEvent {
+    /// PAM wants an answer.
+    Prompt(String),
+    /// Informational or error text from PAM, shown with the next prompt.
+    Message(String),
+    Done(Result<(), pam::PamError>),
+}
+
+/// Relays PAM's questions to the UI thread and its answers back. An answer of `None` abandons the
+/// attempt.
+struct ChannelConv {
+    username: String,
+    events: UnboundedSender<ConvEvent>,
+    responses: mpsc::Receiver<Option<String>>,
+    /// The password typed before the attempt started, for the first prompt.
+    password: Option<String>,
 }
 
+impl ChannelConv {
+    fn prompt(&mut self, msg: &CStr) -> std::result::Result<CString, ()> {
+        if let Some(password) = self.password.take() {
+            return CString::new(password).map_err(|_| ());
+        }
+        let msg = msg.to_string_lossy().into_owned();
+        self.events.send(ConvEvent::Prompt(msg)).map_err(|_| ())?;
+        match self.responses.recv() {
+            Ok(Some(response)) => CString::new(response).map_err(|_| ()),
+            _ => Err(()),
+        }
+    }
+}
+
+impl pam::Converse for ChannelConv {
+    fn prompt_echo(&mut self, msg: &CStr) -> std::result::Result<CString, ()> {
+        self.prompt(msg)
+    }
+
+    fn prompt_blind(&mut self, msg: &CStr) -> std::result::Result<CString, ()> {
+        self.prompt(msg)
+    }
+
+    fn info(&mut self, msg: &CStr) {
+        info!("PAM: {}", msg.to_string_lossy());
+        let _ = self
+            .events
+            .send(ConvEvent::Message(msg.to_string_lossy().into_owned()));
+    }
+
+    fn error(&mut self, msg: &CStr) {
+        warn!("PAM: {}", msg.to_string_lossy());
+        let _ = self
+            .events
+            .send(ConvEvent::Message(msg.to_string_lossy().into_owned()));
+    }
+
+    fn username(&self) -> &str {
+        &self.username
+    }
+}
+
+/// Runs PAM on its own thread, as its conversation blocks until every prompt is answered, which
+/// for multi-step stacks means waiting for the user to type again.
 pub struct PamAuthenticatorBackend {
+    /// Shared with the response being awaited, if any.
+    conversation: Rc<tokio::sync::Mutex<Conversation>>,
+    /// How long PAM may take to answer before waiting again or giving up.
+    timeout: Option<Duration>,
+    /// How many more times to wait `timeout` for an answer before giving up on the attempt.
+    retries: u32,
+}
+
+/// The thread running PAM, and where an attempt with it got to.
+struct Conversation {
     username: String,
-    auth: pam::Authenticator<'static, pam::PasswordConv>,
+    events: UnboundedReceiver<ConvEvent>,
+    responses: mpsc::Sender<Option<String>>,
+    /// An attempt is waiting on an answer to a prompt the UI was given.
+    in_conversation: bool,
+    /// An attempt was cancelled, and must finish before PAM reads another answer.
+    abandoned: bool,
 }
 
 impl PamAuthenticatorBackend {
-    pub fn new() -> Result<Self> {
+    /// Start PAM, waiting up to `timeout` for each answer, `retries` more times, before giving up
+    /// on an attempt, e.g. for network-backed modules that sometimes stall.
+    pub fn new(timeout: Option<Duration>, retries: u32) -> Result<Self> {
         let username = users::get_current_username()
-            .context("Failed to get username")?
+            .ok_or(AuthError::NoUsername)?
             .into_string()
-            .map_err(|oss| anyhow!("Failed to parse username {:?}", oss))?;
+            .map_err(AuthError::InvalidUsername)?;
         info!("My username: {}", username);
 
-        let auth =
-            pam::Authenticator::with_password(PAM_SERVICE).context("Failed to initialize PAM")?;
+        let (events, responses) = spawn(username.clone())?;
+        Ok(Self {
+            conversation: Rc::new(tokio::sync::Mutex::new(Conversation {
+                username,
+                events,
+                responses,
+                in_conversation: false,
+                abandoned: false,
+            })),
+            timeout,
+            retries,
+        })
+    }
+}
+
+/// Start a thread running PAM for `username`, returning its events and where to send its
+/// responses.
+fn spawn(username: String) -> Result<(UnboundedReceiver<ConvEvent>, mpsc::Sender<Option<String>>)> {
+    let (events_tx, events) = tokio::sync::mpsc::unbounded_channel();
+    let (responses, responses_rx) = mpsc::channel();
+    let (init_tx, init_rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let conv = ChannelConv {
+            username,
+            events: events_tx.clone(),
+            responses: responses_rx,
+            password: None,
+        };
+        // PAM handles can't move between threads, so this one lives here for good.
+        let mut auth = match pam::Authenticator::with_handler(PAM_SERVICE, conv) {
+            Ok(auth) => {
+                let _ = init_tx.send(Ok(()));
+                auth
+            }
+            Err(e) => {
+                let _ = init_tx.send(Err(e));
+                return;
+            }
+        };
+        // Each attempt starts when the UI sends the password.
+        loop {
+            let password = match auth.get_handler().responses.recv() {
+                Ok(Some(password)) => password,
+                // Left over from abandoning an attempt.
+                Ok(None) => continue,
+                Err(_) => return,
+            };
+            auth.get_handler().password = Some(password);
+            let result = auth.authenticate();
+            auth.get_handler().password = None;
+            if events_tx.send(ConvEvent::Done(result)).is_err() {
+                return;
+            }
+        }
+    });
+    init_rx
+        .recv()
+        .map_err(|_| AuthError::ConversationLost)?
+        .map_err(AuthError::PamInit)?;
+    Ok((events, responses))
+}
 
-        Ok(Self { username, auth })
+/// Run `future`, failing with `AuthError::Timeout` if it takes longer than `timeout`.
+async fn within<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .unwrap_or(Err(AuthError::Timeout)),
+        None => future.await,
+    }
+}
+
+impl Conversation {
+    /// Abandon a stalled thread, which can't be interrupted, to exit when it finds nobody
+    /// listening, and start over with a new one.
+    fn restart(&mut self) -> Result<()> {
+        self.in_conversation = false;
+        self.abandoned = false;
+        let (events, responses) = spawn(self.username.clone())?;
+        self.events = events;
+        self.responses = responses;
+        Ok(())
+    }
+
+    async fn respond(
+        &mut self,
+        response: String,
+        timeout: Option<Duration>,
+        mut retries: u32,
+    ) -> Result<AuthStep> {
+        if self.abandoned && within(timeout, self.finish_abandoned()).await.is_err() {
+            warn!("Cancelled PAM attempt did not finish, restarting PAM");
+            self.restart()?;
+        }
+        self.responses
+            .send(Some(response))
+            .map_err(|_| AuthError::ConversationLost)?;
+        // A stalled attempt is waited on again rather than retried, as it may yet finish, and
+        // sending the password again could count twice against limits like pam_faillock's.
+        let mut messages = vec![];
+        loop {
+            match within(timeout, self.reply(&mut messages)).await {
+                Err(AuthError::Timeout) if retries > 0 => {
+                    retries -= 1;
+                    warn!("PAM did not answer in time, waiting again");
+                }
+                Err(AuthError::Timeout) => {
+                    self.restart()?;
+                    return Err(AuthError::Timeout);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Wait for PAM's next question or the result of the attempt, collecting its messages.
+    async fn reply(&mut self, messages: &mut Vec<String>) -> Result<AuthStep> {
+        loop {
+            match self
+                .events
+                .recv()
+                .await
+                .ok_or(AuthError::ConversationLost)?
+            {
+                ConvEvent::Message(message) => messages.push(message),
+                ConvEvent::Prompt(prompt) => {
+                    self.in_conversation = true;
+                    messages.push(prompt);
+                    return Ok(AuthStep::Prompt(messages.join("\n")));
+                }
+                ConvEvent::Done(result) => {
+                    self.in_conversation = false;
+                    return result.map(|()| AuthStep::Done).map_err(AuthError::Pam);
+                }
+            }
+        }
+    }
+
+    /// Refuse a cancelled attempt's questions until PAM gives up on it.
+    async fn finish_abandoned(&mut self) -> Result<()> {
+        loop {
+            match self
+                .events
+                .recv()
+                .await
+                .ok_or(AuthError::ConversationLost)?
+            {
+                ConvEvent::Prompt(_) => {
+                    let _ = self.responses.send(None);
+                }
+                ConvEvent::Message(_) => {}
+                ConvEvent::Done(_) => {
+                    self.abandoned = false;
+                    return Ok(());
+                }
+            }
+        }
     }
 }
 
 impl AuthenticatorBackend for PamAuthenticatorBackend {
-    fn authenticate(&mut self, password: &str) -> Result<()> {
-        self.auth
-            .get_handler()
-            .set_credentials(&self.username, password);
-        self.auth.authenticate().context("PAM auth failed")
+    fn respond(&mut self, response: &str) -> LocalBoxFuture<'static, Result<AuthStep>> {
+        let conversation = self.conversation.clone();
+        let response = response.to_owned();
+        let (timeout, retries) = (self.timeout, self.retries);
+        Box::pin(async move {
+            let mut conversation = conversation.lock().await;
+            conversation.respond(response, timeout, retries).await
+        })
+    }
+
+    fn cancel(&mut self) {
+        // An attempt being checked can't be taken back, only one waiting on a prompt.
+        let mut conversation = match self.conversation.try_lock() {
+            Ok(conversation) => conversation,
+            Err(_) => return,
+        };
+        if !conversation.in_conversation {
+            return;
+        }
+        debug!("Abandoning PAM conversation");
+        conversation.in_conversation = false;
+        conversation.abandoned = true;
+        let _ = conversation.responses.send(None);
     }
 }
 
@@ -53,15 +364,16 @@ impl Default for NullAuthenticatorBackend {
 }
 
 impl AuthenticatorBackend for NullAuthenticatorBackend {
-    fn authenticate(&mut self, _: &str) -> Result<()> {
+    fn respond(&mut self, _: &str) -> LocalBoxFuture<'static, Result<AuthStep>> {
         warn!("null authentication = success");
-        Ok(())
+        Box::pin(futures::future::ready(Ok(AuthStep::Done)))
     }
 }
 
 pub struct Authenticator<'a> {
     backend: &'a mut dyn AuthenticatorBackend,
     password: arrayvec::ArrayString<{ PASSWORD_SIZE }>,
+    last_push: Option<std::time::Instant>,
 }
 
 impl<'a> Authenticator<'a> {
@@ -69,29 +381,79 @@ impl<'a> Authenticator<'a> {
         Ok(Self {
             backend,
             password: arrayvec::ArrayString::new(),
+            last_push: None,
         })
     }
 
     pub fn push(&mut self, c: char) {
         self.password
             .try_push(c)
-            .unwrap_or_else(|_| error!("Overflowed password field"))
+            .unwrap_or_else(|_| error!("Overflowed password field"));
+        self.last_push = Some(std::time::Instant::now());
     }
 
     pub fn pop(&mut self) -> Option<char> {
         self.password.pop()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.password.is_empty()
+    }
+
+    /// Number of characters typed, for drawing an indicator without revealing the password.
+    pub fn len(&self) -> usize {
+        self.password.chars().count()
+    }
+
+    /// When a character was last typed, to animate its appearance.
+    pub fn last_push(&self) -> Option<std::time::Instant> {
+        self.last_push
+    }
+
     pub fn clear(&mut self) {
         debug!("Clearing password buffer");
         self.password.clear()
     }
 
-    pub fn authenticate(&mut self) -> Result<()> {
+    /// Send what was typed, as the password or the answer to the last prompt, clearing it. The
+    /// result must be awaited before authenticating again.
+    pub fn authenticate(&mut self) -> impl Future<Output = Result<AuthStep>> + 'static {
         debug!("Beginning authentication");
-        let result = self.backend.authenticate(&self.password);
+        let result = self.backend.respond(&self.password);
+        self.clear();
+        async move {
+            let result = result.await;
+            info!("Authentication result: {:?}", result);
+            result
+        }
+    }
+
+    /// Give up on an attempt waiting on a prompt, so the next one starts with the password again.
+    pub fn cancel(&mut self) {
         self.clear();
-        info!("Authentication result: {:?}", result);
-        result
+        self.backend.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn within_times_out() {
+        let pending = futures::future::pending::<Result<()>>();
+        let result = within(Some(Duration::from_millis(1)), pending).await;
+        assert!(matches!(result, Err(AuthError::Timeout)));
+        assert!(within(None, async { Ok(()) }).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn authenticate_clears_password() {
+        let mut backend = NullAuthenticatorBackend::new();
+        let mut auth = Authenticator::new(&mut backend).unwrap();
+        auth.push('x');
+        let attempt = auth.authenticate();
+        assert!(auth.is_empty());
+        assert_eq!(attempt.await.unwrap(), AuthStep::Done);
     }
 }
@@ -0,0 +1,143 @@
+use std::path::Path;
+
+use anyhow::*;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+/// Charge of the machine's batteries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Battery {
+    /// From 0 (empty) to 1 (full), averaged over every battery.
+    pub level: f32,
+    /// Whether any battery is charging or being kept full by external power.
+    pub charging: bool,
+}
+
+impl Battery {
+    /// Short description for showing on the lock screen.
+    pub fn label(&self) -> String {
+        let percent = (self.level * 100.0).round();
+        if self.charging {
+            format!("Charging {}%", percent)
+        } else {
+            format!("Battery {}%", percent)
+        }
+    }
+}
+
+/// Read battery charge from sysfs, or `None` if there are no batteries, e.g. on desktops.
+pub fn read() -> Result<Option<Battery>> {
+    read_from(Path::new(POWER_SUPPLY_DIR))
+}
+
+fn read_from(dir: &Path) -> Result<Option<Battery>> {
+    let entries = match std::fs::read_dir(dir) {
+        Result::Ok(entries) => entries,
+        Result::Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Result::Err(e) => return Err(e).context("Failed to list power supplies"),
+    };
+    let mut levels = vec![];
+    let mut charging = false;
+    for entry in entries {
+        let supply = entry.context("Failed to list power supplies")?.path();
+        if read_attribute(&supply, "type").as_deref() != Some("Battery") {
+            continue;
+        }
+        // Peripherals such as wireless mice also report batteries, but don't power the machine.
+        if read_attribute(&supply, "scope").as_deref() == Some("Device") {
+            continue;
+        }
+        let capacity = match read_attribute(&supply, "capacity").map(|c| c.parse::<f32>()) {
+            Some(Result::Ok(capacity)) => capacity,
+            _ => {
+                debug!("no capacity for power supply {:?}", supply);
+                continue;
+            }
+        };
+        levels.push((capacity / 100.0).clamp(0.0, 1.0));
+        charging |= matches!(
+            read_attribute(&supply, "status").as_deref(),
+            Some("Charging" | "Full")
+        );
+    }
+    if levels.is_empty() {
+        return Ok(None);
+    }
+    let level = levels.iter().sum::<f32>() / levels.len() as f32;
+    Ok(Some(Battery { level, charging }))
+}
+
+fn read_attribute(supply: &Path, name: &str) -> Option<String> {
+    std::fs::read_to_string(supply.join(name))
+        .ok()
+        .map(|value| value.trim().to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fake_supply(dir: &Path, name: &str, attributes: &[(&str, &str)]) {
+        let supply = dir.join(name);
+        std::fs::create_dir_all(&supply).unwrap();
+        for (attribute, value) in attributes {
+            std::fs::write(supply.join(attribute), format!("{}\n", value)).unwrap();
+        }
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "shaderlock-battery-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn averages_batteries_and_ignores_other_supplies() {
+        let dir = temp_dir("average");
+        fake_supply(&dir, "AC", &[("type", "Mains"), ("online", "1")]);
+        fake_supply(
+            &dir,
+            "BAT0",
+            &[
+                ("type", "Battery"),
+                ("capacity", "40"),
+                ("status", "Discharging"),
+            ],
+        );
+        fake_supply(
+            &dir,
+            "BAT1",
+            &[
+                ("type", "Battery"),
+                ("capacity", "80"),
+                ("status", "Charging"),
+            ],
+        );
+        fake_supply(
+            &dir,
+            "hidpp_battery_0",
+            &[("type", "Battery"), ("scope", "Device"), ("capacity", "5")],
+        );
+
+        let battery = read_from(&dir).unwrap().unwrap();
+        assert!((battery.level - 0.6).abs() < 1e-6);
+        assert!(battery.charging);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn no_batteries_is_none() {
+        let dir = temp_dir("none");
+        fake_supply(&dir, "AC", &[("type", "Mains"), ("online", "1")]);
+        assert_eq!(read_from(&dir).unwrap(), None);
+        assert_eq!(read_from(&dir.join("missing")).unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
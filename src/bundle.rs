@@ -0,0 +1,107 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::*;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+/// Name of the background shader in a bundle.
+const SHADER_FILE: &str = "shader.frag";
+/// Stem of the icon in a bundle, which may have any extension the icon loader supports.
+const ICON_STEM: &str = "icon";
+
+/// A themed lock screen shipped as one tar archive, holding `shader.frag` and an icon named
+/// `icon` with any supported extension, e.g. `icon.svg`. Either may be left out.
+#[derive(Debug, Default)]
+pub struct Bundle {
+    /// Name and source of the shader.
+    pub shader: Option<(PathBuf, String)>,
+    /// Name and contents of the icon, whose extension tells its format.
+    pub icon: Option<(PathBuf, Vec<u8>)>,
+}
+
+impl Bundle {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open bundle {:?}", path))?;
+        Self::read(file).with_context(|| format!("Failed to read bundle {:?}", path))
+    }
+
+    fn read(reader: impl Read) -> Result<Self> {
+        let mut bundle = Bundle::default();
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = entry.path()?.into_owned();
+            // Archives made with `tar -C dir .` name their entries `./shader.frag`.
+            let name = name.strip_prefix(".").unwrap_or(&name).to_owned();
+            if name == Path::new(SHADER_FILE) {
+                let mut source = String::new();
+                entry
+                    .read_to_string(&mut source)
+                    .with_context(|| format!("Failed to read {:?}", name))?;
+                debug!("bundled shader: {:?}", name);
+                bundle.shader = Some((name, source));
+            } else if name.parent() == Some(Path::new(""))
+                && name.file_stem() == Some(ICON_STEM.as_ref())
+            {
+                let mut data = vec![];
+                entry
+                    .read_to_end(&mut data)
+                    .with_context(|| format!("Failed to read {:?}", name))?;
+                debug!("bundled icon: {:?}", name);
+                bundle.icon = Some((name, data));
+            }
+        }
+        ensure!(
+            bundle.shader.is_some() || bundle.icon.is_some(),
+            "Bundle has neither {} nor an icon",
+            SHADER_FILE
+        );
+        Ok(bundle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (name, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn reads_shader_and_icon() {
+        let data = archive(&[
+            ("./shader.frag", b"void main() {}"),
+            ("icon.svg", b"<svg/>"),
+            ("themes/icon.png", b"ignored"),
+        ]);
+        let bundle = Bundle::read(&data[..]).unwrap();
+        assert_eq!(
+            bundle.shader,
+            Some((PathBuf::from("shader.frag"), "void main() {}".to_owned()))
+        );
+        assert_eq!(
+            bundle.icon,
+            Some((PathBuf::from("icon.svg"), b"<svg/>".to_vec()))
+        );
+    }
+
+    #[test]
+    fn rejects_empty_bundle() {
+        let data = archive(&[("README", b"nothing here")]);
+        assert!(Bundle::read(&data[..]).is_err());
+    }
+}
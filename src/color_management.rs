@@ -0,0 +1,249 @@
+use std::sync::Mutex;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use sctk::{
+    globals::GlobalData,
+    reexports::client::{
+        globals::GlobalList, protocol::wl_surface::WlSurface, Connection, Dispatch, QueueHandle,
+        WEnum,
+    },
+    registry::GlobalProxy,
+};
+use wayland_protocols::wp::color_management::v1::client::{
+    wp_color_management_surface_feedback_v1, wp_color_manager_v1, wp_image_description_info_v1,
+    wp_image_description_v1,
+};
+
+/// How the compositor would like a surface's pixels encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransferFunction {
+    /// sRGB, or near enough, as without color management.
+    #[default]
+    Srgb,
+    /// Linear light that may go beyond 1.0, as in scRGB, for HDR outputs.
+    ExtendedLinear,
+}
+
+impl TransferFunction {
+    fn from_protocol(tf: wp_color_manager_v1::TransferFunction) -> Self {
+        use wp_color_manager_v1::TransferFunction::*;
+        match tf {
+            // HDR encodings can't be rendered directly, but the compositor can convert to
+            // them from extended linear without clipping highlights.
+            ExtLinear | St2084Pq | Hlg => Self::ExtendedLinear,
+            _ => Self::Srgb,
+        }
+    }
+}
+
+pub trait ColorManagementHandler: Sized {
+    fn color_management_state(&mut self) -> &mut ColorManagementState;
+
+    /// The compositor's preferred transfer function for a surface is known, or has changed.
+    fn preferred_transfer_function(&mut self, surface: &WlSurface, tf: TransferFunction);
+}
+
+#[derive(Debug)]
+pub struct ColorManagementState {
+    manager: GlobalProxy<wp_color_manager_v1::WpColorManagerV1>,
+}
+
+impl ColorManagementState {
+    pub fn new<D>(globals: &GlobalList, qh: &QueueHandle<D>) -> Self
+    where
+        D: Dispatch<wp_color_manager_v1::WpColorManagerV1, GlobalData> + 'static,
+    {
+        // Later versions replace the events used here with ones carrying more detail.
+        let manager = GlobalProxy::from(globals.bind(qh, 1..=1, GlobalData));
+        Self { manager }
+    }
+
+    /// Follow the compositor's preferred image description for a surface, reporting its transfer
+    /// function to `ColorManagementHandler::preferred_transfer_function`.
+    ///
+    /// The surface's own image description is left to the graphics driver, which sets it to
+    /// match the format it presents in.
+    pub fn watch_surface<D>(
+        &self,
+        surface: &WlSurface,
+        qh: &QueueHandle<D>,
+    ) -> Result<(), sctk::error::GlobalError>
+    where
+        D: Dispatch<
+                wp_color_management_surface_feedback_v1::WpColorManagementSurfaceFeedbackV1,
+                SurfaceData,
+            > + Dispatch<wp_image_description_v1::WpImageDescriptionV1, SurfaceData>
+            + 'static,
+    {
+        let manager = self.manager.get()?;
+        let data = SurfaceData {
+            surface: surface.clone(),
+        };
+        let feedback = manager.get_surface_feedback(surface, qh, data.clone());
+        // Only changes are announced, so ask for the current one straight away.
+        feedback.get_preferred(qh, data);
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! delegate_color_management {
+    ($(@<$( $lt:tt $( : $clt:tt $(+ $dlt:tt )* )? ),+>)? $ty: ty) => {
+        sctk::reexports::client::delegate_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty:
+            [
+                wayland_protocols::wp::color_management::v1::client::wp_color_manager_v1::WpColorManagerV1: sctk::globals::GlobalData
+            ] => $crate::color_management::ColorManagementState
+        );
+        sctk::reexports::client::delegate_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty:
+            [
+                wayland_protocols::wp::color_management::v1::client::wp_color_management_surface_feedback_v1::WpColorManagementSurfaceFeedbackV1: $crate::color_management::SurfaceData
+            ] => $crate::color_management::ColorManagementState
+        );
+        sctk::reexports::client::delegate_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty:
+            [
+                wayland_protocols::wp::color_management::v1::client::wp_image_description_v1::WpImageDescriptionV1: $crate::color_management::SurfaceData
+            ] => $crate::color_management::ColorManagementState
+        );
+        sctk::reexports::client::delegate_dispatch!($(@< $( $lt $( : $clt $(+ $dlt )* )? ),+ >)? $ty:
+            [
+                wayland_protocols::wp::color_management::v1::client::wp_image_description_info_v1::WpImageDescriptionInfoV1: $crate::color_management::ImageDescriptionInfoData
+            ] => $crate::color_management::ColorManagementState
+        );
+    };
+}
+
+/// The surface a feedback or image description object is about.
+#[derive(Clone, Debug)]
+pub struct SurfaceData {
+    surface: WlSurface,
+}
+
+/// Details of a preferred image description, gathered until they are all sent.
+#[derive(Debug)]
+pub struct ImageDescriptionInfoData {
+    surface: WlSurface,
+    tf: Mutex<Option<wp_color_manager_v1::TransferFunction>>,
+}
+
+impl<D> Dispatch<wp_color_manager_v1::WpColorManagerV1, GlobalData, D> for ColorManagementState
+where
+    D: Dispatch<wp_color_manager_v1::WpColorManagerV1, GlobalData>,
+{
+    fn event(
+        _state: &mut D,
+        _proxy: &wp_color_manager_v1::WpColorManagerV1,
+        _event: wp_color_manager_v1::Event,
+        _: &GlobalData,
+        _: &Connection,
+        _: &QueueHandle<D>,
+    ) {
+        // Supported features only matter when creating image descriptions, which the graphics
+        // driver does.
+    }
+}
+
+impl<D>
+    Dispatch<
+        wp_color_management_surface_feedback_v1::WpColorManagementSurfaceFeedbackV1,
+        SurfaceData,
+        D,
+    > for ColorManagementState
+where
+    D: Dispatch<
+            wp_color_management_surface_feedback_v1::WpColorManagementSurfaceFeedbackV1,
+            SurfaceData,
+        > + Dispatch<wp_image_description_v1::WpImageDescriptionV1, SurfaceData>
+        + 'static,
+{
+    fn event(
+        _state: &mut D,
+        proxy: &wp_color_management_surface_feedback_v1::WpColorManagementSurfaceFeedbackV1,
+        event: wp_color_management_surface_feedback_v1::Event,
+        data: &SurfaceData,
+        _: &Connection,
+        qh: &QueueHandle<D>,
+    ) {
+        if let wp_color_management_surface_feedback_v1::Event::PreferredChanged { .. } = event {
+            debug!("preferred image description changed: {:?}", data.surface);
+            proxy.get_preferred(qh, data.clone());
+        }
+    }
+}
+
+impl<D> Dispatch<wp_image_description_v1::WpImageDescriptionV1, SurfaceData, D>
+    for ColorManagementState
+where
+    D: Dispatch<wp_image_description_v1::WpImageDescriptionV1, SurfaceData>
+        + Dispatch<wp_image_description_info_v1::WpImageDescriptionInfoV1, ImageDescriptionInfoData>
+        + ColorManagementHandler
+        + 'static,
+{
+    fn event(
+        state: &mut D,
+        proxy: &wp_image_description_v1::WpImageDescriptionV1,
+        event: wp_image_description_v1::Event,
+        data: &SurfaceData,
+        _: &Connection,
+        qh: &QueueHandle<D>,
+    ) {
+        match event {
+            wp_image_description_v1::Event::Ready { .. } => {
+                proxy.get_information(
+                    qh,
+                    ImageDescriptionInfoData {
+                        surface: data.surface.clone(),
+                        tf: Mutex::new(None),
+                    },
+                );
+            }
+            wp_image_description_v1::Event::Failed { cause, msg } => {
+                warn!(
+                    "Compositor has no preferred image description ({:?}): {}",
+                    cause, msg
+                );
+                state.preferred_transfer_function(&data.surface, TransferFunction::default());
+            }
+            _ => {}
+        }
+        proxy.destroy();
+    }
+}
+
+impl<D>
+    Dispatch<wp_image_description_info_v1::WpImageDescriptionInfoV1, ImageDescriptionInfoData, D>
+    for ColorManagementState
+where
+    D: Dispatch<wp_image_description_info_v1::WpImageDescriptionInfoV1, ImageDescriptionInfoData>
+        + ColorManagementHandler,
+{
+    fn event(
+        state: &mut D,
+        _proxy: &wp_image_description_info_v1::WpImageDescriptionInfoV1,
+        event: wp_image_description_info_v1::Event,
+        data: &ImageDescriptionInfoData,
+        _: &Connection,
+        _: &QueueHandle<D>,
+    ) {
+        match event {
+            wp_image_description_info_v1::Event::TfNamed {
+                tf: WEnum::Value(tf),
+            } => {
+                data.tf.lock().unwrap().replace(tf);
+            }
+            // The object is destroyed by the compositor once everything is sent.
+            wp_image_description_info_v1::Event::Done => {
+                let tf = *data.tf.lock().unwrap();
+                debug!(
+                    "preferred transfer function on {:?}: {:?}",
+                    data.surface, tf
+                );
+                // Power law transfer functions, which have no name, are near enough to sRGB.
+                let tf = tf.map_or_else(Default::default, TransferFunction::from_protocol);
+                state.preferred_transfer_function(&data.surface, tf);
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::*;
+use futures::channel::mpsc;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::window_manager::Event;
+
+/// Number of values shaders can read from `iControl`.
+pub const CONTROLS: usize = 4;
+/// What `set` calls each component of `iControl`, as shaders read it.
+const CONTROL_NAMES: [&str; CONTROLS] = ["iControl.x", "iControl.y", "iControl.z", "iControl.w"];
+
+/// A command read from the control socket, one per line, e.g. `set iControl.x 0.5`.
+///
+/// There is no command to take a new screenshot: while locked, the compositor shows the lock
+/// screen, so it would only capture shaderlock itself.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// `set <uniform> <value>`: set one component of `iControl`, named as shaders read it or by
+    /// index.
+    Set(usize, f32),
+    /// `shader <name or path>`: switch every output to another shader.
+    Shader(String),
+    /// `freeze` or `unfreeze`: freeze or unfreeze the animation, as --freeze-key does.
+    Freeze(bool),
+}
+
+impl FromStr for Command {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words = s.split_whitespace();
+        let command = match words.next() {
+            Some("set") => {
+                let name = words.next().context("Missing uniform")?;
+                let index = CONTROL_NAMES
+                    .iter()
+                    .position(|&control| control == name)
+                    .or_else(|| name.parse().ok().filter(|&index| index < CONTROLS))
+                    .with_context(|| {
+                        format!(
+                            "Unknown uniform {:?}, must be one of {}",
+                            name,
+                            CONTROL_NAMES.join(", ")
+                        )
+                    })?;
+                let value = words.next().context("Missing control value")?;
+                let value = value
+                    .parse()
+                    .with_context(|| format!("{:?} is not a number", value))?;
+                Command::Set(index, value)
+            }
+            Some("shader") => {
+                // Paths may have spaces in them.
+                let shader = s.trim().strip_prefix("shader").unwrap_or_default().trim();
+                ensure!(!shader.is_empty(), "Missing shader");
+                return Ok(Command::Shader(shader.to_owned()));
+            }
+            Some("freeze") => Command::Freeze(true),
+            Some("unfreeze") => Command::Freeze(false),
+            Some(command) => bail!("Unknown command {:?}", command),
+            None => bail!("Empty command"),
+        };
+        ensure!(words.next().is_none(), "Too many arguments");
+        Ok(command)
+    }
+}
+
+/// Listen for commands on a Unix socket at `path`, which only the current user can connect to.
+/// A socket left behind by an earlier run is replaced.
+pub fn bind(path: &Path) -> Result<UnixListener> {
+    if let Result::Ok(metadata) = std::fs::symlink_metadata(path) {
+        ensure!(
+            metadata.file_type().is_socket(),
+            "{:?} exists and is not a socket",
+            path
+        );
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove old socket {:?}", path))?;
+    }
+    // Created with no access for anyone else from the start, as changing permissions afterwards
+    // would leave a moment for other users to connect. umask can't fail.
+    let umask = unsafe { libc::umask(0o077) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener.with_context(|| format!("Failed to listen on {:?}", path))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to restrict access to {:?}", path))?;
+    Ok(listener)
+}
+
+/// Accept connections, sending each command read as an event and answering `ok` or the error,
+/// until the event loop stops listening. Must run on a `LocalSet`.
+pub async fn serve(listener: UnixListener, events: mpsc::UnboundedSender<Event>) {
+    loop {
+        let stream = match listener.accept().await {
+            Result::Ok((stream, _)) => stream,
+            Result::Err(e) => {
+                warn!("Failed to accept control connection: {}", e);
+                continue;
+            }
+        };
+        if events.is_closed() {
+            return;
+        }
+        tokio::task::spawn_local(handle(stream, events.clone()));
+    }
+}
+
+async fn handle(stream: UnixStream, events: mpsc::UnboundedSender<Event>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Result::Ok(Some(line)) = lines.next_line().await {
+        let reply = match line.parse() {
+            Result::Ok(command) => {
+                debug!("control command: {:?}", command);
+                if events.unbounded_send(Event::Control(command)).is_err() {
+                    return;
+                }
+                "ok\n".to_owned()
+            }
+            Result::Err(e) => format!("error: {:#}\n", e),
+        };
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            "set iControl.y 0.5".parse::<Command>().unwrap(),
+            Command::Set(1, 0.5)
+        );
+        assert_eq!(
+            "set 3 -1".parse::<Command>().unwrap(),
+            Command::Set(3, -1.0)
+        );
+        assert_eq!(
+            " shader ~/shaders/my waves.frag "
+                .parse::<Command>()
+                .unwrap(),
+            Command::Shader("~/shaders/my waves.frag".to_owned())
+        );
+        assert_eq!("freeze".parse::<Command>().unwrap(), Command::Freeze(true));
+        assert_eq!(
+            "unfreeze".parse::<Command>().unwrap(),
+            Command::Freeze(false)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        for command in [
+            "",
+            "set 4 1",
+            "set iControl 1",
+            "set iTime 1",
+            "set 0",
+            "set 0 x",
+            "freeze now",
+            "shader",
+            "unlock",
+        ] {
+            assert!(command.parse::<Command>().is_err(), "{:?}", command);
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::*;
+
+/// Easing applied to the normalised fade before it reaches the shader.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FadeCurve {
+    #[default]
+    Linear,
+    EaseIn,
+    EaseOut,
+    Smoothstep,
+}
+
+impl FadeCurve {
+    pub const ALL: [FadeCurve; 4] = [
+        FadeCurve::Linear,
+        FadeCurve::EaseIn,
+        FadeCurve::EaseOut,
+        FadeCurve::Smoothstep,
+    ];
+
+    /// Map a fade progress in `[0, 1]` onto the curve. Values outside the range are clamped, and
+    /// NaN is treated as 0.
+    pub fn apply(self, t: f32) -> f32 {
+        let t = if t.is_nan() { 0.0 } else { t.clamp(0.0, 1.0) };
+        match self {
+            FadeCurve::Linear => t,
+            FadeCurve::EaseIn => t * t,
+            FadeCurve::EaseOut => t * (2.0 - t),
+            FadeCurve::Smoothstep => t * t * (3.0 - 2.0 * t),
+        }
+    }
+
+    /// How far the animation has faded, from 0 to 1, `since_input` after the last input. The fade
+    /// lasts `fade_duration` and ends `freeze_after` the last input.
+    pub fn fade_amount(
+        self,
+        since_input: Duration,
+        freeze_after: Duration,
+        fade_duration: Duration,
+    ) -> f32 {
+        let faded_for = since_input
+            .saturating_add(fade_duration)
+            .saturating_sub(freeze_after);
+        let t = if fade_duration.is_zero() {
+            // A zero duration fades instantly rather than dividing by zero.
+            if faded_for.is_zero() {
+                0.0
+            } else {
+                1.0
+            }
+        } else {
+            (faded_for.as_secs_f64() / fade_duration.as_secs_f64()) as f32
+        };
+        self.apply(t)
+    }
+}
+
+impl FromStr for FadeCurve {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "linear" => Ok(FadeCurve::Linear),
+            "ease-in" => Ok(FadeCurve::EaseIn),
+            "ease-out" => Ok(FadeCurve::EaseOut),
+            "smoothstep" => Ok(FadeCurve::Smoothstep),
+            _ => bail!("Fade curve must be linear, ease-in, ease-out or smoothstep"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curves_preserve_endpoints() {
+        for curve in FadeCurve::ALL {
+            assert_eq!(curve.apply(0.0), 0.0, "{:?}", curve);
+            assert_eq!(curve.apply(1.0), 1.0, "{:?}", curve);
+        }
+    }
+
+    #[test]
+    fn curves_clamp_out_of_range() {
+        for curve in FadeCurve::ALL {
+            assert_eq!(curve.apply(-0.5), 0.0, "{:?}", curve);
+            assert_eq!(curve.apply(1.5), 1.0, "{:?}", curve);
+        }
+    }
+
+    #[test]
+    fn curves_are_monotonic() {
+        for curve in FadeCurve::ALL {
+            let samples: Vec<_> = (0..=100).map(|i| curve.apply(i as f32 / 100.0)).collect();
+            assert!(samples.windows(2).all(|w| w[0] <= w[1]), "{:?}", curve);
+        }
+    }
+
+    #[test]
+    fn curves_map_nan_to_start() {
+        for curve in FadeCurve::ALL {
+            assert_eq!(curve.apply(f32::NAN), 0.0, "{:?}", curve);
+        }
+    }
+
+    #[test]
+    fn fade_amount_spans_fade_duration() {
+        let secs = Duration::from_secs;
+        let curve = FadeCurve::Linear;
+        assert_eq!(curve.fade_amount(secs(0), secs(10), secs(5)), 0.0);
+        assert_eq!(curve.fade_amount(secs(5), secs(10), secs(5)), 0.0);
+        assert_eq!(curve.fade_amount(secs(7), secs(10), secs(4)), 0.25);
+        assert_eq!(curve.fade_amount(secs(10), secs(10), secs(5)), 1.0);
+        assert_eq!(curve.fade_amount(secs(20), secs(10), secs(5)), 1.0);
+    }
+
+    #[test]
+    fn fade_amount_with_zero_duration_is_instant() {
+        let secs = Duration::from_secs;
+        let curve = FadeCurve::Linear;
+        assert_eq!(curve.fade_amount(secs(9), secs(10), Duration::ZERO), 0.0);
+        assert_eq!(curve.fade_amount(secs(11), secs(10), Duration::ZERO), 1.0);
+    }
+
+    #[test]
+    fn fade_amount_stays_in_range_for_extreme_durations() {
+        let extremes = [
+            Duration::ZERO,
+            Duration::from_nanos(1),
+            Duration::from_secs(u32::MAX as u64),
+            Duration::MAX,
+        ];
+        for curve in FadeCurve::ALL {
+            for since_input in extremes {
+                for freeze_after in extremes {
+                    for fade_duration in extremes {
+                        let amount = curve.fade_amount(since_input, freeze_after, fade_duration);
+                        assert!(
+                            (0.0..=1.0).contains(&amount),
+                            "{:?} {:?} {:?} {:?} = {}",
+                            curve,
+                            since_input,
+                            freeze_after,
+                            fade_duration,
+                            amount
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parses_names() {
+        assert_eq!("ease-in".parse::<FadeCurve>().unwrap(), FadeCurve::EaseIn);
+        assert_eq!(
+            "smoothstep".parse::<FadeCurve>().unwrap(),
+            FadeCurve::Smoothstep
+        );
+        assert!("bounce".parse::<FadeCurve>().is_err());
+    }
+}
@@ -1,149 +1,1735 @@
+mod backdrop;
 mod bg;
+mod blit;
 mod icon;
+mod metadata;
+mod shadertoy;
 
+pub use self::metadata::ShaderMetadata;
+
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use anyhow::*;
+use crate::color_management::TransferFunction;
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
+use sctk::reexports::client::protocol::wl_output::Transform;
 use wgpu::SurfaceTarget;
 
+/// Failures setting up or rendering graphics.
+#[derive(Debug, thiserror::Error)]
+pub enum GraphicsError {
+    #[error("Invalid colour {0:?}, must be #rgb or #rrggbb")]
+    InvalidColor(String),
+    #[error("Invalid background {0:?}, must be solid:<colour> or gradient:<colour>,<colour>")]
+    InvalidBackground(String),
+    #[error("Invalid icon backdrop {0:?}, must be none, dim or blur")]
+    InvalidBackdrop(String),
+    #[error("Failed to read {path:?}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Failed to create shader compiler")]
+    NoShaderCompiler,
+    // shaderc's diagnostics are part of the message, not a source, so they show wherever the
+    // error is logged, naming the file and line of each problem.
+    #[error("Failed to compile shader {path:?}: {error}")]
+    ShaderCompile {
+        path: PathBuf,
+        error: shaderc::Error,
+    },
+    #[error("Failed to parse shader {path:?}: {message}")]
+    WgslParse { path: PathBuf, message: String },
+    #[error("Invalid GLSL front-end {0:?}, must be shaderc or naga")]
+    InvalidGlslFrontend(String),
+    #[error("Invalid y flip {0:?}, must be auto, always or never")]
+    InvalidFlipY(String),
+    #[error("Invalid internal format {0:?}, must be rgba8 or rgba16float")]
+    InvalidInternalFormat(String),
+    #[error("Invalid shader entry point {0:?}, must be a GLSL identifier")]
+    InvalidShaderEntry(String),
+    #[error("Built without the naga GLSL front-end")]
+    NoNaga,
+    #[cfg(feature = "naga")]
+    #[error("Failed to parse shader")]
+    ShaderParse(#[from] wgpu::naga::front::glsl::ParseErrors),
+    #[error("Icon file {0:?} not found")]
+    IconNotFound(PathBuf),
+    #[error("Icon file {path:?} is not in a supported image format")]
+    IconFormat {
+        path: PathBuf,
+        #[source]
+        source: image::ImageError,
+    },
+    #[error("Icon file {path:?} is corrupt")]
+    IconCorrupt {
+        path: PathBuf,
+        #[source]
+        source: image::ImageError,
+    },
+    #[error("Failed to read icon file {path:?}")]
+    Icon {
+        path: PathBuf,
+        #[source]
+        source: image::ImageError,
+    },
+    #[error("Failed to parse SVG icon")]
+    Svg(#[from] resvg::usvg::Error),
+    #[error("Invalid SVG icon size")]
+    SvgSize,
+    #[error("No font found to draw text")]
+    NoFont,
+    #[error("Failed to create surface")]
+    CreateSurface(#[from] wgpu::CreateSurfaceError),
+    #[error("Failed to get graphics adapter")]
+    NoAdapter,
+    #[error("Graphics adapter supports {name} of {available}, but {required} is required")]
+    Limit {
+        name: &'static str,
+        required: u64,
+        available: u64,
+    },
+    #[error("Failed to get device")]
+    RequestDevice(#[from] wgpu::RequestDeviceError),
+    #[error("Graphics device lost")]
+    DeviceLost,
+    #[error("Failed to get surface texture")]
+    Surface(#[from] wgpu::SurfaceError),
+    #[error("Failed to read back frame")]
+    Readback(#[from] wgpu::BufferAsyncError),
+}
+
+type Result<T, E = GraphicsError> = std::result::Result<T, E>;
+
+/// A colour in linear RGBA, parsed from sRGB `#rgb` or `#rrggbb` notation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color(pub [f32; 4]);
+
+impl Color {
+    pub const BLACK: Color = Color([0.0, 0.0, 0.0, 1.0]);
+    pub const RED: Color = Color([1.0, 0.0, 0.0, 1.0]);
+}
+
+impl FromStr for Color {
+    type Err = GraphicsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || GraphicsError::InvalidColor(s.to_owned());
+        let hex = s.strip_prefix('#').ok_or_else(invalid)?;
+        let digits = hex
+            .chars()
+            .map(|c| c.to_digit(16).map(|d| d as u8))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        let rgb = match digits[..] {
+            [r, g, b] => [r * 17, g * 17, b * 17],
+            [r1, r0, g1, g0, b1, b0] => [r1 * 16 + r0, g1 * 16 + g0, b1 * 16 + b0],
+            _ => return Err(invalid()),
+        };
+        let [r, g, b] = rgb.map(|c| srgb_to_linear(c as f32 / 255.0));
+        Ok(Color([r, g, b, 1.0]))
+    }
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// What to draw behind the icon.
+#[derive(Clone, Debug)]
+pub enum Background {
+    /// A GLSL fragment shader file.
+    Shader(PathBuf),
+    /// GLSL fragment shader source already read, e.g. from a bundle, with the name it had there.
+    ShaderSource(PathBuf, String),
+    /// A directory of Shadertoy-style passes: `Image.frag` drawn to the window, reading any of
+    /// `BufferA.frag` to `BufferD.frag` as `iChannel0` to `iChannel3`.
+    ShaderDir(PathBuf),
+    /// A single colour, given as `solid:#rrggbb`.
+    Solid(Color),
+    /// A vertical gradient from top to bottom, given as `gradient:#rrggbb,#rrggbb`.
+    Gradient(Color, Color),
+    /// The screen as it was before locking, given as `screenshot`, or black without one.
+    Screenshot,
+}
+
+impl FromStr for Background {
+    type Err = GraphicsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "screenshot" {
+            return Ok(Background::Screenshot);
+        }
+        match s.split_once(':') {
+            Some(("solid", color)) => Ok(Background::Solid(color.parse()?)),
+            Some(("gradient", colors)) => {
+                let (top, bottom) = colors
+                    .split_once(',')
+                    .ok_or_else(|| GraphicsError::InvalidBackground(s.to_owned()))?;
+                Ok(Background::Gradient(top.parse()?, bottom.parse()?))
+            }
+            _ => Err(GraphicsError::InvalidBackground(s.to_owned())),
+        }
+    }
+}
+
+/// Where the lock icon is read from.
+#[derive(Clone, Debug)]
+pub enum IconSource {
+    /// An image or SVG file.
+    File(PathBuf),
+    /// An icon file's contents, e.g. from a bundle, with the name it had there.
+    Data(PathBuf, Vec<u8>),
+}
+
+/// What to draw between the background and the icon, to keep the icon legible.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backdrop {
+    #[default]
+    None,
+    /// Darken a rounded rectangle behind the icon.
+    Dim,
+    /// Blur a rounded rectangle behind the icon.
+    Blur,
+}
+
+impl FromStr for Backdrop {
+    type Err = GraphicsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Backdrop::None),
+            "dim" => Ok(Backdrop::Dim),
+            "blur" => Ok(Backdrop::Blur),
+            _ => Err(GraphicsError::InvalidBackdrop(s.to_owned())),
+        }
+    }
+}
+
+/// How background shaders are turned from GLSL into something wgpu can use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GlslFrontend {
+    /// Compile to SPIR-V with libshaderc, falling back to naga if it can't be loaded.
+    #[default]
+    Shaderc,
+    /// Parse with naga, without any native dependencies. Needs the `naga` feature.
+    Naga,
+}
+
+impl FromStr for GlslFrontend {
+    type Err = GraphicsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "shaderc" => Ok(GlslFrontend::Shaderc),
+            "naga" => Ok(GlslFrontend::Naga),
+            _ => Err(GraphicsError::InvalidGlslFrontend(s.to_owned())),
+        }
+    }
+}
+
+/// Whether the background shader's `gl_FragCoord` counts up from the bottom of the window, as in
+/// OpenGL, rather than down from the top.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlipY {
+    /// Flip shaders ported from Shadertoy, which define `mainImage`.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl FlipY {
+    fn applies_to(self, source: &str) -> bool {
+        match self {
+            FlipY::Auto => source.contains("mainImage"),
+            FlipY::Always => true,
+            FlipY::Never => false,
+        }
+    }
+}
+
+impl FromStr for FlipY {
+    type Err = GraphicsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(FlipY::Auto),
+            "always" => Ok(FlipY::Always),
+            "never" => Ok(FlipY::Never),
+            _ => Err(GraphicsError::InvalidFlipY(s.to_owned())),
+        }
+    }
+}
+
+/// Format of the textures the background is drawn to before being copied to the window, with
+/// feedback or a reduced render scale.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InternalFormat {
+    Rgba8,
+    /// Half floats, so shaders accumulating over frames don't band.
+    Rgba16Float,
+}
+
+impl InternalFormat {
+    fn texture_format(self) -> wgpu::TextureFormat {
+        match self {
+            InternalFormat::Rgba8 => wgpu::TextureFormat::Rgba8UnormSrgb,
+            InternalFormat::Rgba16Float => wgpu::TextureFormat::Rgba16Float,
+        }
+    }
+}
+
+impl FromStr for InternalFormat {
+    type Err = GraphicsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rgba8" => Ok(InternalFormat::Rgba8),
+            "rgba16float" => Ok(InternalFormat::Rgba16Float),
+            _ => Err(GraphicsError::InvalidInternalFormat(s.to_owned())),
+        }
+    }
+}
+
+fn load_shader(background: &Background, config: &Config) -> Result<self::bg::Program> {
+    Ok(match background {
+        Background::Shader(shader_file) => compile_shader(
+            shader_file,
+            config.glsl_frontend,
+            &config.shader_entry,
+            config.flip_y,
+            config.shadertoy_wrapper,
+        )?
+        .into(),
+        Background::ShaderSource(name, source) => compile_shader_source(
+            name,
+            source.clone(),
+            config.glsl_frontend,
+            &config.shader_entry,
+            config.flip_y,
+            config.shadertoy_wrapper,
+        )?
+        .into(),
+        Background::ShaderDir(dir) => {
+            let mut buffers: [Option<self::bg::Shader>; CHANNELS] = Default::default();
+            for (buffer, name) in buffers.iter_mut().zip(BUFFER_FILES) {
+                let buffer_file = dir.join(name);
+                if buffer_file.exists() {
+                    debug!("loading buffer {:?}", buffer_file);
+                    // Buffers are never seen, and left unflipped they hold rows in the order
+                    // flipped passes sample them in.
+                    *buffer = Some(compile_shader(
+                        &buffer_file,
+                        config.glsl_frontend,
+                        bg::FS_MAIN,
+                        FlipY::Never,
+                        config.shadertoy_wrapper,
+                    )?);
+                }
+            }
+            self::bg::Program {
+                image: compile_shader(
+                    &dir.join(IMAGE_FILE),
+                    config.glsl_frontend,
+                    &config.shader_entry,
+                    config.flip_y,
+                    config.shadertoy_wrapper,
+                )?,
+                buffers,
+            }
+        }
+        Background::Solid(color) => self::bg::Shader::Gradient([*color; 2]).into(),
+        Background::Gradient(top, bottom) => self::bg::Shader::Gradient([*top, *bottom]).into(),
+        Background::Screenshot => self::bg::Shader::Screenshot.into(),
+    })
+}
+
+/// Load `background` as `Manager::new` would, without falling back, to check it is usable.
+pub fn check_background(background: &Background, config: &Config) -> Result<()> {
+    load_shader(background, config)?;
+    shader_metadata(background)?;
+    Ok(())
+}
+
+/// Load `background`, or else the first of the configured fallbacks that loads, failing with the
+/// last error.
+fn load_background(
+    background: &Background,
+    config: &Config,
+) -> Result<(self::bg::Program, Option<ShaderMetadata>)> {
+    let mut result = load_shader(background, config)
+        .and_then(|program| Ok((program, shader_metadata(background)?)));
+    for fallback in &config.fallback_backgrounds {
+        let e = match result {
+            Result::Ok(loaded) => return Ok(loaded),
+            Result::Err(e) => e,
+        };
+        warn!("Failed to load background, trying {:?}: {}", fallback, e);
+        result = load_shader(fallback, config)
+            .and_then(|program| Ok((program, shader_metadata(fallback)?)));
+        if result.is_ok() {
+            info!("Using fallback background {:?}", fallback);
+        }
+    }
+    result
+}
+
+/// Read the header of the shader drawn to the window, if the background has one.
+fn shader_metadata(background: &Background) -> Result<Option<ShaderMetadata>> {
+    let read = |path: &std::path::Path| {
+        std::fs::read_to_string(path).map_err(|source| GraphicsError::Read {
+            path: path.to_owned(),
+            source,
+        })
+    };
+    Ok(match background {
+        Background::Shader(shader_file) => Some(ShaderMetadata::parse(&read(shader_file)?)),
+        Background::ShaderSource(_, source) => Some(ShaderMetadata::parse(source)),
+        Background::ShaderDir(dir) => Some(ShaderMetadata::parse(&read(&dir.join(IMAGE_FILE))?)),
+        Background::Solid(_) | Background::Gradient(..) | Background::Screenshot => None,
+    })
+}
+
+/// Log a shader's attribution, and warn if it reads channels that nothing is bound to.
+fn check_metadata(
+    metadata: &ShaderMetadata,
+    program: &self::bg::Program,
+    channels: &[Option<image::RgbaImage>; CHANNELS],
+) {
+    if metadata.title.is_some() || metadata.author.is_some() {
+        info!(
+            "Shader {:?} by {}",
+            metadata.title.as_deref().unwrap_or("untitled"),
+            metadata.author.as_deref().unwrap_or("unknown author")
+        );
+    }
+    if let Some(required) = metadata.channels {
+        if required > CHANNELS {
+            warn!(
+                "Shader reads {} channels, but only {} can be bound",
+                required, CHANNELS
+            );
+        }
+        let bound = program.buffers.iter().zip(channels).enumerate();
+        for (channel, (buffer, image)) in bound.take(required) {
+            if buffer.is_none() && image.is_none() {
+                warn!("Shader reads iChannel{}, which is unbound", channel);
+            }
+        }
+    }
+}
+
+fn compile_shader(
+    shader_file: &std::path::Path,
+    frontend: GlslFrontend,
+    entry: &str,
+    flip_y: FlipY,
+    shadertoy: bool,
+) -> Result<self::bg::Shader> {
+    let shader_source =
+        std::fs::read_to_string(shader_file).map_err(|source| GraphicsError::Read {
+            path: shader_file.to_owned(),
+            source,
+        })?;
+    compile_shader_source(
+        shader_file,
+        shader_source,
+        frontend,
+        entry,
+        flip_y,
+        shadertoy,
+    )
+}
+
+/// Compile shader source, naming it `shader_file` in errors. Files ending `.wgsl` are WGSL, which
+/// wgpu compiles itself, and the rest GLSL.
+fn compile_shader_source(
+    shader_file: &std::path::Path,
+    shader_source: String,
+    frontend: GlslFrontend,
+    entry: &str,
+    flip_y: FlipY,
+    shadertoy: bool,
+) -> Result<self::bg::Shader> {
+    if shader_file
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("wgsl"))
+    {
+        let entry = wgsl_entry(shader_file, &shader_source, entry)?;
+        debug!("WGSL shader {:?} entry point: {}", shader_file, entry);
+        let source = wgpu::ShaderSource::Wgsl(shader_source.into());
+        return Ok(self::bg::Shader::Custom(source, entry));
+    }
+    let source = compile_glsl(
+        shader_file,
+        shader_source,
+        frontend,
+        entry,
+        flip_y,
+        shadertoy,
+    )?;
+    Ok(self::bg::Shader::Custom(source, bg::FS_MAIN.to_owned()))
+}
+
+/// Compile GLSL, first wrapping shaders copied from Shadertoy if `shadertoy` is set.
+fn compile_glsl(
+    shader_file: &std::path::Path,
+    shader_source: String,
+    frontend: GlslFrontend,
+    entry: &str,
+    flip_y: FlipY,
+    shadertoy: bool,
+) -> Result<wgpu::ShaderSource<'static>> {
+    let flip_y = flip_y.applies_to(&shader_source);
+    let shader_source = if shadertoy && self::shadertoy::is_shadertoy(&shader_source) {
+        debug!("wrapping Shadertoy shader {:?}", shader_file);
+        self::shadertoy::wrap(&shader_source, flip_y)
+    } else {
+        shader_source
+    };
+    let shader_source = select_entry(shader_source, entry, flip_y)?;
+    let compiler = match frontend {
+        GlslFrontend::Shaderc => shaderc::Compiler::new(),
+        GlslFrontend::Naga => None,
+    };
+    let compiler = match compiler {
+        Some(compiler) => compiler,
+        None if frontend == GlslFrontend::Naga => return parse_shader(shader_source),
+        None if cfg!(feature = "naga") => {
+            warn!("Failed to create shader compiler, parsing shader with naga instead");
+            return parse_shader(shader_source);
+        }
+        None => return Err(GraphicsError::NoShaderCompiler),
+    };
+    let spirv = compiler
+        .compile_into_spirv(
+            &shader_source,
+            shaderc::ShaderKind::Fragment,
+            &shader_file.to_string_lossy(),
+            bg::FS_MAIN,
+            None,
+        )
+        .map_err(|error| GraphicsError::ShaderCompile {
+            path: shader_file.to_owned(),
+            error,
+        })?;
+
+    let data = Vec::from(spirv.as_binary());
+    Ok(wgpu::ShaderSource::SpirV(data.into()))
+}
+
+/// Make `entry` the shader's entry point, and with `flip_y`, turn `gl_FragCoord` upside down.
+///
+/// GLSL entry points must be called `main`, so the shader's own `main` is renamed out of the way
+/// and a new one added that calls `entry` instead. `gl_FragCoord` is read-only, so a flipped copy
+/// is set up before the call and the shader reads that in its place.
+fn select_entry(source: String, entry: &str, flip_y: bool) -> Result<String> {
+    if entry == bg::FS_MAIN && !flip_y {
+        return Ok(source);
+    }
+    let is_identifier = entry
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && entry.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !is_identifier {
+        return Err(GraphicsError::InvalidShaderEntry(entry.to_owned()));
+    }
+
+    // Nothing but comments may come before #version, so the rename goes straight after it.
+    let mut version_end = 0;
+    let mut version_line = 0;
+    for (number, line) in source.split_inclusive('\n').enumerate() {
+        version_end += line.len();
+        if line.trim_start().starts_with("#version") {
+            version_line = number + 1;
+            break;
+        }
+    }
+    if version_line == 0 {
+        version_end = 0;
+    }
+    let (head, body) = source.split_at(version_end);
+    let entry = match entry {
+        bg::FS_MAIN => "_shaderlock_main",
+        entry => entry,
+    };
+    let (declarations, body, flip) = if flip_y {
+        (
+            format!(
+                "layout(set = 0, binding = {}) uniform _ShaderlockUniforms {{\n    \
+                 mat4 transform;\n    vec4 colors[2];\n    vec2 resolution;\n}} _shaderlock;\n\
+                 vec4 _shaderlock_FragCoord;\n",
+                bg::FLIP_Y_BINDING
+            ),
+            body.replace("gl_FragCoord", "_shaderlock_FragCoord"),
+            "_shaderlock_FragCoord = vec4(gl_FragCoord.x, _shaderlock.resolution.y - \
+             gl_FragCoord.y, gl_FragCoord.zw); ",
+        )
+    } else {
+        (String::new(), body.to_owned(), "")
+    };
+    Ok(format!(
+        "{}\n#define main _shaderlock_main\n{}#line {}\n{}\n#undef main\nvoid main() {{ {}{}(); }}\n",
+        head.trim_end_matches('\n'),
+        declarations,
+        version_line + 1,
+        body,
+        flip,
+        entry
+    ))
+}
+
+/// WGSL entry points can have any name, so unless another was asked for, run the first
+/// `@fragment` function rather than looking for `main`. The shader is parsed to find it, which
+/// also reports syntax errors before wgpu sees them.
+fn wgsl_entry(shader_file: &std::path::Path, source: &str, entry: &str) -> Result<String> {
+    let module =
+        wgpu::naga::front::wgsl::parse_str(source).map_err(|e| GraphicsError::WgslParse {
+            path: shader_file.to_owned(),
+            message: e.emit_to_string_with_path(source, shader_file),
+        })?;
+    if entry != bg::FS_MAIN {
+        return Ok(entry.to_owned());
+    }
+    Ok(module
+        .entry_points
+        .iter()
+        .find(|ep| ep.stage == wgpu::naga::ShaderStage::Fragment)
+        .map_or(entry, |ep| &ep.name)
+        .to_owned())
+}
+
+/// Check a shader parses with naga, so errors are reported rather than failing on the device.
+#[cfg(feature = "naga")]
+fn parse_shader(shader_source: String) -> Result<wgpu::ShaderSource<'static>> {
+    use wgpu::naga::front::glsl;
+    let stage = wgpu::naga::ShaderStage::Fragment;
+    glsl::Frontend::default().parse(&glsl::Options::from(stage), &shader_source)?;
+    Ok(wgpu::ShaderSource::Glsl {
+        shader: shader_source.into(),
+        stage,
+        defines: Default::default(),
+    })
+}
+
+#[cfg(not(feature = "naga"))]
+fn parse_shader(_shader_source: String) -> Result<wgpu::ShaderSource<'static>> {
+    Err(GraphicsError::NoNaga)
+}
+
+/// Number of `iChannelN` textures available to background shaders.
+pub const CHANNELS: usize = 4;
+
+/// Pass of a shader directory drawn to the window.
+const IMAGE_FILE: &str = "Image.frag";
+/// Buffer passes of a shader directory, bound as the channel of the same index.
+const BUFFER_FILES: [&str; CHANNELS] = [
+    "BufferA.frag",
+    "BufferB.frag",
+    "BufferC.frag",
+    "BufferD.frag",
+];
+
+/// Format of frames rendered without a window, matching `image::RgbaImage`.
+const OFFSCREEN_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+/// Space between the icon and the message below it, in logical pixels.
+const MESSAGE_SPACING: f32 = 16.0;
+
+/// Space between the battery and layout indicators and the corners of the window, in logical
+/// pixels.
+const CORNER_MARGIN: f32 = 24.0;
+
+/// Animation time between frames rendered by `Manager::bench`, as if running at 60 FPS.
+const BENCH_FRAME_INTERVAL: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// Tunables for every window's rendering.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// How many frames may be queued for presentation. Lower values reduce input latency,
+    /// higher values give smoother animation.
+    pub frame_latency: u32,
+    /// Drawn before the background. Red by default so a shader that fails to cover the surface
+    /// is obvious.
+    pub clear_color: Color,
+    pub backdrop: Backdrop,
+    /// Let background shaders sample the previous frame, at the cost of two more frame-sized
+    /// textures per window.
+    pub feedback: bool,
+    pub glsl_frontend: GlslFrontend,
+    /// Function background shaders run, for shaders with several techniques.
+    pub shader_entry: String,
+    /// Text shown below the icon.
+    pub message: Option<String>,
+    /// Smooth the icon and text with mipmaps and linear filtering when drawn at other sizes.
+    pub icon_antialias: bool,
+    /// Multiplies the icon's colour, so a white icon takes on the tint.
+    pub icon_tint: Option<Color>,
+    /// Fraction of the window's resolution the background is rendered at, then upscaled
+    /// linearly. The icon and text are still drawn at full resolution.
+    pub render_scale: f32,
+    /// Tried in order by `Manager::new` if the background fails to load.
+    pub fallback_backgrounds: Vec<Background>,
+    /// Format of the feedback and upscaling textures, or `None` for the window's format.
+    /// Multi-pass buffers are always half floats, as Shadertoy's are.
+    pub internal_format: Option<InternalFormat>,
+    /// Draw the icon with its longer side this many millimetres on every output that reports
+    /// its physical size, instead of at its own size in pixels.
+    pub icon_size_mm: Option<f32>,
+    /// Start each window's animation when it is created, rather than together.
+    pub per_window_time: bool,
+    /// Which background shaders have `gl_FragCoord` flipped to count up from the bottom.
+    pub flip_y: FlipY,
+    /// Give shaders copied from Shadertoy, with `mainImage` but no `main`, a `main` calling it.
+    pub shadertoy_wrapper: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            frame_latency: 2,
+            clear_color: Color::RED,
+            backdrop: Backdrop::None,
+            feedback: false,
+            glsl_frontend: GlslFrontend::Shaderc,
+            shader_entry: bg::FS_MAIN.to_owned(),
+            message: None,
+            icon_antialias: true,
+            icon_tint: None,
+            render_scale: 1.0,
+            fallback_backgrounds: vec![],
+            internal_format: None,
+            icon_size_mm: None,
+            per_window_time: false,
+            flip_y: FlipY::Auto,
+            shadertoy_wrapper: true,
+        }
+    }
+}
+
 pub struct Manager {
     instance: wgpu::Instance,
-    shader: wgpu::ShaderSource<'static>,
-    icon: image::RgbaImage,
+    program: self::bg::Program,
+    icon: self::icon::Icon,
+    message: Option<self::icon::Icon>,
+    battery: Option<self::icon::Icon>,
+    layout: Option<self::icon::Icon>,
+    now_playing: Option<self::icon::Icon>,
+    channels: [Option<image::RgbaImage>; CHANNELS],
+    /// Header of the current shader, if the background is one.
+    metadata: Option<ShaderMetadata>,
+    /// Device every window renders with, created for the first one.
+    device: Mutex<Option<Arc<SharedDevice>>>,
+    config: Config,
 }
 
-impl Manager {
-    pub fn new(shader_file: &std::path::Path, icon_file: &std::path::Path) -> Result<Self> {
-        let shader_source =
-            std::fs::read_to_string(shader_file).context("Failed to read shader")?;
-        let compiler = shaderc::Compiler::new().context("Failed to create shader compiler")?;
-        let spirv = compiler
-            .compile_into_spirv(
-                &shader_source,
-                shaderc::ShaderKind::Fragment,
-                &shader_file.to_string_lossy(),
-                bg::FS_MAIN,
-                None,
-            )
-            .context("Failed to compile shader")?;
+/// One device and queue for every output, rather than one each, to save video memory and the
+/// time to create them.
+struct SharedDevice {
+    adapter: wgpu::Adapter,
+    device: Arc<wgpu::Device>,
+    queue: Arc<wgpu::Queue>,
+    /// Set if the device is lost, so windows are initialized again with a new one.
+    lost: Arc<AtomicBool>,
+}
 
-        let data = Vec::from(spirv.as_binary());
-        let shader = wgpu::ShaderSource::SpirV(data.into());
+impl Manager {
+    pub fn new(
+        background: &Background,
+        icon: &IconSource,
+        channels: [Option<image::RgbaImage>; CHANNELS],
+        config: Config,
+    ) -> Result<Self> {
+        let (program, metadata) = load_background(background, &config).map_err(|e| {
+            error!("{}", e);
+            e
+        })?;
+        if let Some(metadata) = &metadata {
+            check_metadata(metadata, &program, &channels);
+        }
 
-        let icon = image::open(icon_file).context("Failed to read icon file")?;
+        let icon = match icon {
+            IconSource::File(path) => self::icon::Icon::open(path)?,
+            IconSource::Data(name, data) => self::icon::Icon::from_data(name, data)?,
+        };
+        // Laid out once here, as finding fonts is slow.
+        let message = config
+            .message
+            .as_deref()
+            .map(self::icon::Icon::text)
+            .transpose()?;
 
         Ok(Manager {
             instance: wgpu::Instance::new(wgpu::InstanceDescriptor {
+                // GL isn't among these, though its flipped clip space would be fine: wgpu's GL
+                // backend rewrites vertex outputs to the same convention, so the quads in
+                // resources/*.vert come out the same way up on every backend.
                 backends: wgpu::Backends::PRIMARY,
                 ..Default::default()
             }),
-            shader,
-            icon: icon.into_rgba8(),
+            program,
+            icon,
+            message,
+            battery: None,
+            layout: None,
+            now_playing: None,
+            channels,
+            metadata,
+            device: Mutex::new(None),
+            config,
         })
     }
 
+    /// Change the background for windows initialized from now on, and by `apply_shader`.
+    pub fn set_background(&mut self, background: &Background) -> Result<()> {
+        self.program = load_shader(background, &self.config)?;
+        self.metadata = shader_metadata(background)?;
+        if let Some(metadata) = &self.metadata {
+            check_metadata(metadata, &self.program, &self.channels);
+        }
+        Ok(())
+    }
+
+    /// Header of the current shader, or `None` for a solid or gradient background.
+    pub fn shader_metadata(&self) -> Option<&ShaderMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Change the text below the icon for windows initialized from now on, and by
+    /// `apply_message`.
+    pub fn set_message(&mut self, message: Option<&str>) -> Result<()> {
+        self.message = message.map(self::icon::Icon::text).transpose()?;
+        Ok(())
+    }
+
+    /// Switch an existing window to the current text below the icon.
+    pub fn apply_message(&self, state: &mut State) -> Result<()> {
+        let mut message = self.create_message(
+            &state.device,
+            &state.queue,
+            state.surface_config.format,
+            state.scale,
+        )?;
+        if let Some(message) = &mut message {
+            message.skip_intro();
+        }
+        state.message = message;
+        state.place_message();
+        Ok(())
+    }
+
+    /// Change the battery indicator for windows initialized from now on, and by `apply_battery`.
+    pub fn set_battery(&mut self, battery: Option<&crate::battery::Battery>) -> Result<()> {
+        self.battery = battery
+            .map(|battery| self::icon::Icon::text(&battery.label()))
+            .transpose()?;
+        Ok(())
+    }
+
+    /// Switch an existing window to the current battery indicator.
+    pub fn apply_battery(&self, state: &mut State) -> Result<()> {
+        let mut battery = self.create_corner_text(
+            self.battery.as_ref(),
+            &state.device,
+            &state.queue,
+            state.surface_config.format,
+            state.scale,
+        )?;
+        if let Some(battery) = &mut battery {
+            battery.skip_intro();
+        }
+        state.battery = battery;
+        state.place_corner_text();
+        Ok(())
+    }
+
+    /// Change the keyboard layout indicator for windows initialized from now on, and by
+    /// `apply_layout`.
+    pub fn set_layout(&mut self, layout: Option<&str>) -> Result<()> {
+        self.layout = layout.map(self::icon::Icon::text).transpose()?;
+        Ok(())
+    }
+
+    /// Switch an existing window to the current keyboard layout indicator.
+    pub fn apply_layout(&self, state: &mut State) -> Result<()> {
+        let mut layout = self.create_corner_text(
+            self.layout.as_ref(),
+            &state.device,
+            &state.queue,
+            state.surface_config.format,
+            state.scale,
+        )?;
+        if let Some(layout) = &mut layout {
+            layout.skip_intro();
+        }
+        state.layout = layout;
+        state.place_corner_text();
+        Ok(())
+    }
+
+    /// Change the playing track shown at the top for windows initialized from now on, and by
+    /// `apply_now_playing`.
+    pub fn set_now_playing(&mut self, now_playing: Option<&str>) -> Result<()> {
+        self.now_playing = now_playing.map(self::icon::Icon::text).transpose()?;
+        Ok(())
+    }
+
+    /// Switch an existing window to the current playing track.
+    pub fn apply_now_playing(&self, state: &mut State) -> Result<()> {
+        let mut now_playing = self.create_corner_text(
+            self.now_playing.as_ref(),
+            &state.device,
+            &state.queue,
+            state.surface_config.format,
+            state.scale,
+        )?;
+        if let Some(now_playing) = &mut now_playing {
+            now_playing.skip_intro();
+        }
+        state.now_playing = now_playing;
+        state.place_corner_text();
+        Ok(())
+    }
+
+    /// Change the image bound as `iChannelN` for windows initialized from now on, and by
+    /// `apply_channel`.
+    pub fn set_channel(&mut self, channel: usize, image: Option<image::RgbaImage>) {
+        self.channels[channel] = image;
+    }
+
+    /// Switch an existing window to the current image for `iChannelN`.
+    pub fn apply_channel(&self, state: &mut State, channel: usize) {
+        state.bg.set_channel(
+            &state.device,
+            &state.queue,
+            channel,
+            self.channels[channel].as_ref(),
+        );
+    }
+
+    /// Render an existing window at a new buffer scale, keeping its logical size.
+    pub fn set_scale(&self, state: &mut State, scale: u32) -> Result<()> {
+        let mut icon = self.create_icon(
+            &state.device,
+            &state.queue,
+            state.surface_config.format,
+            scale,
+        )?;
+        // The window is already showing, so don't animate the icon in again.
+        icon.skip_intro();
+        let mut message = self.create_message(
+            &state.device,
+            &state.queue,
+            state.surface_config.format,
+            scale,
+        )?;
+        if let Some(message) = &mut message {
+            message.skip_intro();
+        }
+        let mut battery = self.create_corner_text(
+            self.battery.as_ref(),
+            &state.device,
+            &state.queue,
+            state.surface_config.format,
+            scale,
+        )?;
+        if let Some(battery) = &mut battery {
+            battery.skip_intro();
+        }
+        let mut layout = self.create_corner_text(
+            self.layout.as_ref(),
+            &state.device,
+            &state.queue,
+            state.surface_config.format,
+            scale,
+        )?;
+        if let Some(layout) = &mut layout {
+            layout.skip_intro();
+        }
+        let mut now_playing = self.create_corner_text(
+            self.now_playing.as_ref(),
+            &state.device,
+            &state.queue,
+            state.surface_config.format,
+            scale,
+        )?;
+        if let Some(now_playing) = &mut now_playing {
+            now_playing.skip_intro();
+        }
+        state.icon = icon;
+        state.message = message;
+        state.battery = battery;
+        state.layout = layout;
+        state.now_playing = now_playing;
+        state.scale = scale;
+        state.resize(state.size);
+        Ok(())
+    }
+
+    /// Switch an existing window to the current background.
+    pub fn apply_shader(&self, state: &mut State) {
+        state
+            .bg
+            .set_shader(&state.device, &state.queue, self.program.clone());
+    }
+
     pub async fn init_window<'window>(
         &self,
         window: impl Into<SurfaceTarget<'window>>,
-        screenshot: crate::screencopy::ScreencopyBuffer,
+        screenshot: Option<crate::screencopy::ScreencopyBuffer>,
         (width, height): (u32, u32),
+        scale: u32,
+        transfer_function: TransferFunction,
     ) -> Result<State<'window>> {
-        let surface = self
-            .instance
-            .create_surface(window)
-            .context("Failed to create surface")?;
-        debug!("requesting adapter");
-        let adapter = self
-            .instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::LowPower,
-                compatible_surface: Some(&surface),
-                force_fallback_adapter: false,
-            })
-            .await
-            .context("Failed to get graphics adapter")?;
+        let surface = self.instance.create_surface(window)?;
+        let shared = self.shared_device(&surface).await?;
+        let (device, queue) = (shared.device.clone(), shared.queue.clone());
+        let capabilities = surface.get_capabilities(&shared.adapter);
 
-        debug!("requesting device");
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    label: None,
-                    required_features: wgpu::Features::PUSH_CONSTANTS,
-                    required_limits: wgpu::Limits {
-                        max_push_constant_size: self::bg::PUSH_CONSTANTS_SIZE,
-                        ..wgpu::Limits::default()
-                    },
-                    memory_hints: Default::default(),
-                },
-                None, // Trace path
-            )
-            .await
-            .context("Failed to get device")?;
+        let mut backdrop = self.config.backdrop;
+        let mut usage = wgpu::TextureUsages::RENDER_ATTACHMENT;
+        if backdrop == Backdrop::Blur {
+            // Blurring reads back what the background drew.
+            if capabilities.usages.contains(wgpu::TextureUsages::COPY_SRC) {
+                usage |= wgpu::TextureUsages::COPY_SRC;
+            } else {
+                warn!("Surface can't be copied to blur the icon backdrop, dimming instead");
+                backdrop = Backdrop::Dim;
+            }
+        }
 
+        // Shaders output linear light either way: the hardware encodes it as sRGB, or the
+        // driver tells the compositor the float format is extended linear.
+        let format = match transfer_function {
+            TransferFunction::Srgb => wgpu::TextureFormat::Bgra8UnormSrgb,
+            TransferFunction::ExtendedLinear
+                if capabilities
+                    .formats
+                    .contains(&wgpu::TextureFormat::Rgba16Float) =>
+            {
+                wgpu::TextureFormat::Rgba16Float
+            }
+            TransferFunction::ExtendedLinear => {
+                warn!("Surface can't present extended linear colour, using sRGB");
+                wgpu::TextureFormat::Bgra8UnormSrgb
+            }
+        };
         let surface_config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            format: wgpu::TextureFormat::Bgra8UnormSrgb,
-            width,
-            height,
+            usage,
+            format,
+            width: (width * scale).max(1),
+            height: (height * scale).max(1),
             present_mode: wgpu::PresentMode::Fifo,
             alpha_mode: wgpu::CompositeAlphaMode::Auto,
             view_formats: vec![],
-            desired_maximum_frame_latency: 2,
+            desired_maximum_frame_latency: self.config.frame_latency,
         };
 
-        let bg = self::bg::State::new(
+        // The background is drawn to an intermediate texture, then copied to the window.
+        let intermediate = self.config.feedback || self.config.render_scale < 1.0;
+        let bg_format = match self.config.internal_format {
+            Some(format) if intermediate => format.texture_format(),
+            _ => surface_config.format,
+        };
+        let (bg, icon) = self.create_layers(
             &device,
             &queue,
+            bg_format,
             surface_config.format,
-            self.shader.clone(),
             screenshot,
+            scale,
+        )?;
+        let message = self.create_message(&device, &queue, surface_config.format, scale)?;
+        let battery = self.create_corner_text(
+            self.battery.as_ref(),
+            &device,
+            &queue,
+            surface_config.format,
+            scale,
         )?;
-        let icon = self::icon::State::new(&device, &queue, surface_config.format, &self.icon)?;
+        let layout = self.create_corner_text(
+            self.layout.as_ref(),
+            &device,
+            &queue,
+            surface_config.format,
+            scale,
+        )?;
+        let now_playing = self.create_corner_text(
+            self.now_playing.as_ref(),
+            &device,
+            &queue,
+            surface_config.format,
+            scale,
+        )?;
+        let backdrop = (backdrop != Backdrop::None)
+            .then(|| self::backdrop::State::new(&device, surface_config.format, backdrop));
+        let feedback = self
+            .config
+            .feedback
+            .then(|| Feedback::new(&device, bg_format, surface_config.format));
+        // Feedback frames are rendered at the background's resolution and upscaled anyway.
+        let upscale = (self.config.render_scale < 1.0 && feedback.is_none())
+            .then(|| Upscale::new(&device, bg_format, surface_config.format));
 
         let mut me = State {
             surface,
             device,
             queue,
             surface_config,
+            size: (width, height),
+            scale,
+            render_scale: self.config.render_scale,
+            physical_size: None,
+            icon_size_mm: self.config.icon_size_mm,
+            time_origin: self.config.per_window_time.then(std::time::Instant::now),
+            transfer_function,
 
             bg,
+            feedback,
+            upscale,
+            backdrop,
             icon,
+            message,
+            battery,
+            layout,
+            now_playing,
+            show_icon: true,
+            device_lost: shared.lost.clone(),
         };
 
         me.resize((width, height));
         Ok(me)
     }
+
+    /// Check a device with the features and limits needed is available, returning its adapter.
+    pub async fn check_device(&self) -> Result<wgpu::AdapterInfo> {
+        let (adapter, _, _) = self.request_device(None, wgpu::Features::empty()).await?;
+        Ok(adapter.get_info())
+    }
+
+    /// Render a single frame without a window, for previewing shaders.
+    pub async fn render_offscreen(
+        &self,
+        screenshot: crate::screencopy::ScreencopyBuffer,
+        (width, height): (u32, u32),
+        ctx: RenderContext,
+    ) -> Result<image::RgbaImage> {
+        let (_, device, queue) = self.request_device(None, wgpu::Features::empty()).await?;
+        let (mut bg, mut icon) = self.create_layers(
+            &device,
+            &queue,
+            OFFSCREEN_FORMAT,
+            OFFSCREEN_FORMAT,
+            Some(screenshot),
+            1,
+        )?;
+        let transform = resolution_transform((width, height));
+        bg.resize(&device, &queue, transform, (width, height));
+        icon.resize(&queue, transform);
+
+        let texture = create_offscreen_texture(&device, (width, height));
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        // Rows of a buffer copy must be aligned, so pad them and strip the padding on readback.
+        let row_bytes = width * 4;
+        let padded_row_bytes = row_bytes.next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Offscreen readback"),
+            size: padded_row_bytes as u64 * height as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Offscreen Encoder"),
+        });
+        icon.skip_intro();
+        icon.update(&queue, ctx.icon_opacity);
+        bg.render(&mut encoder, &view, ctx, None);
+        icon.render(&mut encoder, &view);
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_row_bytes),
+                    rows_per_image: None,
+                },
+            },
+            texture.size(),
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let (tx, rx) = futures::channel::oneshot::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = tx.send(result);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        // The callback is only dropped unsent if the device is.
+        rx.await.map_err(|_| GraphicsError::DeviceLost)??;
+
+        let mapped = slice.get_mapped_range();
+        let pixels = mapped
+            .chunks(padded_row_bytes as usize)
+            .flat_map(|row| &row[..row_bytes as usize])
+            .copied()
+            .collect();
+        Ok(image::RgbaImage::from_raw(width, height, pixels).expect("readback matches frame size"))
+    }
+
+    /// Render `frames` frames of animation without a window, timing how long they take.
+    ///
+    /// GPU time is measured with timestamp queries, if the adapter supports them.
+    pub async fn bench(
+        &self,
+        screenshot: crate::screencopy::ScreencopyBuffer,
+        (width, height): (u32, u32),
+        frames: u32,
+    ) -> Result<BenchResult> {
+        let (_, device, queue) = self
+            .request_device(
+                None,
+                wgpu::Features::TIMESTAMP_QUERY | wgpu::Features::TIMESTAMP_QUERY_INSIDE_ENCODERS,
+            )
+            .await?;
+        let (mut bg, mut icon) = self.create_layers(
+            &device,
+            &queue,
+            OFFSCREEN_FORMAT,
+            OFFSCREEN_FORMAT,
+            Some(screenshot),
+            1,
+        )?;
+        let transform = resolution_transform((width, height));
+        bg.resize(&device, &queue, transform, (width, height));
+        icon.resize(&queue, transform);
+        icon.skip_intro();
+
+        let texture = create_offscreen_texture(&device, (width, height));
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let timestamps = device
+            .features()
+            .contains(wgpu::Features::TIMESTAMP_QUERY_INSIDE_ENCODERS)
+            .then(|| {
+                let size = 2 * wgpu::QUERY_SIZE as u64;
+                let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+                    label: Some("Bench timestamps"),
+                    ty: wgpu::QueryType::Timestamp,
+                    count: 2,
+                });
+                let resolve = device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Bench timestamp resolve"),
+                    size,
+                    usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+                    mapped_at_creation: false,
+                });
+                let readback = device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("Bench timestamp readback"),
+                    size,
+                    usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                (query_set, resolve, readback)
+            });
+
+        let start = std::time::Instant::now();
+        for frame in 0..frames {
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Bench Encoder"),
+            });
+            if let (0, Some((query_set, _, _))) = (frame, &timestamps) {
+                encoder.write_timestamp(query_set, 0);
+            }
+            let ctx = RenderContext {
+                elapsed: BENCH_FRAME_INTERVAL * frame,
+                fade_amount: 0.0,
+                lock_state: LockState::Locked,
+                icon_opacity: 1.0,
+                output_index: 0,
+                output_count: 1,
+                password_length: 0,
+                last_char_age: None,
+                battery: None,
+                blank: false,
+                unlock_progress: 0.0,
+                focused: true,
+                output_transform: Transform::Normal,
+                control: Default::default(),
+                mouse: (0.0, 0.0),
+            };
+            icon.update(&queue, ctx.icon_opacity);
+            bg.render(&mut encoder, &view, ctx, None);
+            icon.render(&mut encoder, &view);
+            if let Some((query_set, resolve, readback)) = &timestamps {
+                if frame + 1 == frames {
+                    encoder.write_timestamp(query_set, 1);
+                    encoder.resolve_query_set(query_set, 0..2, resolve, 0);
+                    encoder.copy_buffer_to_buffer(resolve, 0, readback, 0, resolve.size());
+                }
+            }
+            queue.submit(std::iter::once(encoder.finish()));
+        }
+        let cpu_time = start.elapsed();
+        device.poll(wgpu::Maintain::Wait);
+        let total_time = start.elapsed();
+
+        let gpu_time = match &timestamps {
+            Some((_, _, readback)) if frames > 0 => {
+                let slice = readback.slice(..);
+                let (tx, rx) = futures::channel::oneshot::channel();
+                slice.map_async(wgpu::MapMode::Read, move |result| {
+                    let _ = tx.send(result);
+                });
+                device.poll(wgpu::Maintain::Wait);
+                rx.await.map_err(|_| GraphicsError::DeviceLost)??;
+                let ticks: [u64; 2] = bytemuck::pod_read_unaligned(&slice.get_mapped_range());
+                let nanos =
+                    ticks[1].saturating_sub(ticks[0]) as f64 * queue.get_timestamp_period() as f64;
+                Some(Duration::from_nanos(nanos as u64))
+            }
+            _ => None,
+        };
+
+        Ok(BenchResult {
+            frames,
+            cpu_time,
+            total_time,
+            gpu_time,
+        })
+    }
+
+    /// The device windows render with, creating it if there is none yet, or the last one was
+    /// lost or can't present to `surface`.
+    async fn shared_device(&self, surface: &wgpu::Surface<'_>) -> Result<Arc<SharedDevice>> {
+        if let Some(shared) = self.device.lock().unwrap().as_ref() {
+            if !shared.lost.load(Ordering::SeqCst) && shared.adapter.is_surface_supported(surface) {
+                return Ok(shared.clone());
+            }
+        }
+
+        let (adapter, device, queue) = self
+            .request_device(Some(surface), wgpu::Features::empty())
+            .await?;
+        let lost = Arc::new(AtomicBool::new(false));
+        let callback_lost = lost.clone();
+        device.set_device_lost_callback(move |reason, message| {
+            if reason != wgpu::DeviceLostReason::Dropped {
+                error!("Graphics device lost ({:?}): {}", reason, message);
+                callback_lost.store(true, Ordering::SeqCst);
+            }
+        });
+        let shared = Arc::new(SharedDevice {
+            adapter,
+            device: Arc::new(device),
+            queue: Arc::new(queue),
+            lost,
+        });
+        *self.device.lock().unwrap() = Some(shared.clone());
+        Ok(shared)
+    }
+
+    /// Get a device, with any of `optional_features` that the adapter supports.
+    async fn request_device(
+        &self,
+        compatible_surface: Option<&wgpu::Surface<'_>>,
+        optional_features: wgpu::Features,
+    ) -> Result<(wgpu::Adapter, wgpu::Device, wgpu::Queue)> {
+        debug!("requesting adapter");
+        let mut adapter_options = wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::LowPower,
+            compatible_surface,
+            force_fallback_adapter: false,
+        };
+        let adapter = match self.instance.request_adapter(&adapter_options).await {
+            Some(adapter) => adapter,
+            None => {
+                warn!("No graphics adapter found, retrying with a software fallback");
+                adapter_options.force_fallback_adapter = true;
+                self.instance
+                    .request_adapter(&adapter_options)
+                    .await
+                    .ok_or(GraphicsError::NoAdapter)?
+            }
+        };
+        debug!("using adapter: {:?}", adapter.get_info());
+
+        let required_limits = self::bg::required_limits();
+        let mut missing_limit = None;
+        required_limits.check_limits_with_fail_fn(
+            &adapter.limits(),
+            true,
+            |name, required, available| {
+                missing_limit = Some(GraphicsError::Limit {
+                    name,
+                    required,
+                    available,
+                })
+            },
+        );
+        if let Some(e) = missing_limit {
+            return Err(e);
+        }
+
+        debug!("requesting device");
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: None,
+                    required_features: wgpu::Features::PUSH_CONSTANTS
+                        | (optional_features & adapter.features()),
+                    required_limits,
+                    memory_hints: Default::default(),
+                },
+                None, // Trace path
+            )
+            .await?;
+        Ok((adapter, device, queue))
+    }
+
+    /// Create the background, drawn to `bg_format`, and the icon, drawn to `format`.
+    fn create_layers(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        bg_format: wgpu::TextureFormat,
+        format: wgpu::TextureFormat,
+        screenshot: Option<crate::screencopy::ScreencopyBuffer>,
+        scale: u32,
+    ) -> Result<(self::bg::State, self::icon::State)> {
+        let bg = self::bg::State::new(
+            device,
+            queue,
+            bg_format,
+            self.program.clone(),
+            screenshot,
+            &self.channels,
+            self.config.clear_color,
+        )?;
+        let icon = self.create_icon(device, queue, format, scale)?;
+        Ok((bg, icon))
+    }
+
+    fn create_icon(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        format: wgpu::TextureFormat,
+        scale: u32,
+    ) -> Result<self::icon::State> {
+        let mut icon = self::icon::State::new(
+            device,
+            queue,
+            format,
+            &self.icon,
+            scale as f32,
+            self.config.icon_antialias,
+        )?;
+        if let Some(tint) = self.config.icon_tint {
+            icon.set_tint(queue, tint);
+        }
+        Ok(icon)
+    }
+
+    /// Create the message drawn below the icon, if there is one. It is placed when the window
+    /// is resized.
+    fn create_message(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        format: wgpu::TextureFormat,
+        scale: u32,
+    ) -> Result<Option<self::icon::State>> {
+        self.message
+            .as_ref()
+            .map(|message| {
+                self::icon::State::new(
+                    device,
+                    queue,
+                    format,
+                    message,
+                    scale as f32,
+                    self.config.icon_antialias,
+                )
+            })
+            .transpose()
+    }
+
+    /// Create a battery, layout or playing track indicator, if there is one. It is placed when the
+    /// window is resized.
+    fn create_corner_text(
+        &self,
+        text: Option<&self::icon::Icon>,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        format: wgpu::TextureFormat,
+        scale: u32,
+    ) -> Result<Option<self::icon::State>> {
+        text.map(|text| {
+            self::icon::State::new(
+                device,
+                queue,
+                format,
+                text,
+                scale as f32,
+                self.config.icon_antialias,
+            )
+        })
+        .transpose()
+    }
 }
+
+fn create_offscreen_texture(device: &wgpu::Device, (width, height): (u32, u32)) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Offscreen"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: OFFSCREEN_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    })
+}
+
+fn resolution_transform((width, height): (u32, u32)) -> cgmath::Matrix4<f32> {
+    // Empty sizes are never rendered, but shouldn't leave infinities behind.
+    let (width, height) = (width.max(1), height.max(1));
+    cgmath::Matrix4::from_nonuniform_scale(1.0 / width as f32, 1.0 / height as f32, 1.0)
+}
+
 pub struct State<'window> {
     surface: wgpu::Surface<'window>,
-    device: wgpu::Device,
-    queue: wgpu::Queue,
+    /// Shared with every other window.
+    device: Arc<wgpu::Device>,
+    queue: Arc<wgpu::Queue>,
     surface_config: wgpu::SurfaceConfiguration,
+    /// Logical size, which the surface is rendered at `scale` times.
+    size: (u32, u32),
+    scale: u32,
+    /// Fraction of the surface's resolution the background is rendered at.
+    render_scale: f32,
+    /// Size of the output in millimetres, if it reports one.
+    physical_size: Option<(u32, u32)>,
+    icon_size_mm: Option<f32>,
+    /// When this window's animation started, if it has its own.
+    time_origin: Option<std::time::Instant>,
+    /// What the compositor preferred when the window was initialized, which chose its format.
+    transfer_function: TransferFunction,
 
     bg: self::bg::State,
+    feedback: Option<Feedback>,
+    /// Where the background is rendered when it is smaller than the surface, without feedback.
+    upscale: Option<Upscale>,
+    backdrop: Option<self::backdrop::State>,
     icon: self::icon::State,
+    /// Drawn below the icon, and shown and hidden with it.
+    message: Option<self::icon::State>,
+    /// Drawn in the bottom right corner, and shown and hidden with the icon.
+    battery: Option<self::icon::State>,
+    /// Drawn in the bottom left corner, and shown and hidden with the icon.
+    layout: Option<self::icon::State>,
+    /// Drawn at the top centre, and shown and hidden with the icon.
+    now_playing: Option<self::icon::State>,
+    show_icon: bool,
+    device_lost: Arc<AtomicBool>,
 }
 
 impl State<'_> {
-    pub fn resize(&mut self, (width, height): (u32, u32)) {
+    /// Whether the icon is drawn over the background on this window.
+    pub fn set_icon_visible(&mut self, visible: bool) {
+        self.show_icon = visible;
+    }
+
+    /// Show the icon without animating it in, e.g. when the window was already showing.
+    pub fn skip_icon_intro(&mut self) {
+        self.icon.skip_intro();
+        if let Some(message) = &mut self.message {
+            message.skip_intro();
+        }
+        if let Some(battery) = &mut self.battery {
+            battery.skip_intro();
+        }
+        if let Some(layout) = &mut self.layout {
+            layout.skip_intro();
+        }
+        if let Some(now_playing) = &mut self.now_playing {
+            now_playing.skip_intro();
+        }
+    }
+
+    pub fn icon_visible(&self) -> bool {
+        self.show_icon
+    }
+
+    /// The transfer function the window was initialized for. It must be initialized again to
+    /// follow a change.
+    pub fn transfer_function(&self) -> TransferFunction {
+        self.transfer_function
+    }
+
+    /// When this window's animation started, with `Config::per_window_time`.
+    pub fn time_origin(&self) -> Option<std::time::Instant> {
+        self.time_origin
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    /// Set the output's physical size in millimetres, as the compositor reports it, to size
+    /// the icon with `Config::icon_size_mm`. Outputs such as projectors report zero.
+    pub fn set_physical_size(&mut self, (width, height): (i32, i32)) {
+        self.physical_size = (width > 0 && height > 0).then_some((width as u32, height as u32));
+        self.resize(self.size);
+    }
+
+    /// Change the logical size of the window.
+    pub fn resize(&mut self, size: (u32, u32)) {
+        // Surfaces can't be configured empty, so keep the last size until a real one arrives.
+        if size.0 == 0 || size.1 == 0 {
+            debug!("ignoring resize to empty size {:?}", size);
+            return;
+        }
+        self.size = size;
+        let (width, height) = (size.0 * self.scale, size.1 * self.scale);
         self.surface_config.width = width;
         self.surface_config.height = height;
         self.surface.configure(&self.device, &self.surface_config);
 
-        let resolution_transform =
-            cgmath::Matrix4::from_nonuniform_scale(1.0 / width as f32, 1.0 / height as f32, 1.0);
+        let bg_size = (
+            ((width as f32 * self.render_scale).round() as u32).max(1),
+            ((height as f32 * self.render_scale).round() as u32).max(1),
+        );
+        self.bg.resize(
+            &self.device,
+            &self.queue,
+            resolution_transform(bg_size),
+            bg_size,
+        );
+        if let Some(feedback) = &mut self.feedback {
+            feedback.resize(&self.device, &self.bg, bg_size);
+        }
+        if let Some(upscale) = &mut self.upscale {
+            upscale.resize(&self.device, bg_size);
+        }
 
-        self.bg.resize(&self.queue, resolution_transform);
+        let resolution_transform = resolution_transform((width, height));
+        self.icon.set_extent(self.icon_extent());
         self.icon.resize(&self.queue, resolution_transform);
+        self.place_message();
+        self.place_corner_text();
+        if let Some(backdrop) = &mut self.backdrop {
+            backdrop.resize(
+                &self.device,
+                &self.queue,
+                resolution_transform,
+                (width, height),
+                self.icon.size(),
+            );
+        }
+    }
+
+    /// Length of the icon's longer side in pixels to draw it at its size in millimetres, or
+    /// `None` if either isn't known.
+    fn icon_extent(&self) -> Option<f32> {
+        let size_mm = self.icon_size_mm?;
+        let (width_mm, height_mm) = self.physical_size?;
+        // Diagonals are the same whichever way round the output is rotated.
+        let (width, height) = (self.surface_config.width, self.surface_config.height);
+        let pixels_per_mm =
+            (width as f32).hypot(height as f32) / (width_mm as f32).hypot(height_mm as f32);
+        Some(size_mm * pixels_per_mm)
     }
 
-    pub fn render(&mut self, ctx: RenderContext) -> wgpu::SurfaceTexture {
-        let frame = self
-            .surface
-            .get_current_texture()
-            .expect("Timeout getting texture");
+    /// Move the message below the icon at its current size.
+    fn place_message(&mut self) {
+        if let Some(message) = &mut self.message {
+            let (_, icon_height) = self.icon.size();
+            let (_, height) = message.size();
+            message.set_offset((
+                0.0,
+                icon_height / 2.0 + MESSAGE_SPACING * self.scale as f32 + height / 2.0,
+            ));
+            message.resize(
+                &self.queue,
+                resolution_transform((self.surface_config.width, self.surface_config.height)),
+            );
+        }
+    }
+
+    /// Move the battery indicator to the bottom right corner, the layout indicator to the
+    /// bottom left corner and the playing track to the top centre at the current size.
+    fn place_corner_text(&mut self) {
+        let (width, height) = (self.surface_config.width, self.surface_config.height);
+        let margin = CORNER_MARGIN * self.scale as f32;
+        if let Some(battery) = &mut self.battery {
+            let (battery_width, battery_height) = battery.size();
+            battery.set_offset((
+                width as f32 / 2.0 - margin - battery_width / 2.0,
+                height as f32 / 2.0 - margin - battery_height / 2.0,
+            ));
+            battery.resize(&self.queue, resolution_transform((width, height)));
+        }
+        if let Some(layout) = &mut self.layout {
+            let (layout_width, layout_height) = layout.size();
+            layout.set_offset((
+                -(width as f32) / 2.0 + margin + layout_width / 2.0,
+                height as f32 / 2.0 - margin - layout_height / 2.0,
+            ));
+            layout.resize(&self.queue, resolution_transform((width, height)));
+        }
+        if let Some(now_playing) = &mut self.now_playing {
+            let (_, now_playing_height) = now_playing.size();
+            now_playing.set_offset((
+                0.0,
+                -(height as f32) / 2.0 + margin + now_playing_height / 2.0,
+            ));
+            now_playing.resize(&self.queue, resolution_transform((width, height)));
+        }
+    }
+
+    /// Render a frame, or `None` if the compositor is not ready for one.
+    ///
+    /// Errors mean the device was lost and the window must be initialized again.
+    pub fn render(&mut self, ctx: RenderContext) -> Result<Option<wgpu::SurfaceTexture>> {
+        if self.device_lost.load(Ordering::SeqCst) {
+            return Err(GraphicsError::DeviceLost);
+        }
+        let frame = match self.surface.get_current_texture() {
+            Result::Ok(frame) => frame,
+            Result::Err(wgpu::SurfaceError::Timeout) => {
+                warn!("Timed out getting surface texture, skipping frame");
+                return Ok(None);
+            }
+            Result::Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+                debug!("surface lost, reconfiguring");
+                self.surface.configure(&self.device, &self.surface_config);
+                self.surface.get_current_texture()?
+            }
+            Result::Err(e) => return Err(e.into()),
+        };
         let view = frame
             .texture
             .create_view(&wgpu::TextureViewDescriptor::default());
@@ -154,17 +1740,369 @@ impl State<'_> {
                 label: Some("Render Encoder"),
             });
 
-        self.bg.render(&mut encoder, &view, ctx);
-        self.icon.render(&mut encoder, &view);
+        // Shaders see the pointer in the background's pixels, as they do gl_FragCoord.
+        let pixels = self.scale as f32 * self.render_scale;
+        let ctx = RenderContext {
+            mouse: (ctx.mouse.0 * pixels, ctx.mouse.1 * pixels),
+            ..ctx
+        };
+        let icon_opacity = ctx.icon_opacity;
+        let blank = ctx.blank;
+        self.icon.update(&self.queue, icon_opacity);
+        if let Some(message) = &mut self.message {
+            message.update(&self.queue, icon_opacity);
+        }
+        if let Some(battery) = &mut self.battery {
+            battery.update(&self.queue, icon_opacity);
+        }
+        if let Some(layout) = &mut self.layout {
+            layout.update(&self.queue, icon_opacity);
+        }
+        if let Some(now_playing) = &mut self.now_playing {
+            now_playing.update(&self.queue, icon_opacity);
+        }
+        match &mut self.feedback {
+            Some(feedback) => {
+                let current = &feedback.frames[feedback.current];
+                let previous = &feedback.frames[1 - feedback.current];
+                self.bg.render(
+                    &mut encoder,
+                    &current.view,
+                    ctx,
+                    Some(&previous.as_previous_frame),
+                );
+                feedback
+                    .blit
+                    .render(&mut encoder, &current.as_blit_source, &view);
+                feedback.current = 1 - feedback.current;
+            }
+            None => match &self.upscale {
+                Some(Upscale {
+                    blit,
+                    frame: Some((frame_view, as_blit_source)),
+                    ..
+                }) => {
+                    self.bg.render(&mut encoder, frame_view, ctx, None);
+                    blit.render(&mut encoder, as_blit_source, &view);
+                }
+                _ => self.bg.render(&mut encoder, &view, ctx, None),
+            },
+        }
+        if self.show_icon && !blank {
+            if let Some(backdrop) = &mut self.backdrop {
+                backdrop.update(&self.queue, icon_opacity);
+                backdrop.render(&mut encoder, &frame.texture, &view);
+            }
+            self.icon.render(&mut encoder, &view);
+            if let Some(message) = &mut self.message {
+                message.render(&mut encoder, &view);
+            }
+            if let Some(battery) = &mut self.battery {
+                battery.render(&mut encoder, &view);
+            }
+            if let Some(layout) = &mut self.layout {
+                layout.render(&mut encoder, &view);
+            }
+            if let Some(now_playing) = &mut self.now_playing {
+                now_playing.render(&mut encoder, &view);
+            }
+        }
 
         // submit will accept anything that implements IntoIter
         self.queue.submit(std::iter::once(encoder.finish()));
 
-        frame
+        Ok(Some(frame))
+    }
+}
+
+/// Timings from `Manager::bench`.
+#[derive(Debug)]
+pub struct BenchResult {
+    pub frames: u32,
+    /// Time spent encoding and submitting frames.
+    pub cpu_time: Duration,
+    /// Time until the GPU finished every frame.
+    pub total_time: Duration,
+    /// Time the GPU spent between the first and last frame, if timestamp queries are supported.
+    pub gpu_time: Option<Duration>,
+}
+
+/// Two frame-sized textures the background is rendered to in turn, sampling the other one as the
+/// previous frame, before being copied to the surface.
+struct Feedback {
+    blit: self::blit::State,
+    format: wgpu::TextureFormat,
+    /// Empty until the first resize.
+    frames: Vec<FeedbackFrame>,
+    current: usize,
+}
+
+struct FeedbackFrame {
+    view: wgpu::TextureView,
+    as_previous_frame: wgpu::BindGroup,
+    as_blit_source: wgpu::BindGroup,
+}
+
+impl Feedback {
+    /// Frames are `format`, copied to a `target_format` window.
+    fn new(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        target_format: wgpu::TextureFormat,
+    ) -> Self {
+        Self {
+            blit: self::blit::State::new(device, target_format),
+            format,
+            frames: Vec::new(),
+            current: 0,
+        }
+    }
+
+    /// Recreate the frames at a new size, starting from transparent black.
+    fn resize(&mut self, device: &wgpu::Device, bg: &self::bg::State, (width, height): (u32, u32)) {
+        self.frames = (0..2)
+            .map(|_| {
+                let texture = device.create_texture(&wgpu::TextureDescriptor {
+                    label: Some("Feedback"),
+                    size: wgpu::Extent3d {
+                        width,
+                        height,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: self.format,
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                        | wgpu::TextureUsages::TEXTURE_BINDING,
+                    view_formats: &[],
+                });
+                let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+                FeedbackFrame {
+                    as_previous_frame: bg.bind_feedback(device, &view),
+                    as_blit_source: self.blit.bind(device, &view),
+                    view,
+                }
+            })
+            .collect();
+        self.current = 0;
+    }
+}
+
+/// A texture smaller than the surface that the background is rendered to, then upscaled onto the
+/// surface.
+struct Upscale {
+    blit: self::blit::State,
+    format: wgpu::TextureFormat,
+    /// Empty until the first resize.
+    frame: Option<(wgpu::TextureView, wgpu::BindGroup)>,
+}
+
+impl Upscale {
+    /// The texture is `format`, upscaled to a `target_format` window.
+    fn new(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        target_format: wgpu::TextureFormat,
+    ) -> Self {
+        Self {
+            blit: self::blit::State::new(device, target_format),
+            format,
+            frame: None,
+        }
+    }
+
+    /// Recreate the texture at the background's new size.
+    fn resize(&mut self, device: &wgpu::Device, (width, height): (u32, u32)) {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Upscale"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let as_blit_source = self.blit.bind(device, &view);
+        self.frame = Some((view, as_blit_source));
     }
 }
 
 pub struct RenderContext {
     pub elapsed: Duration,
     pub fade_amount: f32,
+    pub lock_state: LockState,
+    /// Opacity of the icon, from 0 (hidden) to 1.
+    pub icon_opacity: f32,
+    /// Position of this window's output in the order outputs were announced.
+    pub output_index: u32,
+    pub output_count: u32,
+    /// Characters typed so far.
+    pub password_length: u32,
+    /// Time since the last character was typed, if any has been.
+    pub last_char_age: Option<Duration>,
+    /// Charge of the machine's batteries, if it has any and the indicator is enabled.
+    pub battery: Option<crate::battery::Battery>,
+    /// Draw plain black instead of the shader and icon, e.g. after the panic key.
+    pub blank: bool,
+    /// From 0 until the password is accepted, rising to 1 as the outro plays.
+    pub unlock_progress: f32,
+    /// Whether this window has keyboard focus, so typing goes to it.
+    pub focused: bool,
+    /// How this window's output is rotated and flipped.
+    pub output_transform: Transform,
+    /// Values set over the control socket, for shaders to use however they like.
+    pub control: [f32; crate::control::CONTROLS],
+    /// Where the pointer last was over this window, in logical coordinates, or (0, 0) if it
+    /// hasn't been.
+    pub mouse: (f32, f32),
+}
+
+/// Progress through locking the session, exposed to shaders as `iLockState`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum LockState {
+    /// Connected to the compositor, nothing captured yet.
+    Starting = 0,
+    /// Capturing screenshots of each output.
+    Capturing = 1,
+    /// Lock requested, but the compositor has not confirmed it yet.
+    Locking = 2,
+    /// The session is locked.
+    Locked = 3,
+    /// The user is entering a password.
+    Authenticating = 4,
+    /// The password was accepted, and the outro is playing before unlocking.
+    Unlocking = 5,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The screenshot, drawn where `iTransform` says, as the bundled shaders draw it.
+    const SCREENSHOT_SHADER: &str = r#"#version 450
+
+layout(location=0) out vec4 f_color;
+
+layout(set = 0, binding = 0) uniform texture2D t_screenshot;
+layout(set = 0, binding = 1) uniform sampler s_screenshot;
+layout(set = 0, binding = 2) uniform Uniforms {
+    mat4 iTransform;
+};
+
+void main() {
+    vec4 ouv = iTransform * vec4(gl_FragCoord.xy, 0.0, 1.0);
+    f_color = texture(sampler2D(t_screenshot, s_screenshot), ouv.xy / ouv.w);
+}
+"#;
+
+    /// The screenshot comes out the right way up on GL, whose clip space is flipped. Skipped
+    /// without a GL adapter.
+    #[test]
+    fn gl_screenshot_orientation() {
+        let icon = IconSource::Data(
+            "icon.svg".into(),
+            br#"<svg xmlns="http://www.w3.org/2000/svg" width="1" height="1"/>"#.to_vec(),
+        );
+        let mut manager = Manager::new(
+            &Background::ShaderSource("screenshot.frag".into(), SCREENSHOT_SHADER.to_owned()),
+            &icon,
+            Default::default(),
+            Default::default(),
+        )
+        .unwrap();
+        manager.instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::GL,
+            ..Default::default()
+        });
+        match pollster::block_on(manager.request_device(None, wgpu::Features::empty())) {
+            Ok((adapter, ..)) if adapter.get_info().backend == wgpu::Backend::Gl => {}
+            _ => {
+                eprintln!("No GL adapter, skipping");
+                return;
+            }
+        }
+
+        // Red, green, blue and white corners, clockwise from the top left.
+        let corners = [[255, 0, 0], [0, 255, 0], [255, 255, 255], [0, 0, 255]];
+        let data = [corners[0], corners[1], corners[3], corners[2]]
+            .iter()
+            .flat_map(|&[r, g, b]| [r, g, b, 255])
+            .collect();
+        let screenshot = crate::screencopy::ScreencopyBuffer::from_raw(
+            2,
+            2,
+            8,
+            sctk::reexports::client::protocol::wl_shm::Format::Xbgr8888,
+            data,
+        )
+        .unwrap();
+        let ctx = RenderContext {
+            elapsed: Duration::ZERO,
+            fade_amount: 0.0,
+            lock_state: LockState::Locked,
+            icon_opacity: 0.0,
+            output_index: 0,
+            output_count: 1,
+            password_length: 0,
+            last_char_age: None,
+            battery: None,
+            blank: false,
+            unlock_progress: 0.0,
+            focused: true,
+            output_transform: Transform::Normal,
+            control: Default::default(),
+            mouse: (0.0, 0.0),
+        };
+        let frame = pollster::block_on(manager.render_offscreen(screenshot, (8, 8), ctx)).unwrap();
+        let rendered = [(0, 0), (7, 0), (7, 7), (0, 7)].map(|(x, y)| {
+            let [r, g, b, _] = frame.get_pixel(x, y).0;
+            [r, g, b]
+        });
+        assert_eq!(rendered, corners);
+    }
+
+    #[test]
+    fn select_entry_renames_main() {
+        let source = "#version 450\nvoid main() {}\nvoid night() {}\n".to_owned();
+        assert_eq!(select_entry(source.clone(), "main", false).unwrap(), source);
+        let selected = select_entry(source, "night", false).unwrap();
+        assert!(selected.starts_with("#version 450\n#define main _shaderlock_main\n#line 2\n"));
+        assert!(selected.ends_with("#undef main\nvoid main() { night(); }\n"));
+        for entry in ["", "1st", "main()", "night; discard"] {
+            assert!(
+                select_entry(String::new(), entry, false).is_err(),
+                "{:?}",
+                entry
+            );
+        }
+    }
+
+    #[test]
+    fn select_entry_flips_frag_coord() {
+        let source = "#version 450\nvoid main() { vec2 p = gl_FragCoord.xy; }\n".to_owned();
+        let selected = select_entry(source, "main", true).unwrap();
+        assert!(selected.contains("vec2 p = _shaderlock_FragCoord.xy;"));
+        assert!(selected.contains("_shaderlock.resolution.y - gl_FragCoord.y"));
+        assert!(selected.ends_with("_shaderlock_main(); }\n"));
+    }
+
+    #[test]
+    fn wgsl_entry_finds_fragment_function() {
+        let path = std::path::Path::new("test.wgsl");
+        let source = "// @fragment fn commented() {}\n\
+                      @vertex fn vs() -> @builtin(position) vec4<f32> { return vec4<f32>(0.0); }\n\
+                      @fragment fn fs() -> @location(0) vec4<f32> { return vec4<f32>(1.0); }\n\
+                      @fragment fn night() -> @location(0) vec4<f32> { return vec4<f32>(0.0); }\n";
+        assert_eq!(wgsl_entry(path, source, "main").unwrap(), "fs");
+        assert_eq!(wgsl_entry(path, source, "night").unwrap(), "night");
+        assert!(wgsl_entry(path, "@fragment fn", "main").is_err());
+    }
 }
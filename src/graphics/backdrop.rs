@@ -0,0 +1,317 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use wgpu::util::DeviceExt;
+
+use super::Backdrop;
+
+pub const VS_MAIN: &str = "main";
+pub const FS_MAIN: &str = "main";
+
+/// Space between the edge of the icon and the edge of the backdrop, relative to the icon's size
+/// so it stays in proportion at any scale.
+const PADDING: f32 = 0.25;
+const CORNER_RADIUS: f32 = 0.2;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct Uniforms {
+    transform: cgmath::Matrix4<f32>,
+    half_size: [f32; 2],
+    resolution: [f32; 2],
+    opacity: f32,
+    radius: f32,
+    blur: u32,
+    _padding: [u32; 1],
+}
+unsafe impl bytemuck::Pod for Uniforms {}
+unsafe impl bytemuck::Zeroable for Uniforms {}
+
+// Uniforms is read as a std140 block, so check the offsets match its alignment rules.
+const _: () = {
+    assert!(std::mem::offset_of!(Uniforms, transform) == 0);
+    assert!(std::mem::offset_of!(Uniforms, half_size) == 64);
+    assert!(std::mem::offset_of!(Uniforms, resolution) == 72);
+    assert!(std::mem::offset_of!(Uniforms, opacity) == 80);
+    assert!(std::mem::offset_of!(Uniforms, radius) == 84);
+    assert!(std::mem::offset_of!(Uniforms, blur) == 88);
+    assert!(std::mem::size_of::<Uniforms>().is_multiple_of(16));
+};
+
+/// Dims or blurs a rounded rectangle behind the icon, so it stays legible over busy shaders.
+pub struct State {
+    pipeline: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    sampler: wgpu::Sampler,
+    /// Copy of the frame so far, when blurring.
+    frame_copy: Option<wgpu::Texture>,
+    format: wgpu::TextureFormat,
+    uniforms: Uniforms,
+    uniforms_buffer: wgpu::Buffer,
+}
+
+impl State {
+    /// `backdrop` must not be `Backdrop::None`. Blurring copies the frame, so frames must be
+    /// created with `COPY_SRC` usage.
+    pub fn new(
+        device: &wgpu::Device,
+        swapchain_format: wgpu::TextureFormat,
+        backdrop: Backdrop,
+    ) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("backdrop bind_group_layout"),
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Backdrop Render pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Backdrop Render pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &device.create_shader_module(wgpu::include_spirv!(
+                    "../../resources/backdrop.vert.spv"
+                )),
+                entry_point: VS_MAIN,
+                buffers: &[],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &device.create_shader_module(wgpu::include_spirv!(
+                    "../../resources/backdrop.frag.spv"
+                )),
+                entry_point: FS_MAIN,
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: swapchain_format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleStrip,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: None,
+                ..Default::default()
+            },
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            depth_stencil: None,
+            multiview: None,
+            cache: None,
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let uniforms = Uniforms {
+            transform: cgmath::Matrix4::from_scale(0.0),
+            half_size: [0.0; 2],
+            resolution: [1.0; 2],
+            opacity: 1.0,
+            radius: 0.0,
+            blur: (backdrop == Backdrop::Blur) as u32,
+            _padding: [0; 1],
+        };
+        let uniforms_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Backdrop Uniforms Buffer"),
+            contents: bytemuck::cast_slice(&[uniforms]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        // Dimming never samples the frame, so it only needs a placeholder.
+        let frame_copy = create_frame_copy(device, swapchain_format, (1, 1));
+        let bind_group = create_bind_group(
+            device,
+            &bind_group_layout,
+            &frame_copy,
+            &sampler,
+            &uniforms_buffer,
+        );
+
+        Self {
+            pipeline,
+            bind_group_layout,
+            bind_group,
+            sampler,
+            frame_copy: (backdrop == Backdrop::Blur).then_some(frame_copy),
+            format: swapchain_format,
+            uniforms,
+            uniforms_buffer,
+        }
+    }
+
+    /// Fit the backdrop around an icon of the given size in pixels, centred in the frame.
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        resolution_transform: cgmath::Matrix4<f32>,
+        (width, height): (u32, u32),
+        (icon_width, icon_height): (f32, f32),
+    ) {
+        let icon_extent = icon_width.min(icon_height);
+        let padding = PADDING * icon_extent;
+        let half_size = [icon_width / 2.0 + padding, icon_height / 2.0 + padding];
+        self.uniforms.radius = CORNER_RADIUS * icon_extent;
+        self.uniforms.transform =
+            cgmath::Matrix4::from_nonuniform_scale(2.0 * half_size[0], 2.0 * half_size[1], 1.0)
+                * resolution_transform;
+        self.uniforms.half_size = half_size;
+        self.uniforms.resolution = [width as f32, height as f32];
+        queue.write_buffer(
+            &self.uniforms_buffer,
+            0,
+            bytemuck::cast_slice(&[self.uniforms]),
+        );
+
+        if self.frame_copy.is_some() {
+            let frame_copy = create_frame_copy(device, self.format, (width, height));
+            self.bind_group = create_bind_group(
+                device,
+                &self.bind_group_layout,
+                &frame_copy,
+                &self.sampler,
+                &self.uniforms_buffer,
+            );
+            self.frame_copy = Some(frame_copy);
+        }
+    }
+
+    /// Update per-frame uniforms, fading the backdrop with the icon.
+    pub fn update(&mut self, queue: &wgpu::Queue, opacity: f32) {
+        if self.uniforms.opacity == opacity {
+            return;
+        }
+        self.uniforms.opacity = opacity;
+        queue.write_buffer(
+            &self.uniforms_buffer,
+            0,
+            bytemuck::cast_slice(&[self.uniforms]),
+        );
+    }
+
+    pub fn render(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        frame: &wgpu::Texture,
+        view: &wgpu::TextureView,
+    ) {
+        if let Some(frame_copy) = &self.frame_copy {
+            encoder.copy_texture_to_texture(
+                frame.as_image_copy(),
+                frame_copy.as_image_copy(),
+                frame_copy.size(),
+            );
+        }
+
+        let mut rp = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("backdrop render pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        rp.set_pipeline(&self.pipeline);
+        rp.set_bind_group(0, &self.bind_group, &[]);
+        rp.draw(0..4, 0..1);
+    }
+}
+
+fn create_frame_copy(
+    device: &wgpu::Device,
+    format: wgpu::TextureFormat,
+    (width, height): (u32, u32),
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Backdrop frame copy"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    })
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    frame_copy: &wgpu::Texture,
+    sampler: &wgpu::Sampler,
+    uniforms_buffer: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    let view = frame_copy.create_view(&wgpu::TextureViewDescriptor::default());
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: uniforms_buffer.as_entire_binding(),
+            },
+        ],
+        label: Some("backdrop bind group"),
+    })
+}
@@ -1,4 +1,3 @@
-use anyhow::*;
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
 
@@ -7,19 +6,69 @@ use wgpu::util::DeviceExt;
 
 use crate::screencopy::ScreencopyBuffer;
 
-use super::RenderContext;
+use super::{Color, RenderContext, Result, CHANNELS};
+
+/// First binding of the `iChannelN` textures, which are followed by their shared sampler.
+pub const CHANNEL_BINDING: u32 = 3;
+
+/// Binding of a second view of the uniforms, which shaders with a flipped `gl_FragCoord` read the
+/// window height from.
+pub const FLIP_Y_BINDING: u32 = CHANNEL_BINDING + CHANNELS as u32 + 1;
+
+/// Format of buffer passes' textures, precise enough to carry state between frames.
+const BUFFER_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
 
 pub const VS_MAIN: &str = "main";
 pub const FS_MAIN: &str = "main";
 
+/// Fragment shader drawing the background.
+#[derive(Clone)]
+pub enum Shader {
+    /// A user-supplied shader, and its fragment entry point.
+    Custom(wgpu::ShaderSource<'static>, String),
+    /// The built-in shader, blending from the first colour at the top to the second at the bottom.
+    Gradient([Color; 2]),
+    /// The built-in shader drawing the screenshot as it is, or black without one.
+    Screenshot,
+}
+
+/// Fragment shaders for every pass drawing the background.
+#[derive(Clone)]
+pub struct Program {
+    /// Drawn to the window, after any buffers.
+    pub image: Shader,
+    /// Shadertoy-style buffers, each drawn to a texture bound in place of its `iChannelN`.
+    pub buffers: [Option<Shader>; CHANNELS],
+}
+
+impl From<Shader> for Program {
+    fn from(image: Shader) -> Self {
+        Self {
+            image,
+            buffers: Default::default(),
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 struct Uniforms {
     transform: cgmath::Matrix4<f32>,
+    colors: [[f32; 4]; 2],
+    resolution: [f32; 2],
+    _padding: [f32; 2],
 }
 unsafe impl bytemuck::Pod for Uniforms {}
 unsafe impl bytemuck::Zeroable for Uniforms {}
 
+// Uniforms is read as a std140 block, so check the offsets match its alignment rules.
+const _: () = {
+    assert!(std::mem::offset_of!(Uniforms, transform) == 0);
+    assert!(std::mem::offset_of!(Uniforms, colors) == 64);
+    assert!(std::mem::offset_of!(Uniforms, resolution) == 96);
+    assert!(std::mem::size_of::<Uniforms>().is_multiple_of(16));
+};
+
 struct UniformsHandle {
     data: Uniforms,
     texture_transform: cgmath::Matrix4<f32>,
@@ -31,171 +80,241 @@ struct UniformsHandle {
 struct FrameUniforms {
     elapsed: f32,
     fade_amount: f32,
+    lock_state: u32,
+    output_index: u32,
+    output_count: u32,
+    password_length: u32,
+    last_char_age: f32,
+    battery_level: f32,
+    battery_charging: u32,
+    unlock_progress: f32,
+    focused: u32,
+    output_transform: u32,
+    control: [f32; crate::control::CONTROLS],
+    resolution: [f32; 2],
+    aspect: f32,
+    _padding: f32,
+    mouse: [f32; 2],
+    _padding2: [f32; 2],
 }
 unsafe impl bytemuck::Pod for FrameUniforms {}
 unsafe impl bytemuck::Zeroable for FrameUniforms {}
 
-impl From<RenderContext> for FrameUniforms {
-    fn from(ctx: RenderContext) -> Self {
+// FrameUniforms is laid out as std140 so the same bytes can be used as push constants or a
+// uniform buffer on adapters without push constant support.
+const _: () = {
+    assert!(std::mem::offset_of!(FrameUniforms, elapsed) == 0);
+    assert!(std::mem::offset_of!(FrameUniforms, fade_amount) == 4);
+    assert!(std::mem::offset_of!(FrameUniforms, lock_state) == 8);
+    assert!(std::mem::offset_of!(FrameUniforms, output_index) == 12);
+    assert!(std::mem::offset_of!(FrameUniforms, output_count) == 16);
+    assert!(std::mem::offset_of!(FrameUniforms, password_length) == 20);
+    assert!(std::mem::offset_of!(FrameUniforms, last_char_age) == 24);
+    assert!(std::mem::offset_of!(FrameUniforms, battery_level) == 28);
+    assert!(std::mem::offset_of!(FrameUniforms, battery_charging) == 32);
+    assert!(std::mem::offset_of!(FrameUniforms, unlock_progress) == 36);
+    assert!(std::mem::offset_of!(FrameUniforms, focused) == 40);
+    assert!(std::mem::offset_of!(FrameUniforms, output_transform) == 44);
+    assert!(std::mem::offset_of!(FrameUniforms, control) == 48);
+    assert!(std::mem::offset_of!(FrameUniforms, resolution) == 64);
+    assert!(std::mem::offset_of!(FrameUniforms, aspect) == 72);
+    assert!(std::mem::offset_of!(FrameUniforms, mouse) == 80);
+    assert!(std::mem::size_of::<FrameUniforms>().is_multiple_of(16));
+};
+
+impl FrameUniforms {
+    /// Uniforms for a frame drawn at `size`, the size of the background's own texture.
+    fn new(ctx: RenderContext, (width, height): (u32, u32)) -> Self {
         Self {
             elapsed: ctx.elapsed.as_secs_f32(),
             fade_amount: ctx.fade_amount,
+            lock_state: ctx.lock_state as u32,
+            output_index: ctx.output_index,
+            output_count: ctx.output_count,
+            password_length: ctx.password_length,
+            last_char_age: ctx
+                .last_char_age
+                .map_or(f32::INFINITY, |age| age.as_secs_f32()),
+            battery_level: ctx.battery.map_or(-1.0, |battery| battery.level),
+            battery_charging: ctx.battery.is_some_and(|battery| battery.charging) as u32,
+            unlock_progress: ctx.unlock_progress,
+            focused: ctx.focused as u32,
+            output_transform: ctx.output_transform.into(),
+            control: ctx.control,
+            resolution: [width as f32, height as f32],
+            aspect: width as f32 / height.max(1) as f32,
+            _padding: 0.0,
+            mouse: [ctx.mouse.0, ctx.mouse.1],
+            _padding2: [0.0; 2],
         }
     }
 }
 
 pub const PUSH_CONSTANTS_SIZE: u32 = std::mem::size_of::<FrameUniforms>() as u32;
 
+/// Device limits needed by the bindings every background shader is given.
+pub fn required_limits() -> wgpu::Limits {
+    let defaults = wgpu::Limits::default();
+    wgpu::Limits {
+        max_push_constant_size: PUSH_CONSTANTS_SIZE,
+        // The screenshot plus each channel.
+        max_sampled_textures_per_shader_stage: defaults
+            .max_sampled_textures_per_shader_stage
+            .max(1 + CHANNELS as u32),
+        max_samplers_per_shader_stage: defaults.max_samplers_per_shader_stage.max(2),
+        ..defaults
+    }
+}
+
 pub struct State {
     pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    bind_group_layout: wgpu::BindGroupLayout,
     bind_group: wgpu::BindGroup,
+    feedback_layout: wgpu::BindGroupLayout,
+    /// Bound as the previous frame when feedback is disabled.
+    no_feedback: wgpu::BindGroup,
     uniforms_handle: UniformsHandle,
+    clear_color: Color,
+    screenshot: Option<(wgpu::TextureView, wgpu::Sampler)>,
+    channel_views: Vec<wgpu::TextureView>,
+    channel_sampler: wgpu::Sampler,
+    /// Passes drawn before the background each frame, in channel order.
+    buffers: Vec<Buffer>,
+    /// Inputs for each buffer pass and then the background, for each of the buffers' frames.
+    /// Empty without buffers or before the first resize.
+    pass_bind_groups: [Vec<wgpu::BindGroup>; 2],
+    /// Which of the buffers' frames is drawn to next.
+    current: usize,
+    size: (u32, u32),
+}
+
+/// A Shadertoy-style buffer pass, drawn to a texture that other passes read as a channel.
+struct Buffer {
+    /// The `iChannelN` the buffer is bound as.
+    channel: usize,
+    pipeline: wgpu::RenderPipeline,
+    /// Drawn to alternately, so the other holds the previous frame. Empty until the first resize.
+    frames: Vec<wgpu::TextureView>,
 }
 
 impl State {
-    pub fn new<'buffer>(
+    pub fn new(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         swapchain_format: wgpu::TextureFormat,
-        shader: wgpu::ShaderSource,
-        screenshot: ScreencopyBuffer,
+        program: Program,
+        screenshot: Option<ScreencopyBuffer>,
+        channels: &[Option<image::RgbaImage>; CHANNELS],
+        clear_color: Color,
     ) -> Result<Self> {
-        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        multisampled: false,
-                    },
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
-                },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 2,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
+        let channel_layout_entries = (0..=CHANNELS as u32).map(|i| wgpu::BindGroupLayoutEntry {
+            binding: CHANNEL_BINDING + i,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: if i < CHANNELS as u32 {
+                wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                }
+            } else {
+                wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering)
+            },
+            count: None,
+        });
+        let screenshot_layout_entries = [
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
                 },
-            ],
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+        ];
+        // Without a screenshot its bindings are left out, so shaders that sample it fail to build.
+        let mut layout_entries = match screenshot {
+            Some(_) => screenshot_layout_entries.to_vec(),
+            None => vec![],
+        };
+        layout_entries.push(wgpu::BindGroupLayoutEntry {
+            binding: 2,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+        layout_entries.extend(channel_layout_entries);
+        layout_entries.push(wgpu::BindGroupLayoutEntry {
+            binding: FLIP_Y_BINDING,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &layout_entries,
             label: Some("bind_group_layout"),
         });
 
+        // The previous frame has its own set, as it alternates between two textures each frame.
+        let feedback_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            }],
+            label: Some("feedback bind_group_layout"),
+        });
+
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("BG Render pipeline layout"),
-            bind_group_layouts: &[&bind_group_layout],
+            bind_group_layouts: &[&bind_group_layout, &feedback_layout],
             push_constant_ranges: &[wgpu::PushConstantRange {
                 stages: wgpu::ShaderStages::FRAGMENT,
                 range: 0..PUSH_CONSTANTS_SIZE,
             }],
         });
 
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("BG Render pipeline"),
-            layout: Some(&pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &device
-                    .create_shader_module(wgpu::include_spirv!("../../resources/bg.vert.spv")),
-                entry_point: VS_MAIN,
-                buffers: &[],
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &device.create_shader_module(wgpu::ShaderModuleDescriptor {
-                    label: Some("shader"),
-                    source: shader,
-                }),
-                entry_point: FS_MAIN,
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: swapchain_format,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-                compilation_options: wgpu::PipelineCompilationOptions::default(),
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleStrip,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: None,
-                ..Default::default()
-            },
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            depth_stencil: None,
-            multiview: None,
-            cache: None,
-        });
-
-        let texture_size = wgpu::Extent3d {
-            width: screenshot.width(),
-            height: screenshot.height(),
-            depth_or_array_layers: 1,
-        };
-        let texture_descriptor = wgpu::TextureDescriptor {
-            label: Some("Screenshot"),
-            size: texture_size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: texture_format_from_sctk(screenshot.format()),
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-            view_formats: &[],
-        };
-        let texture = device.create_texture(&texture_descriptor);
-        let texture_view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::MirrorRepeat,
-            address_mode_v: wgpu::AddressMode::MirrorRepeat,
-            address_mode_w: wgpu::AddressMode::MirrorRepeat,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Linear,
-            mipmap_filter: wgpu::FilterMode::Linear,
-            ..Default::default()
-        });
-
-        let stride = screenshot.stride();
-        let height = screenshot.height();
-        let width = screenshot.width();
-        queue.write_texture(
-            wgpu::ImageCopyTexture {
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-                aspect: wgpu::TextureAspect::All,
-            },
-            screenshot.bytes(),
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(stride),
-                rows_per_image: Some(height),
-            },
-            wgpu::Extent3d {
-                width,
-                height,
-                depth_or_array_layers: 1,
-            },
+        let (pipeline, colors) = create_pipeline(
+            device,
+            &pipeline_layout,
+            swapchain_format,
+            program.image,
+            screenshot.is_some(),
         );
 
-        let texture_transform =
-            cgmath::Matrix4::from_translation(cgmath::Vector3::new(0.5, 0.5, 0.0))
-                * screenshot.transform_matrix()
-                * cgmath::Matrix4::from_translation(cgmath::Vector3::new(-0.5, -0.5, 0.0));
+        let screenshot = screenshot.map(|screenshot| upload_screenshot(device, queue, screenshot));
+        let texture_transform = match &screenshot {
+            Some((_, _, transform)) => *transform,
+            None => cgmath::Matrix4::from_scale(1.0),
+        };
+        let screenshot = screenshot.map(|(texture_view, sampler, _)| (texture_view, sampler));
         let uniforms = Uniforms {
             transform: texture_transform,
+            colors: colors.map(|c| c.0),
+            resolution: [0.0; 2],
+            _padding: [0.0; 2],
         };
 
         let uniforms_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -204,45 +323,130 @@ impl State {
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&texture_view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&sampler),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: uniforms_buffer.as_entire_binding(),
-                },
-            ],
-            label: Some("bg bind group"),
+        let channel_views = channels
+            .iter()
+            .map(|channel| create_channel_texture(device, queue, channel.as_ref()))
+            .collect::<Vec<_>>();
+        let channel_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
         });
 
+        let bind_group = create_bind_group(
+            device,
+            &bind_group_layout,
+            screenshot.as_ref(),
+            &uniforms_buffer,
+            &channel_views.iter().collect::<Vec<_>>(),
+            &channel_sampler,
+        );
+
         let uniforms_handle = UniformsHandle {
             data: uniforms,
             texture_transform,
             buffer: uniforms_buffer,
         };
 
-        Ok(Self {
+        let no_feedback = create_feedback_bind_group(
+            device,
+            &feedback_layout,
+            &create_channel_texture(device, queue, None),
+        );
+
+        let mut me = Self {
             pipeline,
+            pipeline_layout,
+            format: swapchain_format,
+            bind_group_layout,
             bind_group,
+            feedback_layout,
+            no_feedback,
             uniforms_handle,
-        })
+            clear_color,
+            screenshot,
+            channel_views,
+            channel_sampler,
+            buffers: vec![],
+            pass_bind_groups: Default::default(),
+            current: 0,
+            size: (0, 0),
+        };
+        me.set_buffers(device, program.buffers);
+        Ok(me)
     }
-    pub fn resize(&mut self, queue: &wgpu::Queue, resolution_transform: cgmath::Matrix4<f32>) {
+
+    /// Bind a previous frame, to be passed to `render`.
+    pub fn bind_feedback(
+        &self,
+        device: &wgpu::Device,
+        previous_frame: &wgpu::TextureView,
+    ) -> wgpu::BindGroup {
+        create_feedback_bind_group(device, &self.feedback_layout, previous_frame)
+    }
+
+    /// Replace the fragment shaders, keeping the screenshot and other inputs.
+    pub fn set_shader(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, program: Program) {
+        let (pipeline, colors) = create_pipeline(
+            device,
+            &self.pipeline_layout,
+            self.format,
+            program.image,
+            self.screenshot.is_some(),
+        );
+        self.pipeline = pipeline;
+        self.set_buffers(device, program.buffers);
+        self.uniforms_handle.data.colors = colors.map(|c| c.0);
+        queue.write_buffer(
+            &self.uniforms_handle.buffer,
+            0,
+            bytemuck::cast_slice(&[self.uniforms_handle.data]),
+        );
+    }
+
+    /// Replace the image bound as `iChannelN`, e.g. when album art arrives after locking.
+    /// Buffers bound to the same channel take precedence, as they do when creating the state.
+    pub fn set_channel(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        channel: usize,
+        image: Option<&image::RgbaImage>,
+    ) {
+        self.channel_views[channel] = create_channel_texture(device, queue, image);
+        self.bind_group = create_bind_group(
+            device,
+            &self.bind_group_layout,
+            self.screenshot.as_ref(),
+            &self.uniforms_handle.buffer,
+            &self.channel_views.iter().collect::<Vec<_>>(),
+            &self.channel_sampler,
+        );
+        // Without buffers, or before the first resize, there are no passes to bind yet.
+        if !self.pass_bind_groups[0].is_empty() {
+            self.bind_passes(device);
+        }
+    }
+
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        resolution_transform: cgmath::Matrix4<f32>,
+        (width, height): (u32, u32),
+    ) {
         self.uniforms_handle.data.transform =
             self.uniforms_handle.texture_transform * resolution_transform;
+        self.uniforms_handle.data.resolution = [width as f32, height as f32];
         queue.write_buffer(
             &self.uniforms_handle.buffer,
             0,
             bytemuck::cast_slice(&[self.uniforms_handle.data]),
         );
+        self.size = (width, height);
+        self.create_buffer_frames(device);
     }
 
     pub fn render(
@@ -250,37 +454,396 @@ impl State {
         encoder: &mut wgpu::CommandEncoder,
         view: &wgpu::TextureView,
         ctx: RenderContext,
+        feedback: Option<&wgpu::BindGroup>,
     ) {
-        let mut rp = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            label: Some("BG render pass"),
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &view,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color {
-                        r: 1.0,
-                        g: 0.0,
-                        b: 0.0,
-                        a: 1.0,
-                    }),
-                    store: wgpu::StoreOp::Store,
-                },
-            })],
-            depth_stencil_attachment: None,
-            timestamp_writes: None,
-            occlusion_query_set: None,
+        let blank = ctx.blank;
+        let uniforms = FrameUniforms::new(ctx, self.size);
+        let bind_groups = &self.pass_bind_groups[self.current];
+        let bind_group = match bind_groups.last() {
+            Some(bind_group) if !blank => {
+                for (buffer, bind_group) in self.buffers.iter().zip(bind_groups) {
+                    let mut rp = begin_pass(
+                        encoder,
+                        &buffer.frames[self.current],
+                        "Buffer render pass",
+                        Color([0.0; 4]),
+                    );
+                    rp.set_pipeline(&buffer.pipeline);
+                    rp.set_bind_group(0, bind_group, &[]);
+                    rp.set_bind_group(1, &self.no_feedback, &[]);
+                    rp.set_push_constants(
+                        wgpu::ShaderStages::FRAGMENT,
+                        0,
+                        bytemuck::cast_slice(&[uniforms]),
+                    );
+                    rp.draw(0..4, 0..1);
+                }
+                bind_group
+            }
+            _ => &self.bind_group,
+        };
+
+        let clear_color = if blank {
+            Color::BLACK
+        } else {
+            self.clear_color
+        };
+        let mut rp = begin_pass(encoder, view, "BG render pass", clear_color);
+        if !blank {
+            rp.set_pipeline(&self.pipeline);
+            rp.set_bind_group(0, bind_group, &[]);
+            rp.set_bind_group(1, feedback.unwrap_or(&self.no_feedback), &[]);
+            rp.set_push_constants(
+                wgpu::ShaderStages::FRAGMENT,
+                0,
+                bytemuck::cast_slice(&[uniforms]),
+            );
+            rp.draw(0..4, 0..1);
+        }
+        drop(rp);
+        if !blank && !self.pass_bind_groups[self.current].is_empty() {
+            self.current = 1 - self.current;
+        }
+    }
+
+    /// Replace the buffer passes, which start over from transparent black.
+    fn set_buffers(&mut self, device: &wgpu::Device, shaders: [Option<Shader>; CHANNELS]) {
+        // By value: on edition 2018, `shaders.into_iter()` would iterate by reference.
+        self.buffers = IntoIterator::into_iter(shaders)
+            .enumerate()
+            .filter_map(|(channel, shader)| {
+                let (pipeline, _) = create_pipeline(
+                    device,
+                    &self.pipeline_layout,
+                    BUFFER_FORMAT,
+                    shader?,
+                    self.screenshot.is_some(),
+                );
+                Some(Buffer {
+                    channel,
+                    pipeline,
+                    frames: vec![],
+                })
+            })
+            .collect();
+        self.create_buffer_frames(device);
+    }
+
+    /// Recreate the buffers' frames at the current size, and bind them as each pass's channels.
+    fn create_buffer_frames(&mut self, device: &wgpu::Device) {
+        self.current = 0;
+        self.pass_bind_groups = Default::default();
+        let (width, height) = self.size;
+        if self.buffers.is_empty() || width == 0 || height == 0 {
+            return;
+        }
+        for buffer in &mut self.buffers {
+            buffer.frames = (0..2)
+                .map(|_| {
+                    device
+                        .create_texture(&wgpu::TextureDescriptor {
+                            label: Some("Buffer"),
+                            size: wgpu::Extent3d {
+                                width,
+                                height,
+                                depth_or_array_layers: 1,
+                            },
+                            mip_level_count: 1,
+                            sample_count: 1,
+                            dimension: wgpu::TextureDimension::D2,
+                            format: BUFFER_FORMAT,
+                            usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                                | wgpu::TextureUsages::TEXTURE_BINDING,
+                            view_formats: &[],
+                        })
+                        .create_view(&wgpu::TextureViewDescriptor::default())
+                })
+                .collect();
+        }
+        self.bind_passes(device);
+    }
+
+    /// Bind the channels and the buffers' frames as each pass's inputs.
+    fn bind_passes(&mut self, device: &wgpu::Device) {
+        self.pass_bind_groups = [0, 1].map(|current| {
+            (0..=self.buffers.len())
+                .map(|pass| {
+                    let mut channel_views = self.channel_views.iter().collect::<Vec<_>>();
+                    for (index, buffer) in self.buffers.iter().enumerate() {
+                        // Buffers drawn earlier in the frame are read as just drawn, and the
+                        // rest, including the pass's own, as they were the frame before.
+                        let frame = if index < pass { current } else { 1 - current };
+                        channel_views[buffer.channel] = &buffer.frames[frame];
+                    }
+                    create_bind_group(
+                        device,
+                        &self.bind_group_layout,
+                        self.screenshot.as_ref(),
+                        &self.uniforms_handle.buffer,
+                        &channel_views,
+                        &self.channel_sampler,
+                    )
+                })
+                .collect()
         });
-        rp.set_pipeline(&self.pipeline);
-        rp.set_bind_group(0, &self.bind_group, &[]); // NEW!
-        rp.set_push_constants(
-            wgpu::ShaderStages::FRAGMENT,
-            0,
-            bytemuck::cast_slice(&[FrameUniforms::from(ctx)]),
-        );
-        rp.draw(0..4, 0..1);
     }
 }
 
+fn begin_pass<'encoder>(
+    encoder: &'encoder mut wgpu::CommandEncoder,
+    view: &wgpu::TextureView,
+    label: &str,
+    clear_color: Color,
+) -> wgpu::RenderPass<'encoder> {
+    encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some(label),
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color {
+                    r: clear_color.0[0] as f64,
+                    g: clear_color.0[1] as f64,
+                    b: clear_color.0[2] as f64,
+                    a: clear_color.0[3] as f64,
+                }),
+                store: wgpu::StoreOp::Store,
+            },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+    })
+}
+
+/// Bind the inputs every pass is given, with a view for each `iChannelN`.
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    screenshot: Option<&(wgpu::TextureView, wgpu::Sampler)>,
+    uniforms_buffer: &wgpu::Buffer,
+    channel_views: &[&wgpu::TextureView],
+    channel_sampler: &wgpu::Sampler,
+) -> wgpu::BindGroup {
+    let channel_entries = channel_views
+        .iter()
+        .map(|view| wgpu::BindingResource::TextureView(view))
+        .chain(std::iter::once(wgpu::BindingResource::Sampler(
+            channel_sampler,
+        )))
+        .zip(CHANNEL_BINDING..)
+        .map(|(resource, binding)| wgpu::BindGroupEntry { binding, resource });
+
+    let mut entries = match screenshot {
+        Some((texture_view, sampler)) => vec![
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(texture_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+        ],
+        None => vec![],
+    };
+    entries.push(wgpu::BindGroupEntry {
+        binding: 2,
+        resource: uniforms_buffer.as_entire_binding(),
+    });
+    entries.extend(channel_entries);
+    entries.push(wgpu::BindGroupEntry {
+        binding: FLIP_Y_BINDING,
+        resource: uniforms_buffer.as_entire_binding(),
+    });
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &entries,
+        label: Some("bg bind group"),
+    })
+}
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    pipeline_layout: &wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    shader: Shader,
+    has_screenshot: bool,
+) -> (wgpu::RenderPipeline, [Color; 2]) {
+    let (fragment_module, entry_point, colors) = match shader {
+        Shader::Screenshot if has_screenshot => (
+            device
+                .create_shader_module(wgpu::include_spirv!("../../resources/screenshot.frag.spv")),
+            FS_MAIN.to_owned(),
+            [Color::BLACK; 2],
+        ),
+        // Its bindings are left out without a screenshot, so draw black instead.
+        Shader::Screenshot => (
+            device.create_shader_module(wgpu::include_spirv!("../../resources/gradient.frag.spv")),
+            FS_MAIN.to_owned(),
+            [Color::BLACK; 2],
+        ),
+        Shader::Custom(source, entry_point) => (
+            device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("shader"),
+                source,
+            }),
+            entry_point,
+            [Color::BLACK; 2],
+        ),
+        Shader::Gradient(colors) => (
+            device.create_shader_module(wgpu::include_spirv!("../../resources/gradient.frag.spv")),
+            FS_MAIN.to_owned(),
+            colors,
+        ),
+    };
+
+    let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("BG Render pipeline"),
+        layout: Some(pipeline_layout),
+        vertex: wgpu::VertexState {
+            module: &device
+                .create_shader_module(wgpu::include_spirv!("../../resources/bg.vert.spv")),
+            entry_point: VS_MAIN,
+            buffers: &[],
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &fragment_module,
+            entry_point: &entry_point,
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleStrip,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: None,
+            ..Default::default()
+        },
+        multisample: wgpu::MultisampleState {
+            count: 1,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        depth_stencil: None,
+        multiview: None,
+        cache: None,
+    });
+    (pipeline, colors)
+}
+
+fn create_feedback_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    previous_frame: &wgpu::TextureView,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: wgpu::BindingResource::TextureView(previous_frame),
+        }],
+        label: Some("feedback bind group"),
+    })
+}
+
+/// Upload a screenshot for binding as `t_screenshot`, with the transform from normalized window
+/// coordinates to its texture coordinates.
+fn upload_screenshot(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    screenshot: ScreencopyBuffer,
+) -> (wgpu::TextureView, wgpu::Sampler, cgmath::Matrix4<f32>) {
+    let texture_size = wgpu::Extent3d {
+        width: screenshot.width(),
+        height: screenshot.height(),
+        depth_or_array_layers: 1,
+    };
+    let texture_descriptor = wgpu::TextureDescriptor {
+        label: Some("Screenshot"),
+        size: texture_size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: texture_format_from_sctk(screenshot.format()),
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    };
+    let texture = device.create_texture(&texture_descriptor);
+    let texture_view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        address_mode_u: wgpu::AddressMode::MirrorRepeat,
+        address_mode_v: wgpu::AddressMode::MirrorRepeat,
+        address_mode_w: wgpu::AddressMode::MirrorRepeat,
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        mipmap_filter: wgpu::FilterMode::Linear,
+        ..Default::default()
+    });
+
+    let stride = screenshot.stride();
+    let height = screenshot.height();
+    let width = screenshot.width();
+    queue.write_texture(
+        wgpu::ImageCopyTexture {
+            texture: &texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+            aspect: wgpu::TextureAspect::All,
+        },
+        screenshot.bytes(),
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(stride),
+            rows_per_image: Some(height),
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+
+    let texture_transform = cgmath::Matrix4::from_translation(cgmath::Vector3::new(0.5, 0.5, 0.0))
+        * screenshot.transform_matrix()
+        * cgmath::Matrix4::from_translation(cgmath::Vector3::new(-0.5, -0.5, 0.0));
+    (texture_view, sampler, texture_transform)
+}
+
+/// Upload an image for an `iChannelN` binding. Unset channels are a single transparent texel.
+fn create_channel_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    image: Option<&image::RgbaImage>,
+) -> wgpu::TextureView {
+    let empty = image::RgbaImage::new(1, 1);
+    let image = image.unwrap_or(&empty);
+    let texture = device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("Channel"),
+            size: wgpu::Extent3d {
+                width: image.width(),
+                height: image.height(),
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        },
+        wgpu::util::TextureDataOrder::LayerMajor,
+        image.as_raw(),
+    );
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
+
 fn texture_format_from_sctk(f: Format) -> wgpu::TextureFormat {
     use wgpu::TextureFormat::*;
     use Format::*;
@@ -1,30 +1,241 @@
-use anyhow::*;
+use std::borrow::Cow;
+
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
 
+use resvg::{tiny_skia, usvg};
 use wgpu::util::DeviceExt;
 
+use super::{Color, GraphicsError, Result};
+
 pub const VS_MAIN: &str = "main";
 pub const FS_MAIN: &str = "main";
 
+/// How long the icon takes to animate in after the window is configured.
+const INTRO_DURATION: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Size of message text, in logical pixels.
+const TEXT_SIZE: f32 = 24.0;
+/// Width of the outline keeping message text legible over light backgrounds.
+const TEXT_OUTLINE: f32 = 3.0;
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 struct Uniforms {
     transform: cgmath::Matrix4<f32>,
+    opacity: f32,
+    intro: f32,
+    _padding: [f32; 2],
+    /// Multiplies the icon's colour, leaving its alpha alone.
+    tint: [f32; 4],
 }
 unsafe impl bytemuck::Pod for Uniforms {}
 unsafe impl bytemuck::Zeroable for Uniforms {}
 
+// Uniforms is read as a std140 block, so check the offsets match its alignment rules.
+const _: () = {
+    assert!(std::mem::offset_of!(Uniforms, transform) == 0);
+    assert!(std::mem::offset_of!(Uniforms, opacity) == 64);
+    assert!(std::mem::offset_of!(Uniforms, intro) == 68);
+    assert!(std::mem::offset_of!(Uniforms, tint) == 80);
+    assert!(std::mem::size_of::<Uniforms>().is_multiple_of(16));
+};
+
 struct UniformsHandle {
     data: Uniforms,
     texture_transform: cgmath::Matrix4<f32>,
     buffer: wgpu::Buffer,
 }
 
+/// Source image for the lock icon.
+pub enum Icon {
+    Raster(image::RgbaImage),
+    /// Vector icons are rasterized per-window, so they stay sharp at any resolution.
+    Svg(Box<usvg::Tree>),
+}
+
+impl Icon {
+    pub fn open(path: &std::path::Path) -> Result<Self> {
+        let data = std::fs::read(path).map_err(|source| match source.kind() {
+            std::io::ErrorKind::NotFound => GraphicsError::IconNotFound(absolute(path)),
+            _ => GraphicsError::Read {
+                path: path.to_owned(),
+                source,
+            },
+        })?;
+        Self::from_data(&absolute(path), &data)
+    }
+
+    /// Decode an icon file's contents. `path` gives its format by extension, and names it in
+    /// errors.
+    pub fn from_data(path: &std::path::Path, data: &[u8]) -> Result<Self> {
+        let is_svg = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("svg"));
+        if is_svg {
+            let tree = usvg::Tree::from_data(data, &usvg::Options::default())?;
+            return Ok(Icon::Svg(Box::new(tree)));
+        }
+        let format = image::ImageFormat::from_path(path)
+            .or_else(|_| image::guess_format(data))
+            .map_err(|source| GraphicsError::IconFormat {
+                path: path.to_owned(),
+                source,
+            })?;
+        let icon = image::load_from_memory_with_format(data, format).map_err(|source| {
+            let path = path.to_owned();
+            match source {
+                image::ImageError::Unsupported(_) => GraphicsError::IconFormat { path, source },
+                image::ImageError::Decoding(_) => GraphicsError::IconCorrupt { path, source },
+                _ => GraphicsError::Icon { path, source },
+            }
+        })?;
+        Ok(Icon::Raster(icon.into_rgba8()))
+    }
+
+    /// Lay out a line of text with the system's sans-serif font, to be drawn like an icon.
+    pub fn text(text: &str) -> Result<Self> {
+        let mut options = usvg::Options::default();
+        options.fontdb_mut().load_system_fonts();
+        let element = format!(
+            r#"<text font-family="sans-serif" font-size="{}" fill="white" stroke="black" stroke-width="{}" paint-order="stroke">{}</text>"#,
+            TEXT_SIZE,
+            TEXT_OUTLINE,
+            escape_xml(text),
+        );
+
+        // Lay the text out once to measure it, then again on a canvas that fits it exactly.
+        let measure = usvg::Tree::from_str(
+            &format!(
+                r#"<svg xmlns="{}" width="1" height="1">{}</svg>"#,
+                SVG_NS, element
+            ),
+            &options,
+        )?;
+        if !measure.root().has_children() {
+            return Err(GraphicsError::NoFont);
+        }
+        let bounds = measure.root().abs_stroke_bounding_box();
+        let svg = format!(
+            r#"<svg xmlns="{ns}" width="{w}" height="{h}" viewBox="{x} {y} {w} {h}">{element}</svg>"#,
+            ns = SVG_NS,
+            x = bounds.x(),
+            y = bounds.y(),
+            w = bounds.width().ceil(),
+            h = bounds.height().ceil(),
+            element = element,
+        );
+        Ok(Icon::Svg(Box::new(usvg::Tree::from_str(&svg, &options)?)))
+    }
+
+    /// Size of the icon in logical pixels.
+    fn size(&self) -> (f32, f32) {
+        match self {
+            Icon::Raster(image) => (image.width() as f32, image.height() as f32),
+            Icon::Svg(tree) => (tree.size().width(), tree.size().height()),
+        }
+    }
+
+    /// Get the icon as an image, rendering vector icons `scale` times their intrinsic size.
+    fn rasterize(&self, scale: f32) -> Result<Cow<'_, image::RgbaImage>> {
+        match self {
+            Icon::Raster(image) => Ok(Cow::Borrowed(image)),
+            Icon::Svg(tree) => {
+                let size = tree
+                    .size()
+                    .to_int_size()
+                    .scale_by(scale)
+                    .ok_or(GraphicsError::SvgSize)?;
+                let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+                    .ok_or(GraphicsError::SvgSize)?;
+                resvg::render(
+                    tree,
+                    tiny_skia::Transform::from_scale(scale, scale),
+                    &mut pixmap.as_mut(),
+                );
+
+                // tiny-skia works in premultiplied alpha, but the icon pipeline blends straight alpha.
+                let data = pixmap
+                    .pixels()
+                    .iter()
+                    .flat_map(|p| {
+                        let c = p.demultiply();
+                        [c.red(), c.green(), c.blue(), c.alpha()]
+                    })
+                    .collect();
+                let image = image::RgbaImage::from_raw(size.width(), size.height(), data)
+                    .expect("pixmap matches icon size");
+                Ok(Cow::Owned(image))
+            }
+        }
+    }
+}
+
+/// The path resolved against the working directory, so errors show where a relative path led.
+fn absolute(path: &std::path::Path) -> std::path::PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_owned())
+}
+
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+
+fn escape_xml(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_owned(),
+            '<' => "&lt;".to_owned(),
+            '>' => "&gt;".to_owned(),
+            '"' => "&quot;".to_owned(),
+            '\'' => "&apos;".to_owned(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Successively halved copies of the image, down to 1x1, for the mip levels below it.
+///
+/// Filtered in premultiplied alpha, as the colour of transparent pixels would otherwise bleed
+/// into their neighbours, darkening the edges of downscaled icons.
+fn mip_chain(image: &image::RgbaImage) -> Vec<image::RgbaImage> {
+    let mut premultiplied = image::DynamicImage::from(image.clone()).into_rgba32f();
+    for pixel in premultiplied.pixels_mut() {
+        let alpha = pixel[3];
+        pixel.0[..3].iter_mut().for_each(|c| *c *= alpha);
+    }
+    let mut levels = vec![];
+    let (mut width, mut height) = image.dimensions();
+    while width > 1 || height > 1 {
+        width = (width / 2).max(1);
+        height = (height / 2).max(1);
+        premultiplied = image::imageops::resize(
+            &premultiplied,
+            width,
+            height,
+            image::imageops::FilterType::Triangle,
+        );
+        let mut level = premultiplied.clone();
+        for pixel in level.pixels_mut() {
+            let alpha = pixel[3];
+            if alpha > 0.0 {
+                pixel.0[..3].iter_mut().for_each(|c| *c /= alpha);
+            }
+        }
+        levels.push(image::DynamicImage::from(level).into_rgba8());
+    }
+    levels
+}
+
 pub struct State {
     pipeline: wgpu::RenderPipeline,
     bind_group: wgpu::BindGroup,
     uniforms_handle: UniformsHandle,
+    /// Size of the texture, in pixels.
+    size: (f32, f32),
+    /// Length of the longer side to draw at instead, in pixels.
+    extent: Option<f32>,
+    /// Distance of the centre from the centre of the window, in pixels, with y pointing down.
+    offset: (f32, f32),
+    /// When the intro animation started, or `None` once it is skipped.
+    intro_start: Option<std::time::Instant>,
 }
 
 impl State {
@@ -32,8 +243,13 @@ impl State {
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         swapchain_format: wgpu::TextureFormat,
-        icon: &image::RgbaImage,
+        icon: &Icon,
+        scale: f32,
+        antialias: bool,
     ) -> Result<Self> {
+        let (width, height) = icon.size();
+        let icon = icon.rasterize(scale)?;
+        let mip_levels = if antialias { mip_chain(&icon) } else { vec![] };
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[
                 wgpu::BindGroupLayoutEntry {
@@ -54,7 +270,7 @@ impl State {
                 },
                 wgpu::BindGroupLayoutEntry {
                     binding: 2,
-                    visibility: wgpu::ShaderStages::VERTEX,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Uniform,
                         has_dynamic_offset: false,
@@ -117,7 +333,7 @@ impl State {
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label: Some("Icon"),
             size: texture_size,
-            mip_level_count: 1,
+            mip_level_count: 1 + mip_levels.len() as u32,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format: wgpu::TextureFormat::Rgba8UnormSrgb,
@@ -125,40 +341,51 @@ impl State {
             view_formats: &[],
         });
         let texture_view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let filter = if antialias {
+            wgpu::FilterMode::Linear
+        } else {
+            wgpu::FilterMode::Nearest
+        };
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
             address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Nearest,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
+            mag_filter: filter,
+            min_filter: filter,
+            mipmap_filter: filter,
             ..Default::default()
         });
 
-        queue.write_texture(
-            wgpu::ImageCopyTexture {
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-                aspect: wgpu::TextureAspect::All,
-            },
-            icon,
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(4 * icon.width()),
-                rows_per_image: Some(icon.height()),
-            },
-            wgpu::Extent3d {
-                width: icon.width(),
-                height: icon.height(),
-                depth_or_array_layers: 1,
-            },
-        );
+        for (mip_level, image) in std::iter::once(&*icon).chain(&mip_levels).enumerate() {
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &texture,
+                    mip_level: mip_level as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                image,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * image.width()),
+                    rows_per_image: Some(image.height()),
+                },
+                wgpu::Extent3d {
+                    width: image.width(),
+                    height: image.height(),
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
 
         let texture_transform =
-            cgmath::Matrix4::from_nonuniform_scale(icon.width() as f32, icon.height() as f32, 1.0);
+            cgmath::Matrix4::from_nonuniform_scale(width * scale, height * scale, 1.0);
         let uniforms = Uniforms {
             transform: texture_transform,
+            opacity: 1.0,
+            intro: 0.0,
+            _padding: [0.0; 2],
+            tint: [1.0; 4],
         };
 
         let uniforms_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -196,12 +423,87 @@ impl State {
             pipeline,
             bind_group,
             uniforms_handle,
+            size: (width * scale, height * scale),
+            extent: None,
+            offset: (0.0, 0.0),
+            intro_start: Some(std::time::Instant::now()),
         })
     }
 
+    /// Size the icon is drawn at, in pixels.
+    pub fn size(&self) -> (f32, f32) {
+        let (width, height) = self.size;
+        let zoom = self.zoom();
+        (width * zoom, height * zoom)
+    }
+
+    /// How much larger than its own size the icon is drawn.
+    fn zoom(&self) -> f32 {
+        let (width, height) = self.size;
+        match self.extent {
+            Some(extent) => extent / width.max(height).max(1.0),
+            None => 1.0,
+        }
+    }
+
+    /// Draw the icon with its longer side this many pixels, keeping its aspect ratio, or `None`
+    /// for its own size. Takes effect on the next resize.
+    pub fn set_extent(&mut self, extent: Option<f32>) {
+        self.extent = extent;
+    }
+
+    /// Show the icon fully straight away, without animating it in.
+    pub fn skip_intro(&mut self) {
+        self.intro_start = None;
+    }
+
+    /// Move the icon away from the centre of the window, in pixels with y pointing down. Takes
+    /// effect on the next resize.
+    pub fn set_offset(&mut self, offset: (f32, f32)) {
+        self.offset = offset;
+    }
+
     pub fn resize(&mut self, queue: &wgpu::Queue, resolution_transform: cgmath::Matrix4<f32>) {
-        self.uniforms_handle.data.transform =
-            self.uniforms_handle.texture_transform * resolution_transform;
+        // Clip space is two units across, so offsets are doubled after scaling to the resolution.
+        let (x, y) = self.offset;
+        let translation = cgmath::Matrix4::from_translation(cgmath::Vector3::new(
+            2.0 * x * resolution_transform.x.x,
+            -2.0 * y * resolution_transform.y.y,
+            0.0,
+        ));
+        self.uniforms_handle.data.transform = translation
+            * cgmath::Matrix4::from_scale(self.zoom())
+            * self.uniforms_handle.texture_transform
+            * resolution_transform;
+        queue.write_buffer(
+            &self.uniforms_handle.buffer,
+            0,
+            bytemuck::cast_slice(&[self.uniforms_handle.data]),
+        );
+    }
+
+    /// Recolour the icon, e.g. to match a light or dark theme.
+    pub fn set_tint(&mut self, queue: &wgpu::Queue, tint: Color) {
+        self.uniforms_handle.data.tint = tint.0;
+        queue.write_buffer(
+            &self.uniforms_handle.buffer,
+            0,
+            bytemuck::cast_slice(&[self.uniforms_handle.data]),
+        );
+    }
+
+    /// Update per-frame uniforms: the given opacity and the intro animation's progress.
+    pub fn update(&mut self, queue: &wgpu::Queue, opacity: f32) {
+        let intro = match self.intro_start {
+            Some(start) => (start.elapsed().as_secs_f32() / INTRO_DURATION.as_secs_f32()).min(1.0),
+            None => 1.0,
+        };
+        let data = &mut self.uniforms_handle.data;
+        if data.opacity == opacity && data.intro == intro {
+            return;
+        }
+        data.opacity = opacity;
+        data.intro = intro;
         queue.write_buffer(
             &self.uniforms_handle.buffer,
             0,
@@ -213,7 +515,7 @@ impl State {
         let mut rp = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: Some("icon render pass"),
             color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &view,
+                view,
                 resolve_target: None,
                 ops: wgpu::Operations {
                     load: wgpu::LoadOp::Load,
@@ -229,3 +531,20 @@ impl State {
         rp.draw(0..4, 0..1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mip_chain_keeps_colour_at_edges() {
+        // One opaque white pixel among transparent black ones.
+        let mut image = image::RgbaImage::new(2, 2);
+        image.put_pixel(0, 0, image::Rgba([255, 255, 255, 255]));
+        let levels = mip_chain(&image);
+        assert_eq!(levels.len(), 1);
+        let image::Rgba([r, g, b, a]) = *levels[0].get_pixel(0, 0);
+        assert_eq!([r, g, b], [255, 255, 255]);
+        assert!((60..=68).contains(&a), "alpha {}", a);
+    }
+}
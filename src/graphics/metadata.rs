@@ -0,0 +1,77 @@
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+/// Attribution and requirements from the comment header at the top of a shader, in lines like
+/// `// @title Seascape`, `// @author TDM` and `// @channels 2`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ShaderMetadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    /// How many of `iChannel0` onwards the shader reads, which must all be bound.
+    pub channels: Option<usize>,
+}
+
+impl ShaderMetadata {
+    /// Read the header, which ends at the first line that isn't a `//` comment or blank.
+    pub fn parse(source: &str) -> Self {
+        let mut metadata = Self::default();
+        for line in source.lines().map(str::trim) {
+            if line.is_empty() {
+                continue;
+            }
+            let comment = match line.strip_prefix("//") {
+                Some(comment) => comment.trim(),
+                None => break,
+            };
+            let (key, value) = match comment.strip_prefix('@') {
+                Some(tag) => tag.split_once(char::is_whitespace).unwrap_or((tag, "")),
+                None => continue,
+            };
+            let value = value.trim();
+            match key {
+                "title" => metadata.title = Some(value.to_owned()),
+                "author" => metadata.author = Some(value.to_owned()),
+                "channels" => match value.parse() {
+                    Ok(channels) => metadata.channels = Some(channels),
+                    Err(_) => warn!("Ignoring shader header @channels {:?}, not a number", value),
+                },
+                _ => debug!("ignoring shader header @{}", key),
+            }
+        }
+        metadata
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_header() {
+        let source = "// Ported from Shadertoy.\n\
+                      // @title  Seascape\n\
+                      // @author TDM\n\
+                      //@channels 2\n\
+                      \n\
+                      #version 450\n\
+                      // @title Not the header\n";
+        assert_eq!(
+            ShaderMetadata::parse(source),
+            ShaderMetadata {
+                title: Some("Seascape".to_owned()),
+                author: Some("TDM".to_owned()),
+                channels: Some(2),
+            }
+        );
+    }
+
+    #[test]
+    fn ignores_bad_channels() {
+        let metadata = ShaderMetadata::parse("// @channels two\nvoid main() {}");
+        assert!(metadata.is_empty());
+    }
+}
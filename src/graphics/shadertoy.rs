@@ -0,0 +1,83 @@
+use super::bg::CHANNEL_BINDING;
+use super::CHANNELS;
+
+/// Whether `source` was copied from Shadertoy as it is, defining `mainImage` but no `main`.
+pub fn is_shadertoy(source: &str) -> bool {
+    defines(source, "mainImage") && !defines(source, "main")
+}
+
+/// Whether `source` defines a function called `name`, judging by the name followed by `(`.
+fn defines(source: &str, name: &str) -> bool {
+    let is_identifier = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    source.match_indices(name).any(|(i, _)| {
+        let before = source[..i].chars().next_back();
+        let after = source[i + name.len()..].trim_start();
+        !before.is_some_and(is_identifier) && after.starts_with('(')
+    })
+}
+
+/// Wrap a Shadertoy shader in a `main` calling `mainImage`, declaring the inputs Shadertoy
+/// provides from shaderlock's own. With `flip_y`, `gl_FragCoord` will count up from the bottom,
+/// so `iMouse` is flipped to match.
+pub fn wrap(source: &str, flip_y: bool) -> String {
+    let channels: String = (0..CHANNELS)
+        .map(|i| {
+            format!(
+                "layout(set = 0, binding = {}) uniform texture2D _shadertoy_channel{i};\n\
+                 #define iChannel{i} sampler2D(_shadertoy_channel{i}, _shadertoy_sampler)\n",
+                CHANNEL_BINDING + i as u32,
+                i = i
+            )
+        })
+        .collect();
+    let mouse_y = if flip_y {
+        "iResolution.y - _shadertoy_frame.mouse.y"
+    } else {
+        "_shadertoy_frame.mouse.y"
+    };
+    format!(
+        "#version 450\n\
+         layout(set = 0, binding = 2) uniform _ShadertoyUniforms {{\n    \
+         mat4 transform;\n    vec4 colors[2];\n    vec2 resolution;\n}} _shadertoy;\n\
+         layout(push_constant) uniform _ShadertoyFrame {{\n    \
+         float time;\n    float fade_amount;\n    uint lock_state;\n    uint output_index;\n    \
+         uint output_count;\n    uint password_length;\n    float last_char_age;\n    \
+         float battery_level;\n    uint battery_charging;\n    float unlock_progress;\n    \
+         uint focused;\n    uint output_transform;\n    vec4 control;\n    vec2 size;\n    \
+         float aspect;\n    vec2 mouse;\n}} _shadertoy_frame;\n\
+         {channels}\
+         layout(set = 0, binding = {sampler}) uniform sampler _shadertoy_sampler;\n\
+         layout(location = 0) out vec4 _shadertoy_color;\n\
+         vec3 iResolution;\n\
+         float iTime;\n\
+         vec4 iMouse;\n\
+         #line 1\n\
+         {source}\n\
+         void main() {{\n    \
+         iResolution = vec3(_shadertoy.resolution, 1.0);\n    \
+         iTime = _shadertoy_frame.time;\n    \
+         iMouse = vec4(_shadertoy_frame.mouse.x, {mouse_y}, 0.0, 0.0);\n    \
+         mainImage(_shadertoy_color, gl_FragCoord.xy);\n\
+         }}\n",
+        channels = channels,
+        sampler = CHANNEL_BINDING + CHANNELS as u32,
+        source = source,
+        mouse_y = mouse_y,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_shadertoy_shaders() {
+        let shadertoy = "void mainImage( out vec4 fragColor, in vec2 fragCoord ) {}";
+        assert!(is_shadertoy(shadertoy));
+        assert!(!is_shadertoy(&format!(
+            "{}\nvoid main () {{ mainImage(color, gl_FragCoord.xy); }}",
+            shadertoy
+        )));
+        assert!(!is_shadertoy("void main() { my_mainImage(); }"));
+    }
+}
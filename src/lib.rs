@@ -1,4 +1,13 @@
 pub mod authenticator;
+pub mod battery;
+pub mod bundle;
+pub mod color_management;
+pub mod control;
+pub mod fade;
 pub mod graphics;
+pub mod mpris;
 pub mod screencopy;
+pub mod sound;
+pub mod unlock_check;
+pub mod watchdog;
 pub mod window_manager;
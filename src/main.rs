@@ -1,4 +1,8 @@
-use std::collections::HashMap;
+// Wayland object IDs hash by their protocol ID and serial, which never change.
+#![allow(clippy::mutable_key_type)]
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 
 use anyhow::*;
 use either::Either;
@@ -6,14 +10,19 @@ use futures::StreamExt;
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
 use sctk::reexports::client::backend::ObjectId;
-use sctk::reexports::client::protocol::wl_output::WlOutput;
+use sctk::reexports::client::protocol::wl_output::{Transform, WlOutput};
+use sctk::reexports::client::protocol::wl_shm::Format;
 use sctk::reexports::client::Proxy;
 use sctk::session_lock::*;
+use sctk::shm::slot;
 use shaderlock::authenticator::{
-    Authenticator, AuthenticatorBackend, NullAuthenticatorBackend, PamAuthenticatorBackend,
+    AuthError, AuthStep, Authenticator, AuthenticatorBackend, NullAuthenticatorBackend,
+    PamAuthenticatorBackend,
 };
-use shaderlock::graphics::RenderContext;
-use shaderlock::screencopy::ScreencopyBuffer;
+use shaderlock::control::Command as ControlCommand;
+use shaderlock::fade::FadeCurve;
+use shaderlock::graphics::{Background, Color, IconSource, LockState, RenderContext};
+use shaderlock::screencopy::{ScreencopyBuffer, ScreencopyError};
 use shaderlock::window_manager::ExitSync;
 
 use clap::Parser;
@@ -21,63 +30,1318 @@ use sctk::seat::keyboard::{KeyEvent, Keysym};
 use shaderlock::screencopy::ScreencopyHandler;
 use shaderlock::window_manager::WindowManager;
 use shaderlock::window_manager::{Event, Window};
+use tokio::signal::unix::SignalKind;
 use tokio::task::LocalSet;
 
 const DATADIR: &str = env!("DATADIR");
-const SHADER_GLOB: &str = "shaders/*.frag";
+const SHADER_GLOB: &str = "shaders/*";
 const ICON_FILE: &str = "lock-icon.png";
-const FREEZE_AFTER_INACTIVITY: std::time::Duration = std::time::Duration::from_secs(10);
-const FADE_BEFORE_FREEZE: std::time::Duration = std::time::Duration::from_secs(5);
+const BLACK_ARGB: u32 = 0xff000000;
+const MPRIS_ART_CHANNEL: usize = 0;
+
+const PREVIEW_SCREENSHOT_TILE: u32 = 32;
+/// Longest duration accepted on the command line, so timer arithmetic stays far from overflowing.
+const MAX_SECONDS: f32 = 24.0 * 60.0 * 60.0;
+/// Shown in place of the message when PAM takes longer than --auth-timeout.
+const AUTH_TIMEOUT_MESSAGE: &str = "Authentication timed out, try again";
+/// How long the compositor may take to confirm the session is locked, which it does once every
+/// output has shown a frame.
+const LOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// How often the battery indicator is refreshed.
+const BATTERY_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+/// How often the shader file is checked for changes with --watch-shader.
+const SHADER_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
 
 #[derive(Parser)]
 #[command(version, author, about)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Authentication always succeeds, for testing.
     #[arg(long, default_value_t = false)]
     skip_auth: bool,
 
-    /// Shader applied to the lock screen background.
+    /// Shader applied to the lock screen background, with `~` and `$VAR` expanded. Repeat as
+    /// OUTPUT=PATH to give outputs, by name (e.g. DP-1), their own shader, the rest using this
+    /// one or else one picked at random.
     #[arg(long, short)]
-    shader_file: Option<String>,
+    shader_file: Vec<String>,
+
+    /// Directory of Shadertoy-style passes, with `~` and `$VAR` expanded: Image.frag drawn to
+    /// the screen, reading BufferA.frag to BufferD.frag, where present, as iChannel0 to iChannel3.
+    #[arg(
+        long,
+        conflicts_with_all = ["shader_file", "background", "random_per_output", "max_shader_runtime"]
+    )]
+    shader_dir: Option<String>,
+
+    /// Tar archive holding a whole themed lock screen, with `~` and `$VAR` expanded: shader.frag
+    /// and an icon named icon.svg, icon.png or similar. Either may be left out for the defaults.
+    #[arg(
+        long,
+        conflicts_with_all = ["shader_file", "shader_dir", "background", "random_per_output"]
+    )]
+    bundle: Option<String>,
+
+    /// Where to find shaders to pick from at random, with `~` and `$VAR` expanded.
+    #[arg(long, default_value_t = format!("{}/{}", DATADIR, SHADER_GLOB))]
+    shader_glob: String,
+
+    /// Seed for picking a shader at random, to make the choice reproducible.
+    #[arg(long)]
+    shader_seed: Option<u64>,
+
+    /// Make every lock session look the same, e.g. for kiosks and demos: every shader choice,
+    /// including those by --max-shader-runtime, is seeded by --shader-seed, or 0 without it.
+    #[arg(long, default_value_t = false)]
+    deterministic: bool,
+
+    /// Pick a shader at random for each output, rather than one for all of them. With
+    /// --shader-seed, each output's choice is seeded by the seed plus its index.
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = [
+            "shader_file",
+            "background",
+            "max_shader_runtime",
+            "cycle_shaders",
+            "idle_shader"
+        ]
+    )]
+    random_per_output: bool,
+
+    /// Start each output's animation when it is shown, rather than all together, so different
+    /// shaders on each output don't move in step. Switching shader restarts them together.
+    #[arg(long, default_value_t = false)]
+    per_output_time: bool,
+
+    /// Pick another shader at random after this many seconds, restarting its animation.
+    #[arg(
+        long,
+        env = "SHADERLOCK_MAX_SHADER_RUNTIME",
+        value_parser = parse_seconds,
+        conflicts_with_all = ["shader_file", "background"]
+    )]
+    max_shader_runtime: Option<std::time::Duration>,
 
-    /// Icon to overlay on the lock screen.
+    /// Switch to the next shader matching --shader-glob with Page Down while locked.
+    #[arg(long, default_value_t = false)]
+    cycle_shaders: bool,
+
+    /// Don't capture the screen before locking, for shaders that never sample t_screenshot. This
+    /// saves the capture and its video memory, but shaders that do sample it fail to load.
+    #[arg(long, default_value_t = false)]
+    no_screenshot: bool,
+
+    /// Write each output's screenshot to <dir>/<output>.png as captured, before any shader, to
+    /// diagnose rotated or miscoloured captures.
+    #[arg(long, conflicts_with = "no_screenshot")]
+    dump_screenshots: Option<std::path::PathBuf>,
+
+    /// Don't use the GPU at all, showing each output's screenshot, or black with
+    /// --no-screenshot, instead of the shader and icon. For machines where even software
+    /// rendering fails, as this works wherever the compositor can lock the session.
+    #[arg(long, default_value_t = false)]
+    no_gpu: bool,
+
+    /// Shaders to try in order, by path or name, if the chosen one fails to load, before falling
+    /// back to showing the screenshot as it is, or black with --no-screenshot.
+    #[arg(
+        long = "fallback-shader",
+        env = "SHADERLOCK_FALLBACK_SHADERS",
+        value_delimiter = ':'
+    )]
+    fallback_shaders: Vec<String>,
+
+    /// Exit if the shader fails to load, without locking, instead of falling back.
+    #[arg(long, default_value_t = false, conflicts_with = "fallback_shaders")]
+    strict_shader: bool,
+
+    /// Plain background instead of a shader: solid:<colour> or gradient:<top>,<bottom>,
+    /// with colours as #rgb or #rrggbb, or screenshot for the screen as it is.
+    #[arg(long, conflicts_with = "shader_file")]
+    background: Option<Background>,
+
+    /// Icon to overlay on the lock screen, with `~` and `$VAR` expanded.
     #[arg(long, default_value_t = format!("{}/{}", DATADIR, ICON_FILE))]
     icon_file: String,
+
+    /// Unlock on SIGUSR1, so external agents (e.g. fingerprint readers) can unlock the session.
+    /// Any process running as this user can then unlock, so only enable it if that is acceptable.
+    #[arg(long, default_value_t = false)]
+    unlock_on_sigusr1: bool,
+
+    /// Freeze the animation on SIGUSR2 and wake it on SIGCONT, so idle managers such as
+    /// swayidle can blank the lock screen on their own timers.
+    #[arg(long, default_value_t = false)]
+    idle_signals: bool,
+
+    /// Shell command run periodically while locked, unlocking the session when it exits
+    /// successfully, e.g. to unlock by phone proximity. It is trusted like a correct password.
+    #[arg(long)]
+    unlock_command_check: Option<String>,
+
+    /// Seconds between runs of --unlock-command-check.
+    #[arg(
+        long,
+        default_value = "2",
+        value_parser = parse_seconds,
+        requires = "unlock_command_check"
+    )]
+    unlock_command_interval: std::time::Duration,
+
+    /// Hide the icon while the background animates, fading it in with the freeze and showing it
+    /// while typing a password.
+    #[arg(long, default_value_t = false)]
+    icon_fade: bool,
+
+    /// How to compile shaders: shaderc, or naga if built with it. Falls back to naga when
+    /// libshaderc can't be loaded.
+    #[arg(long, default_value = "shaderc")]
+    glsl_frontend: shaderlock::graphics::GlslFrontend,
+
+    /// Function to run from the shader instead of main, to pick a technique (e.g. day or night)
+    /// from a shader file with several.
+    #[arg(long, default_value = "main")]
+    shader_entry: String,
+
+    /// Make the shader's gl_FragCoord count up from the bottom of the output, as OpenGL does:
+    /// auto for shaders ported from Shadertoy, which define mainImage, always or never.
+    #[arg(long, default_value = "auto")]
+    flip_y: shaderlock::graphics::FlipY,
+
+    /// Reload the shader whenever its file changes, for writing shaders. Windows keep the old
+    /// shader if the new one fails to compile.
+    #[arg(long, default_value_t = false)]
+    watch_shader: bool,
+
+    /// Compile shaders defining mainImage but no main, as copied from Shadertoy, as they are.
+    /// Otherwise they are given a main calling mainImage, with Shadertoy's iResolution, iTime,
+    /// iMouse and iChannel0 to iChannel3.
+    #[arg(long, default_value_t = false)]
+    no_shadertoy_wrapper: bool,
+
+    /// Let the shader sample its previous frame, for feedback and trail effects. This keeps two
+    /// more frame-sized textures per output in video memory.
+    #[arg(long, default_value_t = false)]
+    feedback: bool,
+
+    /// Render the shader at this fraction of each output's resolution, e.g. 0.5, and upscale it,
+    /// to run demanding shaders on modest GPUs. The icon stays sharp.
+    #[arg(long, default_value_t = 1.0, value_parser = parse_render_scale)]
+    render_scale: f32,
+
+    /// Size the icon to this many millimetres across on every output, whatever its resolution,
+    /// using the physical size the output reports. Outputs that don't report one keep the
+    /// icon's own size in pixels.
+    #[arg(long, value_parser = parse_millimetres)]
+    icon_size_mm: Option<f32>,
+
+    /// Format of the textures the shader is drawn to with --feedback or --render-scale: rgba8,
+    /// or rgba16float to stop accumulating shaders banding. Defaults to the output's format.
+    #[arg(long)]
+    internal_format: Option<shaderlock::graphics::InternalFormat>,
+
+    /// Show the cursor over the lock screen, from the XCURSOR_THEME theme at XCURSOR_SIZE.
+    #[arg(long, default_value_t = false)]
+    show_cursor: bool,
+
+    /// Backdrop behind the icon to keep it legible over busy shaders: none, dim or blur.
+    #[arg(long, default_value = "none")]
+    icon_backdrop: shaderlock::graphics::Backdrop,
+
+    /// Draw the icon with nearest-neighbour sampling instead of smoothing it, e.g. for pixel art.
+    #[arg(long, default_value_t = false)]
+    no_icon_antialias: bool,
+
+    /// Colour the icon is multiplied by, as #rgb or #rrggbb, e.g. to match a light or dark theme.
+    #[arg(long)]
+    icon_tint: Option<Color>,
+
+    /// Message shown below the icon, e.g. "Authorized users only".
+    #[arg(long)]
+    message: Option<String>,
+
+    /// Show the battery charge in the corner of the lock screen, and pass it to the shader.
+    #[arg(long, default_value_t = false)]
+    battery_indicator: bool,
+
+    /// Show the active keyboard layout in the corner of the lock screen, e.g. to check which
+    /// layout the password is being typed in.
+    #[arg(long, default_value_t = false)]
+    show_layout: bool,
+
+    /// Key that blanks every screen to black until the next keypress, by keysym name (e.g. Pause),
+    /// to hide the animation from onlookers at once.
+    #[arg(long, value_parser = parse_keysym)]
+    panic_key: Option<Keysym>,
+
+    /// Key that freezes the animation to save power, or unfreezes it to keep it running,
+    /// regardless of --freeze-after, by keysym name (e.g. Scroll_Lock).
+    #[arg(long, value_parser = parse_keysym)]
+    freeze_key: Option<Keysym>,
+
+    /// Only show the icon on this output, by name (e.g. DP-1), or on the first output with "primary".
+    #[arg(long)]
+    icon_output: Option<String>,
+
+    /// Frames queued for presentation: 1 for the lowest input latency, more for smoother animation.
+    #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    frame_latency: u32,
+
+    /// Developer mode: Ctrl+Escape unlocks immediately. Only available in debug builds.
+    #[cfg(debug_assertions)]
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "skip_auth",
+        conflicts_with = "quiet"
+    )]
+    dev: bool,
+
+    /// Seconds to keep drawing after the password is accepted before unlocking, so shaders can
+    /// play an outro using iUnlockProgress. The icon fades out meanwhile.
+    #[arg(long, value_parser = parse_seconds)]
+    outro_duration: Option<std::time::Duration>,
+
+    /// Seconds to wait for PAM to check a password before giving up, for network-backed modules
+    /// such as LDAP or Kerberos that can stall.
+    #[arg(long, value_parser = parse_seconds, conflicts_with = "skip_auth")]
+    auth_timeout: Option<std::time::Duration>,
+
+    /// Times to wait --auth-timeout again for PAM to answer before giving up. The password isn't
+    /// sent again, as a stalled attempt may still finish and count against login limits.
+    #[arg(long, default_value_t = 0, requires = "auth_timeout")]
+    auth_retries: u32,
+
+    /// Unlock by itself this many seconds after locking, to exercise the whole lock path
+    /// unattended, e.g. in CI. Only with --skip-auth.
+    #[arg(long, value_parser = parse_seconds, requires = "skip_auth")]
+    test_lock: Option<std::time::Duration>,
+
+    /// Seconds to stay connected after unlocking before exiting, for compositors that flicker
+    /// or show a black frame when the lock client disconnects as the lock surfaces go away.
+    #[arg(long, value_parser = parse_seconds)]
+    exit_delay: Option<std::time::Duration>,
+
+    /// Production defaults: clear to black instead of red before the background is drawn, and
+    /// only log errors, ignoring RUST_LOG.
+    #[arg(long, default_value_t = false)]
+    quiet: bool,
+
+    /// Seconds without input before the animation freezes.
+    #[arg(long, env = "SHADERLOCK_FREEZE_AFTER", default_value = "10", value_parser = parse_seconds)]
+    freeze_after: std::time::Duration,
+
+    /// Seconds the animation fades out for before freezing.
+    #[arg(long, env = "SHADERLOCK_FADE_DURATION", default_value = "5", value_parser = parse_seconds)]
+    fade_duration: std::time::Duration,
+
+    /// Easing of the fade before the animation freezes: linear, ease-in, ease-out or smoothstep.
+    #[arg(long, env = "SHADERLOCK_FADE_CURVE", default_value = "linear")]
+    fade_curve: FadeCurve,
+
+    /// Never freeze or fade out the animation. The GPU keeps rendering every frame for as long
+    /// as the screen is locked, which costs power and battery life.
+    #[arg(long, env = "SHADERLOCK_NO_FREEZE", default_value_t = false)]
+    no_freeze: bool,
+
+    /// Seconds into the animation to show once frozen, instead of wherever it stopped.
+    #[arg(
+        long,
+        env = "SHADERLOCK_FREEZE_FRAME",
+        value_parser = parse_seconds,
+        conflicts_with = "no_freeze"
+    )]
+    freeze_frame: Option<std::time::Duration>,
+
+    /// Lightweight shader to run at a low frame rate instead of freezing, with `~` and `$VAR`
+    /// expanded.
+    #[arg(long, conflicts_with_all = ["no_freeze", "freeze_frame"])]
+    idle_shader: Option<String>,
+
+    /// Frames per second to render the idle shader at.
+    #[arg(
+        long,
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u32).range(1..),
+        requires = "idle_shader"
+    )]
+    idle_fps: u32,
+
+    /// Restart with --no-gpu if no frame is presented this many seconds after a key press, in case
+    /// the graphics driver or shader hangs, or exit if already without the GPU. The compositor
+    /// keeps the session locked meanwhile.
+    #[arg(long, value_parser = parse_seconds)]
+    watchdog: Option<std::time::Duration>,
+
+    /// Keep a file listing each lock surface as "<output> <x> <y> <width> <height>" in logical
+    /// pixels, so companion tools can position themselves relative to the lock screen.
+    #[arg(long)]
+    surfaces_file: Option<std::path::PathBuf>,
+
+    /// Take commands on a Unix socket at this path, e.g. to set iControl or switch shaders from
+    /// other tools. See the README for the commands.
+    #[arg(long)]
+    control_socket: Option<std::path::PathBuf>,
+
+    /// Show the album art of the playing track from an MPRIS media player as iChannel0, and its
+    /// title and artist at the top of the screen. Both appear once the player answers.
+    #[arg(long, default_value_t = false)]
+    mpris: bool,
+
+    /// Image file for shaders to sample as iChannel0, with `~` and `$VAR` expanded.
+    #[arg(long, conflicts_with = "mpris")]
+    channel0: Option<String>,
+
+    /// Image file for shaders to sample as iChannel1.
+    #[arg(long)]
+    channel1: Option<String>,
+
+    /// Image file for shaders to sample as iChannel2.
+    #[arg(long)]
+    channel2: Option<String>,
+
+    /// Image file for shaders to sample as iChannel3.
+    #[arg(long)]
+    channel3: Option<String>,
+
+    /// Sound to play on each key press with `paplay`, with `~` and `$VAR` expanded.
+    #[arg(long)]
+    keypress_sound: Option<String>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// List the shaders matching --shader-glob.
+    ListShaders,
+    /// Render frames of a shader offscreen and report how long they take.
+    Bench {
+        /// Name of a shader matching --shader-glob, or a path to a shader file.
+        shader: String,
+
+        /// Frames to render.
+        #[arg(long, default_value_t = 600, value_parser = clap::value_parser!(u32).range(1..))]
+        frames: u32,
+
+        #[arg(long, default_value_t = 1920)]
+        width: u32,
+
+        #[arg(long, default_value_t = 1080)]
+        height: u32,
+    },
+    /// Check everything the lock screen needs loads, without locking: the shaders, the icon, the
+    /// PAM service and a graphics device. Every problem is reported, failing if there are any.
+    Validate,
+    /// Render a single frame of a shader to a PNG, opening it unless an output is given.
+    Preview {
+        /// Name of a shader matching --shader-glob, or a path to a shader file.
+        shader: String,
+
+        /// Where to write the PNG.
+        #[arg(long, short)]
+        output: Option<std::path::PathBuf>,
+
+        /// Seconds of animation to render.
+        #[arg(long, default_value = "0", value_parser = parse_seconds)]
+        time: std::time::Duration,
+
+        /// Render partway through the outro, from 0 to 1, as the shader sees it in
+        /// iUnlockProgress. The icon fades out to match.
+        #[arg(long, default_value_t = 0.0, value_parser = parse_progress)]
+        unlock_progress: f32,
+
+        #[arg(long, default_value_t = 1280)]
+        width: u32,
+
+        #[arg(long, default_value_t = 720)]
+        height: u32,
+    },
+}
+
+fn find_shaders(shader_glob: &str) -> Result<Vec<std::path::PathBuf>> {
+    glob::glob(&expand_path(shader_glob)?.to_string_lossy())
+        .context("Failed to parse shader file glob")?
+        .map(|entry| entry.context("Failed to get the path to the shader"))
+        // Globs matching a whole directory, like the default, may match more than shader files.
+        .filter(|entry| entry.as_ref().map_or(true, |path| path.is_file()))
+        .collect()
 }
 
-fn get_shader_file() -> Result<std::path::PathBuf> {
+fn get_shader_file(shader_glob: &str, seed: Option<u64>) -> Result<std::path::PathBuf> {
     use rand::seq::IteratorRandom;
-    let mut rng = rand::thread_rng();
-    let file = glob::glob(&format!("{}/{}", DATADIR, SHADER_GLOB))
-        .expect("Failed to parse shader file glob")
+    use rand::SeedableRng;
+    let mut rng = match seed {
+        Some(seed) => rand::rngs::StdRng::seed_from_u64(seed),
+        None => rand::rngs::StdRng::from_entropy(),
+    };
+    // Sort so a seed picks the same shader regardless of directory order.
+    let mut files = find_shaders(shader_glob)?;
+    files.sort();
+    let file = files
+        .into_iter()
         .choose(&mut rng)
-        .context("Failed to randomly pick a shader file")?
-        .context("Failed to get the path to the shader")?;
+        .context("Failed to randomly pick a shader file")?;
 
     info!("Chosen shader {}", file.to_string_lossy());
     Ok(file)
 }
 
+/// The shader after `current` in --shader-glob order, wrapping around.
+fn next_shader(shader_glob: &str, current: Option<&std::path::Path>) -> Result<std::path::PathBuf> {
+    let mut files = find_shaders(shader_glob)?;
+    files.sort();
+    let next = current
+        .and_then(|current| files.iter().position(|file| file == current))
+        .map_or(0, |i| (i + 1) % files.len());
+    files.into_iter().nth(next).context("No shaders found")
+}
+
+/// Split --shader-file values into the shader for every output and those for outputs named as
+/// `OUTPUT=PATH`. Anything before an `=` with a `/` in it is taken as part of a path.
+fn split_shader_files(
+    values: &[String],
+) -> Result<(
+    Option<std::path::PathBuf>,
+    HashMap<String, std::path::PathBuf>,
+)> {
+    let mut file = None;
+    let mut by_output = HashMap::new();
+    for value in values {
+        match value.split_once('=') {
+            Some((output, path)) if !output.is_empty() && !output.contains('/') => {
+                by_output.insert(output.to_owned(), expand_path(path)?);
+            }
+            _ => {
+                ensure!(
+                    file.is_none(),
+                    "Only one --shader-file can be without an output name"
+                );
+                file = Some(expand_path(value)?);
+            }
+        }
+    }
+    Ok((file, by_output))
+}
+
+/// Pick the shader for the next window initialized, keeping the current one if it fails to load.
+fn use_shader_for_next_window(
+    graphics_manager: &mut shaderlock::graphics::Manager,
+    shader_file: &std::path::Path,
+) {
+    if let Err(e) = graphics_manager.set_background(&Background::Shader(shader_file.to_owned())) {
+        warn!(
+            "Failed to load shader {}, using the previous one: {:#}",
+            shader_file.to_string_lossy(),
+            e
+        );
+    }
+}
+
+/// Go back to the shader shared by every output without its own for the next window initialized,
+/// after one with its own.
+fn use_shared_shader(
+    graphics_manager: &mut shaderlock::graphics::Manager,
+    current_shader: Option<&std::path::Path>,
+    background: &Background,
+) {
+    let shared = match current_shader {
+        Some(file) => Background::Shader(file.to_owned()),
+        None => background.clone(),
+    };
+    if let Err(e) = graphics_manager.set_background(&shared) {
+        warn!(
+            "Failed to load the shared shader, using the previous one: {:#}",
+            e
+        );
+    }
+}
+
+/// Switch the background of every window to a shader file, returning it.
+fn switch_shader<'a, 'window: 'a>(
+    graphics_manager: &mut shaderlock::graphics::Manager,
+    windows: impl Iterator<Item = &'a mut shaderlock::graphics::State<'window>>,
+    shader_file: std::path::PathBuf,
+) -> Result<std::path::PathBuf> {
+    info!("Switching to shader {}", shader_file.to_string_lossy());
+    graphics_manager.set_background(&Background::Shader(shader_file.clone()))?;
+    for graphics in windows {
+        graphics_manager.apply_shader(graphics);
+    }
+    Ok(shader_file)
+}
+
+/// Show `message` below the icon instead, e.g. a prompt from PAM.
+fn show_message<'a, 'window: 'a>(
+    graphics_manager: &mut shaderlock::graphics::Manager,
+    windows: impl Iterator<Item = &'a mut shaderlock::graphics::State<'window>>,
+    message: Option<&str>,
+) -> Result<()> {
+    graphics_manager.set_message(message)?;
+    for graphics in windows {
+        graphics_manager.apply_message(graphics)?;
+    }
+    Ok(())
+}
+
+fn list_shaders(shader_glob: &str) -> Result<()> {
+    for file in find_shaders(shader_glob)? {
+        if let Some(name) = file.file_stem() {
+            println!("{}", name.to_string_lossy());
+        }
+    }
+    Ok(())
+}
+
+/// Load the images given for each channel. Shader directories' buffers are bound in their place.
+fn load_channels(
+    files: [Option<&str>; shaderlock::graphics::CHANNELS],
+) -> Result<[Option<image::RgbaImage>; shaderlock::graphics::CHANNELS]> {
+    let mut channels: [Option<image::RgbaImage>; shaderlock::graphics::CHANNELS] =
+        Default::default();
+    for (channel, file) in channels.iter_mut().zip(files) {
+        if let Some(file) = file {
+            let file = expand_path(file)?;
+            let image = image::open(&file)
+                .with_context(|| format!("Failed to load channel image {:?}", file))?;
+            *channel = Some(image.into_rgba8());
+        }
+    }
+    Ok(channels)
+}
+
+/// Find a shader by path, or by name among those matching the glob.
+fn find_shader(shader: &str, shader_glob: &str) -> Result<std::path::PathBuf> {
+    match expand_path(shader)? {
+        path if path.is_file() => Ok(path),
+        _ => find_shaders(shader_glob)?
+            .into_iter()
+            .find(|file| file.file_stem().is_some_and(|name| name == shader))
+            .with_context(|| format!("No shader named {:?}", shader)),
+    }
+}
+
+async fn bench(
+    shader: String,
+    shader_glob: &str,
+    icon_file: &std::path::Path,
+    frames: u32,
+    (width, height): (u32, u32),
+) -> Result<()> {
+    let shader_file = find_shader(&shader, shader_glob)?;
+    let graphics_manager = shaderlock::graphics::Manager::new(
+        &Background::Shader(shader_file),
+        &IconSource::File(icon_file.to_owned()),
+        Default::default(),
+        Default::default(),
+    )
+    .context("Failed to create graphics manager")?;
+    let result = graphics_manager
+        .bench(preview_screenshot(width, height)?, (width, height), frames)
+        .await?;
+
+    let per_frame = |time: std::time::Duration| {
+        let frame_ms = time.as_secs_f64() * 1000.0 / result.frames as f64;
+        format!("{:.3} ms/frame ({:.1} FPS)", frame_ms, 1000.0 / frame_ms)
+    };
+    println!("{} frames at {}x{}", result.frames, width, height);
+    println!("CPU submit: {}", per_frame(result.cpu_time));
+    println!("Total:      {}", per_frame(result.total_time));
+    match result.gpu_time {
+        Some(gpu_time) => println!("GPU:        {}", per_frame(gpu_time)),
+        None => println!("GPU:        timestamp queries unsupported"),
+    }
+    Ok(())
+}
+
+async fn preview(
+    shader: String,
+    shader_glob: &str,
+    icon_file: &std::path::Path,
+    output: Option<std::path::PathBuf>,
+    time: std::time::Duration,
+    unlock_progress: f32,
+    (width, height): (u32, u32),
+) -> Result<()> {
+    let shader_file = find_shader(&shader, shader_glob)?;
+    let graphics_manager = shaderlock::graphics::Manager::new(
+        &Background::Shader(shader_file),
+        &IconSource::File(icon_file.to_owned()),
+        Default::default(),
+        Default::default(),
+    )
+    .context("Failed to create graphics manager")?;
+    let ctx = RenderContext {
+        elapsed: time,
+        fade_amount: 0.0,
+        lock_state: if unlock_progress > 0.0 {
+            LockState::Unlocking
+        } else {
+            LockState::Locked
+        },
+        icon_opacity: 1.0 - unlock_progress,
+        output_index: 0,
+        output_count: 1,
+        password_length: 0,
+        last_char_age: None,
+        battery: None,
+        blank: false,
+        unlock_progress,
+        focused: true,
+        output_transform: Transform::Normal,
+        control: Default::default(),
+        mouse: (0.0, 0.0),
+    };
+    let frame = graphics_manager
+        .render_offscreen(preview_screenshot(width, height)?, (width, height), ctx)
+        .await?;
+
+    let open = output.is_none();
+    let output = output.unwrap_or_else(|| {
+        std::env::temp_dir().join(format!("shaderlock-preview-{}.png", std::process::id()))
+    });
+    frame.save(&output).context("Failed to write preview")?;
+    info!("Wrote preview to {}", output.to_string_lossy());
+    if open {
+        std::process::Command::new("xdg-open")
+            .arg(&output)
+            .spawn()
+            .context("Failed to open preview")?;
+    }
+    Ok(())
+}
+
+/// Load everything the lock screen would from `args`, reporting each part as it goes.
+async fn validate(args: &Args) -> Result<()> {
+    let mut failures = 0;
+    let mut report = |what: &str, result: Result<()>| match result {
+        Result::Ok(()) => println!("ok      {}", what),
+        Result::Err(e) => {
+            failures += 1;
+            println!("FAILED  {}: {:#}", what, e);
+        }
+    };
+
+    if args.skip_auth {
+        println!("skipped PAM service, with --skip-auth");
+    } else {
+        let result =
+            shaderlock::authenticator::find_service().map(|path| debug!("PAM service: {:?}", path));
+        report("PAM service", result.map_err(Error::from));
+    }
+
+    let mut bundle = None;
+    if let Some(file) = &args.bundle {
+        let result = expand_path(file).and_then(|file| {
+            bundle = Some((shaderlock::bundle::Bundle::open(&file)?, file));
+            Ok(())
+        });
+        report(&format!("bundle {}", file), result);
+    }
+
+    // Failures to even find a shader are reported with the rest, in order.
+    let mut backgrounds: Vec<(String, Result<Background>)> = vec![];
+    if let Some(background) = &args.background {
+        backgrounds.push((
+            format!("background {:?}", background),
+            Ok(background.clone()),
+        ));
+    }
+    match split_shader_files(&args.shader_file) {
+        Result::Ok((file, by_output)) => {
+            for file in file.into_iter().chain(by_output.into_values()) {
+                backgrounds.push((
+                    format!("shader {}", file.to_string_lossy()),
+                    Ok(Background::Shader(file)),
+                ));
+            }
+        }
+        Result::Err(e) => backgrounds.push(("--shader-file".to_owned(), Err(e))),
+    }
+    if let Some(dir) = &args.shader_dir {
+        backgrounds.push((
+            format!("shader directory {}", dir),
+            expand_path(dir).map(Background::ShaderDir),
+        ));
+    }
+    if let Some((file, Some((name, source)))) =
+        bundle.as_mut().map(|(b, file)| (file, b.shader.take()))
+    {
+        let name = file.join(name);
+        backgrounds.push((
+            format!("shader {}", name.to_string_lossy()),
+            Ok(Background::ShaderSource(name, source)),
+        ));
+    }
+    if let Some(file) = &args.idle_shader {
+        backgrounds.push((
+            format!("idle shader {}", file),
+            expand_path(file).map(Background::Shader),
+        ));
+    }
+    for shader in &args.fallback_shaders {
+        backgrounds.push((
+            format!("fallback shader {}", shader),
+            find_shader(shader, &args.shader_glob).map(Background::Shader),
+        ));
+    }
+    // Any of them can be switched to at runtime, so they must all load too.
+    let chosen = backgrounds.iter().any(|(_, background)| background.is_ok());
+    match find_shaders(&args.shader_glob) {
+        Result::Ok(files) if files.is_empty() && !chosen => backgrounds.push((
+            format!("shader glob {}", args.shader_glob),
+            Err(anyhow!("No shaders match")),
+        )),
+        Result::Ok(files) => {
+            for file in files {
+                backgrounds.push((
+                    format!("shader {}", file.to_string_lossy()),
+                    Ok(Background::Shader(file)),
+                ));
+            }
+        }
+        Result::Err(e) => backgrounds.push((format!("shader glob {}", args.shader_glob), Err(e))),
+    }
+
+    let channels = [
+        args.channel0.as_deref(),
+        args.channel1.as_deref(),
+        args.channel2.as_deref(),
+        args.channel3.as_deref(),
+    ];
+    if channels.iter().any(Option::is_some) {
+        report("channel images", load_channels(channels).map(drop));
+    }
+
+    let config = shaderlock::graphics::Config {
+        glsl_frontend: args.glsl_frontend,
+        shader_entry: args.shader_entry.clone(),
+        flip_y: args.flip_y,
+        shadertoy_wrapper: !args.no_shadertoy_wrapper,
+        message: args.message.clone(),
+        ..Default::default()
+    };
+    for (what, background) in &backgrounds {
+        let result = background
+            .as_ref()
+            .map_err(|e| anyhow!("{:#}", e))
+            .and_then(|background| {
+                shaderlock::graphics::check_background(background, &config).map_err(Error::from)
+            });
+        report(what, result);
+    }
+
+    let icon = match bundle.and_then(|(bundle, file)| Some((bundle.icon?, file))) {
+        Some(((name, data), file)) => IconSource::Data(file.join(name), data),
+        None => IconSource::File(expand_path(&args.icon_file)?),
+    };
+    // The icon is loaded with the message, and only a manager can request a device.
+    let graphics_manager = shaderlock::graphics::Manager::new(
+        &Background::Solid(Color::BLACK),
+        &icon,
+        Default::default(),
+        config,
+    );
+    match graphics_manager {
+        Result::Ok(graphics_manager) => {
+            report("icon and message", Ok(()));
+            let result = graphics_manager
+                .check_device()
+                .await
+                .map(|info| debug!("adapter: {:?}", info));
+            report("graphics device", result.map_err(Error::from));
+        }
+        Result::Err(e) => {
+            report("icon and message", Err(e.into()));
+            println!("skipped graphics device, as the icon failed to load");
+        }
+    }
+
+    ensure!(failures == 0, "{} problems found", failures);
+    Ok(())
+}
+
+/// A grey checkerboard standing in for the screen contents when previewing.
+fn preview_screenshot(width: u32, height: u32) -> Result<ScreencopyBuffer> {
+    let data = (0..height)
+        .flat_map(|y| {
+            (0..width).flat_map(move |x| {
+                let dark =
+                    (x / PREVIEW_SCREENSHOT_TILE + y / PREVIEW_SCREENSHOT_TILE).is_multiple_of(2);
+                let v = if dark { 0x60 } else { 0xa0 };
+                [v, v, v, 0xff]
+            })
+        })
+        .collect();
+    Ok(ScreencopyBuffer::from_raw(
+        width,
+        height,
+        width * 4,
+        Format::Xbgr8888,
+        data,
+    )?)
+}
+
+/// Expand a leading `~` and `$VAR` or `${VAR}` references in a path argument.
+fn expand_path(path: &str) -> Result<std::path::PathBuf> {
+    let path = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{}{}", env_var("HOME")?, rest)
+        }
+        _ => path.to_owned(),
+    };
+
+    let mut expanded = String::new();
+    let mut rest = path.as_str();
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, tail) = match after.strip_prefix('{') {
+            Some(braced) => braced
+                .split_once('}')
+                .with_context(|| format!("Unterminated ${{ in path {:?}", path))?,
+            None => after.split_at(
+                after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len()),
+            ),
+        };
+        ensure!(
+            !name.is_empty(),
+            "Missing variable name after $ in path {:?}",
+            path
+        );
+        expanded.push_str(&env_var(name)?);
+        rest = tail;
+    }
+    expanded.push_str(rest);
+    Ok(expanded.into())
+}
+
+fn env_var(name: &str) -> Result<String> {
+    std::env::var(name).with_context(|| format!("Failed to expand ${} in path", name))
+}
+
+/// Replace the surfaces file atomically, so readers never see a partial list.
+fn write_surfaces_file<'a>(
+    path: &std::path::Path,
+    regions: impl Iterator<Item = &'a String>,
+) -> Result<()> {
+    let mut lines: Vec<_> = regions.map(String::as_str).collect();
+    lines.sort();
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, lines.join("\n") + "\n")?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// The output a surface is shown on: the one it entered last, or the one it was created for
+/// until it enters any.
+fn shown_on<'a>(
+    surface: &ObjectId,
+    entered_outputs_by_surface: &'a HashMap<ObjectId, Vec<WlOutput>>,
+    output_by_surface: &'a HashMap<ObjectId, WlOutput>,
+) -> Option<&'a WlOutput> {
+    entered_outputs_by_surface
+        .get(surface)
+        .and_then(|outputs| outputs.last())
+        .or_else(|| output_by_surface.get(surface))
+}
+
+/// Draw a frame on every lock surface with graphics, to wake them while frozen or show a change.
+fn wake_all_surfaces(
+    state: &mut shaderlock::window_manager::WindowManagerStateAccessor,
+    lock_surface_by_surface: &HashMap<ObjectId, SessionLockSurface>,
+    graphics_by_surface: &HashMap<ObjectId, shaderlock::graphics::State>,
+) {
+    state.access(|s| {
+        for (id, lock_surface) in lock_surface_by_surface {
+            if graphics_by_surface.contains_key(id) {
+                s.queue_redraw(lock_surface.wl_surface().clone());
+            }
+        }
+    });
+}
+
+fn dump_screenshot(dir: &std::path::Path, name: &str, frame: &ScreencopyBuffer) -> Result<()> {
+    let path = dir.join(format!("{}.png", name));
+    frame
+        .to_image()?
+        .save(&path)
+        .with_context(|| format!("Failed to write {:?}", path))?;
+    info!("Wrote screenshot of {} to {:?}", name, path);
+    Ok(())
+}
+
+fn parse_seconds(s: &str) -> Result<std::time::Duration> {
+    let seconds: f32 = s
+        .parse()
+        .with_context(|| format!("{:?} is not a number of seconds", s))?;
+    ensure!(
+        (0.0..=MAX_SECONDS).contains(&seconds),
+        "Duration must be between 0 and {} seconds",
+        MAX_SECONDS
+    );
+    Ok(std::time::Duration::from_secs_f32(seconds))
+}
+
+fn parse_render_scale(s: &str) -> Result<f32> {
+    let scale: f32 = s
+        .parse()
+        .with_context(|| format!("{:?} is not a number", s))?;
+    ensure!(
+        scale > 0.0 && scale <= 1.0,
+        "Render scale must be above 0 and at most 1"
+    );
+    Ok(scale)
+}
+
+fn parse_millimetres(s: &str) -> Result<f32> {
+    let size: f32 = s
+        .parse()
+        .with_context(|| format!("{:?} is not a number of millimetres", s))?;
+    ensure!(size > 0.0, "Size must be above 0mm");
+    Ok(size)
+}
+
+fn parse_progress(s: &str) -> Result<f32> {
+    let progress: f32 = s
+        .parse()
+        .with_context(|| format!("{:?} is not a number", s))?;
+    ensure!(
+        (0.0..=1.0).contains(&progress),
+        "Progress must be from 0 to 1"
+    );
+    Ok(progress)
+}
+
+fn parse_keysym(s: &str) -> Result<Keysym> {
+    let keysym = xkbcommon::xkb::keysym_from_name(s, xkbcommon::xkb::KEYSYM_CASE_INSENSITIVE);
+    ensure!(keysym != Keysym::NoSymbol, "{:?} is not a key name", s);
+    Ok(keysym)
+}
+
+/// Send `event` each time a signal is received, until the event loop stops listening.
+fn forward_signal(
+    kind: SignalKind,
+    name: &'static str,
+    events_tx: &futures::channel::mpsc::UnboundedSender<Event>,
+    event: Event,
+) -> Result<()> {
+    let mut signals = tokio::signal::unix::signal(kind)
+        .with_context(|| format!("Failed to listen for {}", name))?;
+    let events_tx = events_tx.clone();
+    tokio::task::spawn_local(async move {
+        while signals.recv().await.is_some() {
+            info!("Received {}", name);
+            if events_tx.unbounded_send(event.clone()).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Time spent in each phase of locking, logged once every surface has shown a frame.
+struct StartupTimings {
+    start: std::time::Instant,
+    compile: std::time::Duration,
+    connect: std::time::Duration,
+    capture: std::time::Duration,
+    /// When the lock was requested, after capturing.
+    lock_requested: Option<std::time::Instant>,
+    /// Summed over outputs, as they initialize one after another.
+    device_init: std::time::Duration,
+}
+
+impl StartupTimings {
+    fn new(start: std::time::Instant) -> Self {
+        Self {
+            start,
+            compile: Default::default(),
+            connect: Default::default(),
+            capture: Default::default(),
+            lock_requested: None,
+            device_init: Default::default(),
+        }
+    }
+
+    /// Log every phase on one line, with the rest of the time after locking as first render.
+    /// Only the first call logs, as outputs connected later present on their own.
+    fn log(&mut self) {
+        let lock_requested = match self.lock_requested.take() {
+            Some(lock_requested) => lock_requested,
+            None => return,
+        };
+        let ms = |duration: std::time::Duration| duration.as_secs_f64() * 1000.0;
+        let first_render = lock_requested.elapsed().saturating_sub(self.device_init);
+        info!(
+            "time to lock: total_ms={:.1} connect_ms={:.1} capture_ms={:.1} compile_ms={:.1} \
+             device_init_ms={:.1} first_render_ms={:.1}",
+            ms(self.start.elapsed()),
+            ms(self.connect),
+            ms(self.capture),
+            ms(self.compile),
+            ms(self.device_init),
+            ms(first_render),
+        );
+    }
+}
+
+fn set_lock_state(lock_state: &mut LockState, new: LockState) {
+    if *lock_state != new {
+        debug!("lock state {:?} -> {:?}", lock_state, new);
+        *lock_state = new;
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    let start = std::time::Instant::now();
+    let args = Args::parse();
+    if args.quiet {
+        env_logger::Builder::new()
+            .filter_level(log::LevelFilter::Error)
+            .init();
+    } else {
+        env_logger::init();
+    }
     LocalSet::new()
         .run_until(async move {
-            let args = Args::parse();
 
-            let shader_file = match args.shader_file {
-                Some(s) => std::path::PathBuf::from(s),
-                None => get_shader_file()?,
+            match args.command {
+                Some(Command::ListShaders) => return list_shaders(&args.shader_glob),
+                Some(Command::Validate) => return validate(&args).await,
+                Some(Command::Preview {
+                    shader,
+                    output,
+                    time,
+                    unlock_progress,
+                    width,
+                    height,
+                }) => {
+                    let icon_file = expand_path(&args.icon_file)?;
+                    return preview(
+                        shader,
+                        &args.shader_glob,
+                        &icon_file,
+                        output,
+                        time,
+                        unlock_progress,
+                        (width, height),
+                    )
+                    .await;
+                }
+                Some(Command::Bench {
+                    shader,
+                    frames,
+                    width,
+                    height,
+                }) => {
+                    let icon_file = expand_path(&args.icon_file)?;
+                    return bench(
+                        shader,
+                        &args.shader_glob,
+                        &icon_file,
+                        frames,
+                        (width, height),
+                    )
+                    .await;
+                }
+                None => {}
+            }
+
+            let (bundle_shader, bundle_icon) = match args.bundle {
+                Some(file) => {
+                    let file = expand_path(&file)?;
+                    let bundle = shaderlock::bundle::Bundle::open(&file)?;
+                    // Named after the bundle too, so errors say where they came from.
+                    (
+                        bundle.shader.map(|(name, source)| (file.join(name), source)),
+                        bundle.icon.map(|(name, data)| (file.join(name), data)),
+                    )
+                }
+                None => (None, None),
+            };
+            let deterministic = args.deterministic;
+            let shader_seed = match args.shader_seed {
+                None if deterministic => Some(0),
+                seed => seed,
+            };
+            let (shader_file, output_shader_files) = split_shader_files(&args.shader_file)?;
+            let background = match (args.background, shader_file, args.shader_dir, bundle_shader) {
+                (Some(background), ..) => background,
+                (None, Some(file), ..) => Background::Shader(file),
+                (None, None, Some(dir), _) => Background::ShaderDir(expand_path(&dir)?),
+                (None, None, None, Some((name, source))) => Background::ShaderSource(name, source),
+                (None, None, None, None) => {
+                    Background::Shader(get_shader_file(&args.shader_glob, shader_seed)?)
+                }
+            };
+            let mut current_shader = match &background {
+                Background::Shader(file) => Some(file.clone()),
+                _ => None,
+            };
+            let icon = match bundle_icon {
+                Some((name, data)) => IconSource::Data(name, data),
+                None => IconSource::File(expand_path(&args.icon_file)?),
+            };
+            let fade_curve = args.fade_curve;
+            let icon_output = args.icon_output;
+            let unlock_on_sigusr1 = args.unlock_on_sigusr1;
+            let idle_signals = args.idle_signals;
+            let unlock_command_check = args.unlock_command_check;
+            let unlock_command_interval = args.unlock_command_interval;
+            let freeze_frame = args.freeze_frame;
+            let panic_key = args.panic_key;
+            let freeze_key = args.freeze_key;
+            let test_lock = args.test_lock;
+            let show_layout = args.show_layout;
+            let message = args.message;
+            let exit_delay = args.exit_delay;
+            let outro_duration = args.outro_duration.filter(|duration| !duration.is_zero());
+            let mut idle_shader = args
+                .idle_shader
+                .map(|file| expand_path(&file))
+                .transpose()?;
+            let idle_frame_interval = std::time::Duration::from_secs(1) / args.idle_fps;
+            let no_freeze = args.no_freeze;
+            let max_shader_runtime = args.max_shader_runtime;
+            let cycle_shaders = args.cycle_shaders;
+            let shader_glob = args.shader_glob;
+            let random_per_output = args.random_per_output;
+            let no_screenshot = args.no_screenshot;
+            let dump_screenshots = args.dump_screenshots;
+            let no_gpu = args.no_gpu;
+            let freeze_after = args.freeze_after;
+            let fade_duration = args.fade_duration;
+            // A manual freeze or unfreeze with --freeze-key overrides the inactivity timer, though
+            // not an external idle manager.
+            let is_frozen = move |last_keypress_time: std::time::Instant,
+                                  idle_requested: bool,
+                                  manual_freeze: Option<bool>| {
+                idle_requested
+                    || manual_freeze.unwrap_or_else(|| {
+                        !no_freeze && last_keypress_time.elapsed() >= freeze_after
+                    })
+            };
+            let icon_fade = args.icon_fade;
+            let watch_shader = args.watch_shader;
+            let icon_size_mm = args.icon_size_mm;
+            let surfaces_file = args.surfaces_file;
+            let control_socket = args.control_socket;
+            let show_cursor = args.show_cursor;
+            // --no-screenshot can't be added alongside --dump-screenshots.
+            let can_skip_screenshot = !no_screenshot && dump_screenshots.is_none();
+            let watchdog = args.watchdog.map(|timeout| {
+                // Started over without the GPU, as the same process can't get past a stuck
+                // driver. A new screenshot would only show the lock screen.
+                let fallback = (!no_gpu).then(|| {
+                    use std::os::unix::process::CommandExt;
+                    let mut args_os = std::env::args_os();
+                    let mut command = std::process::Command::new("/proc/self/exe");
+                    command.arg0(args_os.next().unwrap_or_default()).args(args_os).arg("--no-gpu");
+                    if can_skip_screenshot {
+                        command.arg("--no-screenshot");
+                    }
+                    command
+                });
+                shaderlock::watchdog::Watchdog::spawn(timeout, fallback)
+            });
+            let keypress_sound = args
+                .keypress_sound
+                .map(|file| expand_path(&file).map(shaderlock::sound::KeypressSound::new))
+                .transpose()?;
+            #[cfg(debug_assertions)]
+            let dev = args.dev;
+
+            let channels = load_channels([
+                args.channel0.as_deref(),
+                args.channel1.as_deref(),
+                args.channel2.as_deref(),
+                args.channel3.as_deref(),
+            ])?;
+            let mpris = args.mpris;
+
+            // The lock screen must draw something, so the built-in screenshot shader, which always
+            // compiles, is the last resort unless failing is preferred.
+            let fallback_backgrounds = if args.strict_shader {
+                vec![]
+            } else {
+                args.fallback_shaders
+                    .iter()
+                    .filter_map(|shader| match find_shader(shader, &shader_glob) {
+                        Result::Ok(file) => Some(Background::Shader(file)),
+                        Result::Err(e) => {
+                            warn!("Skipping fallback shader {:?}: {:#}", shader, e);
+                            None
+                        }
+                    })
+                    .chain(std::iter::once(Background::Screenshot))
+                    .collect()
+            };
+            let graphics_config = shaderlock::graphics::Config {
+                frame_latency: args.frame_latency,
+                backdrop: args.icon_backdrop,
+                feedback: args.feedback,
+                glsl_frontend: args.glsl_frontend,
+                shader_entry: args.shader_entry,
+                flip_y: args.flip_y,
+                shadertoy_wrapper: !args.no_shadertoy_wrapper,
+                message: message.clone(),
+                icon_antialias: !args.no_icon_antialias,
+                icon_tint: args.icon_tint,
+                render_scale: args.render_scale,
+                internal_format: args.internal_format,
+                icon_size_mm,
+                per_window_time: args.per_output_time,
+                fallback_backgrounds,
+                clear_color: if args.quiet {
+                    Color::BLACK
+                } else {
+                    Color::RED
+                },
             };
-            let icon_file = std::path::PathBuf::from(args.icon_file);
+            let mut timings = StartupTimings::new(start);
+            let compile_start = std::time::Instant::now();
+            let mut graphics_manager = shaderlock::graphics::Manager::new(
+                &background,
+                &icon,
+                channels,
+                graphics_config,
+            )
+            .context("Failed to create graphics manager")?;
+            timings.compile = compile_start.elapsed();
 
-            let graphics_manager = shaderlock::graphics::Manager::new(&shader_file, &icon_file)
-                .context("Failed to create graphics manager")?;
+            let mut battery = None;
+            let battery_indicator = args.battery_indicator
+                && match shaderlock::battery::read() {
+                    Result::Ok(Some(b)) => {
+                        graphics_manager
+                            .set_battery(Some(&b))
+                            .context("Failed to create battery indicator")?;
+                        battery = Some(b);
+                        true
+                    }
+                    Result::Ok(None) => {
+                        info!("No battery found, not showing the battery indicator");
+                        false
+                    }
+                    Err(e) => {
+                        warn!("Failed to read battery, not showing the battery indicator: {:#}", e);
+                        false
+                    }
+                };
 
             let mut authenticator_backend = if args.skip_auth {
                 Either::Left(NullAuthenticatorBackend::new())
             } else {
-                Either::Right(PamAuthenticatorBackend::new()?)
+                Either::Right(PamAuthenticatorBackend::new(
+                    args.auth_timeout,
+                    args.auth_retries,
+                )?)
             };
 
             let mut auth = Authenticator::new(
@@ -90,39 +1354,198 @@ async fn main() -> Result<()> {
                     .into_inner(),
             )?;
 
+            let connect_start = std::time::Instant::now();
             let mut wm = WindowManager::new()?;
+            timings.connect = connect_start.elapsed();
 
-            let mut keyboard = None;
-            let init_time = std::time::Instant::now();
+            // Every seat's keyboard feeds the authenticator, so no seat is left able to reach
+            // the desktop on multi-seat machines.
+            let mut keyboard_by_seat = HashMap::new();
+            let mut pointer_by_seat = HashMap::new();
+            #[cfg(debug_assertions)]
+            let mut modifiers = sctk::seat::keyboard::Modifiers::default();
+            let mut lock_state = LockState::Starting;
+            let mut shader_start_time = std::time::Instant::now();
+            // Shaders picked by --max-shader-runtime, to seed the next pick when deterministic.
+            let mut shader_rotations = 0u64;
             let mut last_keypress_time = std::time::Instant::now();
+            // Set by an external idle manager, until the user is active again.
+            let mut idle_requested = false;
+            // Set by the panic key, until the next keypress.
+            let mut blanked = false;
+            // Toggled by the freeze key: frozen or live regardless of the inactivity timer.
+            let mut manual_freeze = None;
+            // Set once the password is accepted, if there is an outro to play before unlocking.
+            let mut outro_start: Option<std::time::Instant> = None;
+            // Passed to shaders as iControl, set over the control socket.
+            let mut control = [0.0; shaderlock::control::CONTROLS];
+            // Lock surface with keyboard focus, if any.
+            let mut focused_surface = None;
+            // Set while a PAM prompt or a timeout is shown in place of the message.
+            let mut message_replaced = false;
+            // Set while PAM checks what was typed, which must finish before it can take more.
+            let mut auth_pending = false;
+            let mut idle_start_time = None;
+            let mut idle_timer_by_surface = HashMap::<ObjectId, tokio::task::JoinHandle<()>>::new();
 
             let mut output_by_surface = HashMap::<ObjectId, WlOutput>::new();
+            // How the outputs each surface is shown on are rotated, if not upright.
+            let mut transform_by_surface = HashMap::<ObjectId, Transform>::new();
+            let mut transfer_function_by_surface =
+                HashMap::<ObjectId, shaderlock::color_management::TransferFunction>::new();
+            // Where the pointer was last over each surface.
+            let mut mouse_by_surface = HashMap::<ObjectId, (f32, f32)>::new();
+            // Outputs each surface is shown on, in the order it entered them.
+            let mut entered_outputs_by_surface = HashMap::<ObjectId, Vec<WlOutput>>::new();
             let mut frame_by_output = HashMap::<ObjectId, ScreencopyBuffer>::new();
             let mut lock_surface_by_surface = HashMap::<ObjectId, SessionLockSurface>::new();
             let mut graphics_by_surface = HashMap::<ObjectId, shaderlock::graphics::State>::new();
+            let mut presented_surfaces = HashSet::<ObjectId>::new();
+            let mut output_index_by_surface = HashMap::<ObjectId, u32>::new();
+            let mut shader_by_surface = HashMap::<ObjectId, std::path::PathBuf>::new();
+            // Set while the graphics manager holds an output's own shader from --shader-file.
+            let mut output_shader_loaded = false;
+            let mut surface_regions = HashMap::<ObjectId, String>::new();
+            let mut shm_buffer_by_surface = HashMap::<ObjectId, slot::Buffer>::new();
 
             wm.run(|conn, qh, mut state, events| async move {
+                let events_tx = state.access(|s| s.events.clone());
+                if unlock_on_sigusr1 {
+                    forward_signal(SignalKind::user_defined1(), "SIGUSR1", &events_tx, Event::UnlockRequested)?;
+                }
+                if idle_signals {
+                    forward_signal(SignalKind::user_defined2(), "SIGUSR2", &events_tx, Event::ExternalIdle(true))?;
+                    forward_signal(SignalKind::from_raw(libc::SIGCONT), "SIGCONT", &events_tx, Event::ExternalIdle(false))?;
+                }
+                if let Some(path) = &control_socket {
+                    let listener = shaderlock::control::bind(path)?;
+                    info!("Taking commands on {:?}", path);
+                    tokio::task::spawn_local(shaderlock::control::serve(listener, events_tx.clone()));
+                }
+                if battery_indicator {
+                    let events_tx = events_tx.clone();
+                    tokio::task::spawn_local(async move {
+                        loop {
+                            tokio::time::sleep(BATTERY_POLL_INTERVAL).await;
+                            match shaderlock::battery::read() {
+                                Result::Ok(battery) => {
+                                    if events_tx.unbounded_send(Event::BatteryChanged(battery)).is_err() {
+                                        break;
+                                    }
+                                }
+                                Err(e) => warn!("Failed to read battery: {:#}", e),
+                            }
+                        }
+                    });
+                }
+                if watch_shader {
+                    match current_shader.clone() {
+                        Some(file) => {
+                            let events_tx = events_tx.clone();
+                            tokio::task::spawn_local(async move {
+                                let modified = || std::fs::metadata(&file).and_then(|m| m.modified()).ok();
+                                let mut last_modified = modified();
+                                loop {
+                                    tokio::time::sleep(SHADER_POLL_INTERVAL).await;
+                                    let now_modified = modified();
+                                    if now_modified != last_modified {
+                                        last_modified = now_modified;
+                                        if events_tx.unbounded_send(Event::ShaderChanged(file.clone())).is_err() {
+                                            break;
+                                        }
+                                    }
+                                }
+                            });
+                        }
+                        None => warn!("Not watching the shader, as it isn't a single file"),
+                    }
+                }
+                if mpris {
+                    // Queried alongside locking, so a slow media player doesn't hold it up.
+                    let events_tx = events_tx.clone();
+                    tokio::task::spawn_local(async move {
+                        match shaderlock::mpris::now_playing().await {
+                            Result::Ok(Some(now_playing)) => {
+                                let _ = events_tx.unbounded_send(Event::NowPlaying(now_playing));
+                            }
+                            Result::Ok(None) => debug!("nothing playing"),
+                            Err(e) => warn!("Failed to query media player: {:#}", e),
+                        }
+                    });
+                }
+                if let Some(command) = unlock_command_check {
+                    let events_tx = events_tx.clone();
+                    tokio::task::spawn_local(async move {
+                        shaderlock::unlock_check::wait_for_success(
+                            &command,
+                            unlock_command_interval,
+                        )
+                        .await;
+                        info!("Unlock command check succeeded, unlocking");
+                        let _ = events_tx.unbounded_send(Event::UnlockRequested);
+                    });
+                }
+
                 let outputs: Vec<_> = state.access(|s| s.output_state.outputs().collect());
-                // Screenshot capture must happen before the session lock else we will just get a black screen.
-                for output in outputs {
-                    let frame_handle = state
-                        .access(|s| {
-                            debug!("capture frame on output: {:?}", output);
-                            let res = s.screencopy_state().capture_output(&output, qh);
-                            conn.flush()?;
-                            res
-                        })?
-                        .await??;
-                    debug!("capture complete, getting buffer data");
-                    let frame = state.access(|s| s.get_buffer_data(frame_handle));
+                let primary_output = outputs.first().map(|o| o.id());
+                let capture_start = std::time::Instant::now();
+                if !no_screenshot {
+                    set_lock_state(&mut lock_state, LockState::Capturing);
+                    // Screenshot capture must happen before the session lock else we will just get a black screen.
+                    // Every capture is requested up front so they run concurrently.
+                    let captures = state.access(|s| -> Result<Vec<_>> {
+                        let captures = outputs
+                            .iter()
+                            .map(|output| {
+                                debug!("capture frame on output: {:?}", output);
+                                Ok((output.id(), s.screencopy_state().capture_output(output, qh)?))
+                            })
+                            .collect::<Result<_>>()?;
+                        conn.flush()?;
+                        Ok(captures)
+                    })?;
+                    for (output_id, capture) in captures {
+                        let frame_handle = match capture.await? {
+                            Result::Ok(frame_handle) => frame_handle,
+                            // Outputs without a screenshot are drawn over black instead.
+                            Result::Err(e @ ScreencopyError::CreateBuffer(_)) => {
+                                warn!("Failed to capture output, drawing without a screenshot: {:#}", anyhow!(e));
+                                continue;
+                            }
+                            Result::Err(e) => return Err(e.into()),
+                        };
+                        debug!("capture complete, getting buffer data");
+                        let frame = state.access(|s| s.get_buffer_data(frame_handle));
 
-                    frame_by_output.insert(output.id(), frame);
+                        if let Some(dir) = &dump_screenshots {
+                            let output = outputs.iter().find(|o| o.id() == output_id).unwrap();
+                            let name = state
+                                .access(|s| s.output_state.info(output))
+                                .and_then(|info| info.name)
+                                .unwrap_or_else(|| output_id.protocol_id().to_string());
+                            if let Err(e) = dump_screenshot(dir, &name, &frame) {
+                                warn!("Failed to dump screenshot: {:#}", e);
+                            }
+                        }
+                        frame_by_output.insert(output_id, frame);
+                    }
                 }
+                timings.capture = capture_start.elapsed();
 
                 // From this point onwards, the compositor will blank the screen and inhibit input to apps.
                 let session_lock = state.access(|s| s.session_lock_state.lock(qh))?;
+                set_lock_state(&mut lock_state, LockState::Locking);
+                {
+                    let events_tx = events_tx.clone();
+                    tokio::task::spawn_local(async move {
+                        tokio::time::sleep(LOCK_TIMEOUT).await;
+                        let _ = events_tx.unbounded_send(Event::LockTimeout);
+                    });
+                }
+                timings.lock_requested = Some(std::time::Instant::now());
                 sd_notify::notify(true, &[sd_notify::NotifyState::Ready])
                     .context("Failed to notify readiness")?;
+                let mut unlock_requested = false;
 
                 loop {
                     debug!("awaiting events");
@@ -132,88 +1555,901 @@ async fn main() -> Result<()> {
                         Event::NewOutput(output) => {
                             let lock_surface = state.access(|s| {
                                 let surface = s.compositor_state.create_surface(qh);
+                                // Without color management, every surface is sRGB.
+                                if let Err(e) = s.color_management_state.watch_surface(&surface, qh) {
+                                    debug!("not following preferred colour: {}", e);
+                                }
                                 session_lock.create_lock_surface(surface, &output, qh)
                             });
                             conn.flush()?;
                             debug!("created lock surface: {:?}", lock_surface);
+                            output_index_by_surface.insert(
+                                lock_surface.wl_surface().id(),
+                                output_index_by_surface.len() as u32,
+                            );
                             output_by_surface.insert(lock_surface.wl_surface().id(), output);
                             lock_surface_by_surface
                                 .insert(lock_surface.wl_surface().id(), lock_surface);
                         }
-                        Event::SessionLocked => {}
+                        Event::SessionLocked => {
+                            set_lock_state(&mut lock_state, LockState::Locked);
+                            if let Some(duration) = test_lock {
+                                info!("Test lock, unlocking in {:?}", duration);
+                                let events_tx = events_tx.clone();
+                                tokio::task::spawn_local(async move {
+                                    tokio::time::sleep(duration).await;
+                                    let _ = events_tx.unbounded_send(Event::UnlockRequested);
+                                });
+                            }
+                            if unlock_requested {
+                                session_lock.unlock();
+                                conn.display().sync(qh, ExitSync);
+                                conn.flush()?;
+                            }
+                        }
+                        Event::UnlockRequested => {
+                            // Unlocking is a protocol error until the compositor confirms the lock.
+                            if session_lock.is_locked() {
+                                session_lock.unlock();
+                                conn.display().sync(qh, ExitSync);
+                                conn.flush()?;
+                            } else {
+                                unlock_requested = true;
+                            }
+                        }
                         Event::SessionLockFinished => {
                             error!("session lock failed!");
                             bail!("session lock failed!");
                         }
+                        Event::LockTimeout => {
+                            if !session_lock.is_locked() {
+                                // Exiting withdraws the lock request, so nothing is left on screen
+                                // that looks locked but isn't.
+                                error!(
+                                    "The compositor did not lock the session within {:?}. The session is NOT locked, exiting.",
+                                    LOCK_TIMEOUT
+                                );
+                                bail!("session was not locked within {:?}", LOCK_TIMEOUT);
+                            }
+                        }
                         Event::ConfigureLockSurface(lock_surface, (width, height)) => {
                             let surface = lock_surface.wl_surface();
+                            if width == 0 || height == 0 {
+                                // Some compositors send an empty configure before the real one.
+                                debug!("ignoring empty configure on surface: {:?}", surface);
+                                continue;
+                            }
                             let output = output_by_surface.get(&surface.id()).unwrap();
-                            // TODO: if a new monitor is plugged in there won't be a screenshot.
-                            let frame = frame_by_output.remove(&output.id()).unwrap();
+                            // Screenshots are kept in case graphics need initializing again.
+                            let frame = match frame_by_output.get(&output.id()) {
+                                Some(frame) => Some(frame.clone()),
+                                None if no_screenshot => None,
+                                None => {
+                                    // Outputs connected since locking can only capture the lock
+                                    // screen, but that beats having no surface at all.
+                                    debug!("capture frame on new output: {:?}", output);
+                                    let capture = state
+                                        .access(|s| -> Result<_> {
+                                            let res =
+                                                s.screencopy_state().capture_output(output, qh)?;
+                                            conn.flush()?;
+                                            Ok(res)
+                                        })?
+                                        .await?;
+                                    match capture {
+                                        Result::Ok(frame_handle) => {
+                                            let frame =
+                                                state.access(|s| s.get_buffer_data(frame_handle));
+                                            frame_by_output.insert(output.id(), frame.clone());
+                                            Some(frame)
+                                        }
+                                        Result::Err(e @ ScreencopyError::CreateBuffer(_)) => {
+                                            warn!("Failed to capture output, drawing without a screenshot: {:#}", anyhow!(e));
+                                            None
+                                        }
+                                        Result::Err(e) => return Err(e.into()),
+                                    }
+                                }
+                            };
+
+                            if let Some(path) = &surfaces_file {
+                                let info = state.access(|s| s.output_state.info(output));
+                                let name = info.as_ref().and_then(|i| i.name.clone());
+                                let (x, y) =
+                                    info.and_then(|i| i.logical_position).unwrap_or_default();
+                                surface_regions.insert(
+                                    surface.id(),
+                                    format!(
+                                        "{} {} {} {} {}",
+                                        name.as_deref().unwrap_or("-"),
+                                        x,
+                                        y,
+                                        width,
+                                        height
+                                    ),
+                                );
+                                if let Err(e) = write_surfaces_file(path, surface_regions.values())
+                                {
+                                    warn!("Failed to write surfaces file: {:#}", e);
+                                }
+                            }
 
                             let window = Window {
                                 display: conn.display(),
                                 surface: surface.clone(),
                             };
 
-                            debug!("initializing graphics on output: {:?}", output);
-                            let graphics = graphics_manager
-                                .init_window(window, frame, (width, height))
-                                .await?;
-                            debug!("graphics initialized");
+                            // Render at the output's scale so HiDPI outputs don't upscale a low-res buffer.
+                            let scale = state
+                                .access(|s| s.output_state.info(output))
+                                .map_or(1, |info| info.scale_factor.max(1));
+                            surface.set_buffer_scale(scale);
+
+                            if no_gpu {
+                                let image = frame.map(|frame| -> Result<_> {
+                                    let image = frame.to_image()?;
+                                    let size = (width * scale as u32, height * scale as u32);
+                                    Ok(if image.dimensions() == size {
+                                        image
+                                    } else {
+                                        image::imageops::resize(&image, size.0, size.1, image::imageops::FilterType::Triangle)
+                                    })
+                                });
+                                let buffer = state.access(|s| match image {
+                                    Some(Result::Ok(image)) => s.draw_image(surface, &image, scale),
+                                    Some(Result::Err(e)) => {
+                                        warn!("Failed to draw screenshot, drawing black: {:#}", e);
+                                        s.draw_solid(surface, (width, height), BLACK_ARGB)
+                                    }
+                                    None => s.draw_solid(surface, (width, height), BLACK_ARGB),
+                                })?;
+                                conn.flush()?;
+                                shm_buffer_by_surface.insert(surface.id(), buffer);
+                                if presented_surfaces.insert(surface.id())
+                                    && presented_surfaces.len() == lock_surface_by_surface.len()
+                                {
+                                    state.access(|s| s.queue_all_surfaces_presented());
+                                }
+                                continue;
+                            }
 
-                            graphics_by_surface.insert(surface.id(), graphics);
+                            let output_name = state
+                                .access(|s| s.output_state.info(output))
+                                .and_then(|info| info.name);
+                            match output_name.and_then(|name| output_shader_files.get(&name)) {
+                                Some(shader_file) => {
+                                    use_shader_for_next_window(&mut graphics_manager, shader_file);
+                                    // Remembered for when the window is initialized again.
+                                    shader_by_surface.insert(surface.id(), shader_file.clone());
+                                    output_shader_loaded = true;
+                                }
+                                None if random_per_output => {
+                                    let shader_file = match shader_by_surface.entry(surface.id()) {
+                                        Entry::Occupied(entry) => entry.into_mut(),
+                                        Entry::Vacant(entry) => {
+                                            let index = output_index_by_surface[&surface.id()] as u64;
+                                            entry.insert(get_shader_file(
+                                                &shader_glob,
+                                                shader_seed.map(|seed| seed.wrapping_add(index)),
+                                            )?)
+                                        }
+                                    };
+                                    use_shader_for_next_window(&mut graphics_manager, shader_file);
+                                }
+                                // Outputs without their own shader share the one every other output has.
+                                None if std::mem::take(&mut output_shader_loaded) => {
+                                    use_shared_shader(&mut graphics_manager, current_shader.as_deref(), &background);
+                                }
+                                None => {}
+                            }
 
-                            // Trigger the first draw. Requesting a frame event from Wayland doesn't seem to work,
-                            // I think that only works after the first commit.
-                            state.access(|s| s.queue_redraw(lock_surface.wl_surface().clone()));
+                            debug!("initializing graphics on output: {:?}", output);
+                            let init_start = std::time::Instant::now();
+                            let transfer_function =
+                                transfer_function_by_surface.get(&surface.id()).copied().unwrap_or_default();
+                            let init_result = graphics_manager
+                                .init_window(window, frame, (width, height), scale as u32, transfer_function)
+                                .await;
+                            timings.device_init += init_start.elapsed();
+                            match init_result {
+                                Result::Ok(mut graphics) => {
+                                    debug!("graphics initialized");
+                                    let show_icon = match icon_output.as_deref() {
+                                        None => true,
+                                        Some("primary") => primary_output == Some(output.id()),
+                                        Some(name) => state
+                                            .access(|s| s.output_state.info(output))
+                                            .and_then(|info| info.name)
+                                            .is_some_and(|output_name| output_name == name),
+                                    };
+                                    graphics.set_icon_visible(show_icon);
+                                    if icon_size_mm.is_some() {
+                                        let output = shown_on(&surface.id(), &entered_outputs_by_surface, &output_by_surface);
+                                        if let Some(info) = output.and_then(|o| state.access(|s| s.output_state.info(o))) {
+                                            graphics.set_physical_size(info.physical_size);
+                                        }
+                                    }
+                                    graphics_by_surface.insert(surface.id(), graphics);
+
+                                    // Trigger the first draw. Requesting a frame event from Wayland doesn't seem to work,
+                                    // I think that only works after the first commit.
+                                    state.access(|s| {
+                                        s.queue_redraw(lock_surface.wl_surface().clone())
+                                    });
+                                }
+                                Result::Err(e) => {
+                                    // The session is already locked, so keep it that way with a blank screen
+                                    // rather than bailing out.
+                                    error!("Failed to initialize graphics, drawing black: {:?}", e);
+                                    let buffer = state.access(|s| {
+                                        s.draw_solid(surface, (width, height), BLACK_ARGB)
+                                    })?;
+                                    conn.flush()?;
+                                    shm_buffer_by_surface.insert(surface.id(), buffer);
+                                    if presented_surfaces.insert(surface.id())
+                                        && presented_surfaces.len() == lock_surface_by_surface.len()
+                                    {
+                                        state.access(|s| s.queue_all_surfaces_presented());
+                                    }
+                                }
+                            }
                         }
                         Event::RedrawRequested(surface) => {
                             debug!("redraw requested on surface: {:?}", surface);
+                            // A wake from idle redraws straight away, replacing the throttled one.
+                            if let Some(timer) = idle_timer_by_surface.remove(&surface.id()) {
+                                timer.abort();
+                            }
+                            // The outro plays to the end, even if input would have frozen it.
+                            let frozen = outro_start.is_none()
+                                && is_frozen(last_keypress_time, idle_requested, manual_freeze);
+                            let idle = frozen && idle_shader.is_some();
+                            if idle != idle_start_time.is_some() {
+                                let next = match (&idle_shader, &current_shader) {
+                                    (Some(file), _) if idle => Background::Shader(file.clone()),
+                                    (_, Some(file)) => Background::Shader(file.clone()),
+                                    (_, None) => background.clone(),
+                                };
+                                debug!("idle {} -> {}", !idle, idle);
+                                match graphics_manager.set_background(&next) {
+                                    Result::Ok(()) => {
+                                        for graphics in graphics_by_surface.values_mut() {
+                                            graphics_manager.apply_shader(graphics);
+                                        }
+                                        idle_start_time = idle.then(std::time::Instant::now);
+                                    }
+                                    Result::Err(e) => {
+                                        warn!("Failed to switch idle shader, disabling it: {:#}", e);
+                                        idle_shader = None;
+                                        idle_start_time = None;
+                                    }
+                                }
+                            }
+                            if idle_start_time.is_none()
+                                && max_shader_runtime.is_some_and(|max| shader_start_time.elapsed() > max)
+                            {
+                                shader_rotations += 1;
+                                let seed = shader_seed
+                                    .filter(|_| deterministic)
+                                    .map(|seed| seed.wrapping_add(shader_rotations));
+                                let result = get_shader_file(&shader_glob, seed).and_then(|file| {
+                                    switch_shader(
+                                        &mut graphics_manager,
+                                        graphics_by_surface.values_mut(),
+                                        file,
+                                    )
+                                });
+                                match result {
+                                    Result::Ok(file) => current_shader = Some(file),
+                                    Result::Err(e) => warn!("Failed to switch shader: {:#}", e),
+                                }
+                                shader_start_time = std::time::Instant::now();
+                            }
                             let graphics = graphics_by_surface.get_mut(&surface.id()).unwrap();
+                            let fade_amount = if idle_requested {
+                                1.0
+                            } else if no_freeze || manual_freeze == Some(false) {
+                                0.0
+                            } else {
+                                fade_curve.fade_amount(
+                                    last_keypress_time.elapsed(),
+                                    freeze_after,
+                                    fade_duration,
+                                )
+                            };
+                            let unlock_progress = match (outro_start, outro_duration) {
+                                (Some(start), Some(duration)) => (start.elapsed().as_secs_f32()
+                                    / duration.as_secs_f32())
+                                .min(1.0),
+                                _ => 0.0,
+                            };
                             let ctx = RenderContext {
-                                elapsed: init_time.elapsed(),
-                                fade_amount: (last_keypress_time.elapsed() + FADE_BEFORE_FREEZE)
-                                    .saturating_sub(FREEZE_AFTER_INACTIVITY)
-                                    .as_secs_f32()
-                                    / FADE_BEFORE_FREEZE.as_secs_f32(),
+                                elapsed: match (idle_start_time, freeze_frame) {
+                                    (Some(start), _) => start.elapsed(),
+                                    (None, Some(elapsed)) if frozen => elapsed,
+                                    // A switched shader restarts on every output at once.
+                                    _ => graphics
+                                        .time_origin()
+                                        .map_or(shader_start_time, |origin| origin.max(shader_start_time))
+                                        .elapsed(),
+                                },
+                                // The idle shader is its own low-power look, so it isn't faded.
+                                fade_amount: if idle_start_time.is_some() {
+                                    0.0
+                                } else {
+                                    fade_amount
+                                },
+                                lock_state,
+                                icon_opacity: (1.0 - unlock_progress)
+                                    * if !icon_fade || lock_state == LockState::Authenticating {
+                                        1.0
+                                    } else {
+                                        fade_amount
+                                    },
+                                output_index: output_index_by_surface[&surface.id()],
+                                output_count: output_index_by_surface.len() as u32,
+                                password_length: auth.len() as u32,
+                                last_char_age: auth.last_push().map(|t| t.elapsed()),
+                                battery,
+                                blank: blanked,
+                                unlock_progress,
+                                focused: focused_surface == Some(surface.id()),
+                                output_transform: transform_by_surface
+                                    .get(&surface.id())
+                                    .copied()
+                                    .unwrap_or(Transform::Normal),
+                                control,
+                                mouse: mouse_by_surface
+                                    .get(&surface.id())
+                                    .copied()
+                                    .unwrap_or_default(),
+                            };
+                            let transfer_function =
+                                transfer_function_by_surface.get(&surface.id()).copied().unwrap_or_default();
+                            let result = if graphics.transfer_function() == transfer_function {
+                                graphics.render(ctx).context("Failed to render")
+                            } else {
+                                Err(anyhow!("Compositor now prefers {:?} colour", transfer_function))
+                            };
+                            let frame = match result {
+                                Result::Ok(frame) => frame,
+                                Result::Err(e) => {
+                                    // Keep the session locked while we start over with a new device or format.
+                                    warn!("Reinitializing graphics: {:#}", e);
+                                    let old_graphics =
+                                        graphics_by_surface.remove(&surface.id()).unwrap();
+                                    let size = old_graphics.size();
+                                    let scale = old_graphics.scale();
+                                    let show_icon = old_graphics.icon_visible();
+                                    drop(old_graphics);
+
+                                    let output = output_by_surface.get(&surface.id()).unwrap();
+                                    let screenshot = frame_by_output.get(&output.id()).cloned();
+                                    let window = Window {
+                                        display: conn.display(),
+                                        surface: surface.clone(),
+                                    };
+                                    match shader_by_surface.get(&surface.id()) {
+                                        Some(shader_file) => {
+                                            use_shader_for_next_window(&mut graphics_manager, shader_file);
+                                            output_shader_loaded = true;
+                                        }
+                                        None if std::mem::take(&mut output_shader_loaded) => {
+                                            use_shared_shader(&mut graphics_manager, current_shader.as_deref(), &background);
+                                        }
+                                        None => {}
+                                    }
+                                    match graphics_manager
+                                        .init_window(window, screenshot, size, scale, transfer_function)
+                                        .await
+                                    {
+                                        Result::Ok(mut graphics) => {
+                                            graphics.set_icon_visible(show_icon);
+                                            graphics.skip_icon_intro();
+                                            if icon_size_mm.is_some() {
+                                                let output = shown_on(&surface.id(), &entered_outputs_by_surface, &output_by_surface);
+                                                if let Some(info) = output.and_then(|o| state.access(|s| s.output_state.info(o))) {
+                                                    graphics.set_physical_size(info.physical_size);
+                                                }
+                                            }
+                                            graphics_by_surface.insert(surface.id(), graphics);
+                                            state.access(|s| s.queue_redraw(surface.clone()));
+                                        }
+                                        Result::Err(e) => {
+                                            error!(
+                                                "Failed to reinitialize graphics, drawing black: {:?}",
+                                                e
+                                            );
+                                            let buffer = state.access(|s| {
+                                                s.draw_solid(&surface, size, BLACK_ARGB)
+                                            })?;
+                                            conn.flush()?;
+                                            shm_buffer_by_surface.insert(surface.id(), buffer);
+                                        }
+                                    }
+                                    continue;
+                                }
+                            };
+                            let frame = match frame {
+                                Some(frame) => frame,
+                                None => {
+                                    // Nothing is committed, so a frame callback would never arrive.
+                                    state.access(|s| s.queue_redraw(surface.clone()));
+                                    continue;
+                                }
                             };
-                            let frame = graphics.render(ctx);
-                            if last_keypress_time.elapsed() < FREEZE_AFTER_INACTIVITY {
+                            if blanked {
+                                debug!("blanked, not scheduling another frame");
+                            } else if idle_start_time.is_some() {
+                                debug!("scheduling next idle frame");
+                                let events_tx = events_tx.clone();
+                                let idle_surface = surface.clone();
+                                let timer = tokio::task::spawn_local(async move {
+                                    tokio::time::sleep(idle_frame_interval).await;
+                                    let _ = events_tx.unbounded_send(Event::RedrawRequested(idle_surface));
+                                });
+                                idle_timer_by_surface.insert(surface.id(), timer);
+                            } else if !frozen {
                                 debug!("requesting next frame");
                                 surface.frame(qh, surface.clone());
                                 conn.flush()?;
                             }
                             debug!("scheduling present of current frame");
                             frame.present();
+                            if let Some(watchdog) = &watchdog {
+                                watchdog.presented();
+                            }
+
+                            if presented_surfaces.insert(surface.id())
+                                && presented_surfaces.len() == lock_surface_by_surface.len()
+                            {
+                                state.access(|s| s.queue_all_surfaces_presented());
+                            }
+                        }
+                        Event::ScaleFactorChanged(surface, scale) => {
+                            // Surfaces drawing black without graphics stay at scale 1.
+                            if let Some(graphics) = graphics_by_surface.get_mut(&surface.id()) {
+                                let scale = scale.max(1);
+                                match graphics_manager.set_scale(graphics, scale as u32) {
+                                    Result::Ok(()) => {
+                                        surface.set_buffer_scale(scale);
+                                        // Animating surfaces pick up the new size on their next frame.
+                                        if is_frozen(last_keypress_time, idle_requested, manual_freeze) {
+                                            state.access(|s| s.queue_redraw(surface.clone()));
+                                        }
+                                    }
+                                    Result::Err(e) => warn!("Failed to change scale: {:#}", e),
+                                }
+                            }
+                        }
+                        Event::SurfaceEntered(ref surface, ref output)
+                        | Event::SurfaceLeft(ref surface, ref output) => {
+                            let outputs = entered_outputs_by_surface.entry(surface.id()).or_default();
+                            outputs.retain(|o| o != output);
+                            if matches!(event, Event::SurfaceEntered(..)) {
+                                outputs.push(output.clone());
+                            }
+                            // Take physical properties from the output the surface is shown on
+                            // now, which may not be the one it was created for.
+                            let shown = shown_on(&surface.id(), &entered_outputs_by_surface, &output_by_surface);
+                            if let (Some(graphics), Some(shown), Some(_)) = (
+                                graphics_by_surface.get_mut(&surface.id()),
+                                shown,
+                                icon_size_mm,
+                            ) {
+                                if let Some(info) = state.access(|s| s.output_state.info(shown)) {
+                                    graphics.set_physical_size(info.physical_size);
+                                    if is_frozen(last_keypress_time, idle_requested, manual_freeze) {
+                                        state.access(|s| s.queue_redraw(surface.clone()));
+                                    }
+                                }
+                            }
+                        }
+                        Event::TransformChanged(surface, transform) => {
+                            transform_by_surface.insert(surface.id(), transform);
+                            if is_frozen(last_keypress_time, idle_requested, manual_freeze)
+                                && graphics_by_surface.contains_key(&surface.id())
+                            {
+                                state.access(|s| s.queue_redraw(surface.clone()));
+                            }
+                        }
+                        Event::TransferFunctionChanged(surface, transfer_function) => {
+                            debug!("surface {:?} prefers {:?}", surface, transfer_function);
+                            transfer_function_by_surface.insert(surface.id(), transfer_function);
+                            // The next frame initializes the window again in the new format.
+                            if graphics_by_surface
+                                .get(&surface.id())
+                                .is_some_and(|graphics| graphics.transfer_function() != transfer_function)
+                                && is_frozen(last_keypress_time, idle_requested, manual_freeze)
+                            {
+                                state.access(|s| s.queue_redraw(surface.clone()));
+                            }
+                        }
+                        Event::AllSurfacesPresented => {
+                            info!("all surfaces presented");
+                            timings.log();
+                            sd_notify::notify(false, &[sd_notify::NotifyState::Status("visible")])
+                                .context("Failed to notify status")?;
                         }
                         Event::NewSeatCapability(seat, capability) => {
                             if capability == sctk::seat::Capability::Keyboard {
-                                debug!("configure keyboard");
-                                keyboard.replace(
-                                    state.access(|s| s.seat_state.get_keyboard(qh, &seat, None))?,
-                                );
+                                debug!("configure keyboard for seat {:?}", seat);
+                                let keyboard =
+                                    state.access(|s| s.seat_state.get_keyboard(qh, &seat, None))?;
+                                keyboard_by_seat.insert(seat.id(), keyboard);
+                            }
+                            // Pointers are needed for iMouse, but only themed to show the cursor.
+                            if capability == sctk::seat::Capability::Pointer {
+                                debug!("configure pointer for seat {:?}", seat);
+                                let pointer = state.access(|s| {
+                                    if !show_cursor {
+                                        return s.seat_state.get_pointer(qh, &seat).map(Either::Right);
+                                    }
+                                    let cursor_surface = s.compositor_state.create_surface(qh);
+                                    s.seat_state
+                                        .get_pointer_with_theme(
+                                            qh,
+                                            &seat,
+                                            s.shm.wl_shm(),
+                                            cursor_surface,
+                                            sctk::seat::pointer::ThemeSpec::System,
+                                        )
+                                        .map(Either::Left)
+                                })?;
+                                pointer_by_seat.insert(seat.id(), pointer);
                             }
                         }
-                        Event::RemoveSeatCapability(_seat, capability) => {
+                        Event::RemoveSeatCapability(seat, capability) => {
                             if capability == sctk::seat::Capability::Keyboard {
-                                debug!("deconfigure keyboard");
-                                keyboard.take();
+                                debug!("deconfigure keyboard for seat {:?}", seat);
+                                keyboard_by_seat.remove(&seat.id());
+                            }
+                            if capability == sctk::seat::Capability::Pointer {
+                                pointer_by_seat.remove(&seat.id());
                             }
                         }
+                        Event::RemoveSeat(seat) => {
+                            keyboard_by_seat.remove(&seat.id());
+                            pointer_by_seat.remove(&seat.id());
+                        }
+                        Event::PointerEnter(pointer, serial) => {
+                            let themed = pointer_by_seat
+                                .values()
+                                .filter_map(|pointer| pointer.as_ref().left())
+                                .find(|themed| themed.pointer() == &pointer);
+                            match themed {
+                                Some(themed) => {
+                                    if let Err(e) = themed
+                                        .set_cursor(conn, sctk::seat::pointer::CursorIcon::Default)
+                                    {
+                                        warn!("Failed to set cursor: {}", e);
+                                    }
+                                }
+                                None => pointer.set_cursor(serial, None, 0, 0),
+                            }
+                        }
+                        Event::PointerMoved(surface, position) => {
+                            mouse_by_surface.insert(surface.id(), position);
+                            if is_frozen(last_keypress_time, idle_requested, manual_freeze) {
+                                debug!("waking frozen surfaces");
+                                wake_all_surfaces(&mut state, &lock_surface_by_surface, &graphics_by_surface);
+                            }
+                            idle_requested = false;
+                            last_keypress_time = std::time::Instant::now();
+                        }
+                        Event::ShaderChanged(file) => {
+                            // Ignore the file once another shader has been switched to.
+                            if current_shader.as_ref() != Some(&file) {
+                                continue;
+                            }
+                            let result = switch_shader(&mut graphics_manager, graphics_by_surface.values_mut(), file);
+                            match result {
+                                Result::Ok(file) => {
+                                    current_shader = Some(file);
+                                    if is_frozen(last_keypress_time, idle_requested, manual_freeze) {
+                                        wake_all_surfaces(&mut state, &lock_surface_by_surface, &graphics_by_surface);
+                                    }
+                                }
+                                Result::Err(e) => warn!("Failed to reload shader, keeping the old one: {:#}", e),
+                            }
+                        }
+                        Event::BatteryChanged(new_battery) => {
+                            let relabel = new_battery.map(|b| b.label()) != battery.map(|b| b.label());
+                            battery = new_battery;
+                            if relabel {
+                                debug!("battery changed: {:?}", battery);
+                                match graphics_manager.set_battery(battery.as_ref()) {
+                                    Result::Ok(()) => {
+                                        for graphics in graphics_by_surface.values_mut() {
+                                            if let Err(e) = graphics_manager.apply_battery(graphics) {
+                                                warn!("Failed to update battery indicator: {:#}", e);
+                                            }
+                                        }
+                                        // Animating surfaces pick up the new indicator on their next frame.
+                                        if is_frozen(last_keypress_time, idle_requested, manual_freeze) {
+                                            wake_all_surfaces(&mut state, &lock_surface_by_surface, &graphics_by_surface);
+                                        }
+                                    }
+                                    Result::Err(e) => warn!("Failed to update battery indicator: {:#}", e),
+                                }
+                            }
+                        }
+                        Event::LayoutChanged(name) => {
+                            info!("Keyboard layout: {}", name);
+                            if show_layout {
+                                match graphics_manager.set_layout(Some(&name)) {
+                                    Result::Ok(()) => {
+                                        for graphics in graphics_by_surface.values_mut() {
+                                            if let Err(e) = graphics_manager.apply_layout(graphics) {
+                                                warn!("Failed to update layout indicator: {:#}", e);
+                                            }
+                                        }
+                                        if is_frozen(last_keypress_time, idle_requested, manual_freeze) {
+                                            wake_all_surfaces(&mut state, &lock_surface_by_surface, &graphics_by_surface);
+                                        }
+                                    }
+                                    Result::Err(e) => warn!("Failed to update layout indicator: {:#}", e),
+                                }
+                            }
+                        }
+                        Event::NowPlaying(now_playing) => {
+                            info!("Now playing {:?} by {:?}", now_playing.title, now_playing.artist);
+                            let label = now_playing.label();
+                            graphics_manager.set_channel(MPRIS_ART_CHANNEL, now_playing.art);
+                            if let Err(e) = graphics_manager.set_now_playing(Some(label.as_str()).filter(|l| !l.is_empty())) {
+                                warn!("Failed to show the playing track: {:#}", e);
+                            }
+                            for graphics in graphics_by_surface.values_mut() {
+                                graphics_manager.apply_channel(graphics, MPRIS_ART_CHANNEL);
+                                if let Err(e) = graphics_manager.apply_now_playing(graphics) {
+                                    warn!("Failed to show the playing track: {:#}", e);
+                                }
+                            }
+                            if is_frozen(last_keypress_time, idle_requested, manual_freeze) {
+                                wake_all_surfaces(&mut state, &lock_surface_by_surface, &graphics_by_surface);
+                            }
+                        }
+                        Event::AuthFinished(result) => {
+                            auth_pending = false;
+                            // Surfaces may have frozen while PAM took its time, and the result
+                            // changes what they show.
+                            if is_frozen(last_keypress_time, idle_requested, manual_freeze) {
+                                wake_all_surfaces(&mut state, &lock_surface_by_surface, &graphics_by_surface);
+                            }
+                            // A prompt replaces the message until the conversation ends, and
+                            // a timeout until the next attempt, unlike a wrong password.
+                            let new_message = match &*result {
+                                Result::Ok(AuthStep::Prompt(prompt)) => Some(prompt.as_str()),
+                                Result::Err(AuthError::Timeout) => Some(AUTH_TIMEOUT_MESSAGE),
+                                _ => message.as_deref(),
+                            };
+                            let was_replaced = std::mem::replace(
+                                &mut message_replaced,
+                                matches!(
+                                    *result,
+                                    Result::Ok(AuthStep::Prompt(_)) | Result::Err(AuthError::Timeout)
+                                ),
+                            );
+                            if message_replaced || was_replaced {
+                                if let Err(e) = show_message(
+                                    &mut graphics_manager,
+                                    graphics_by_surface.values_mut(),
+                                    new_message,
+                                ) {
+                                    warn!("Failed to show PAM prompt: {:#}", e);
+                                }
+                            }
+                            match &*result {
+                                Result::Ok(AuthStep::Prompt(_)) => {
+                                    debug!("waiting for an answer to the PAM prompt");
+                                }
+                                Result::Ok(AuthStep::Done) => match outro_duration {
+                                    Some(duration) => {
+                                        // Surfaces keep drawing until the outro ends and
+                                        // unlocks.
+                                        debug!("playing outro before unlocking");
+                                        set_lock_state(&mut lock_state, LockState::Unlocking);
+                                        outro_start = Some(std::time::Instant::now());
+                                        let events_tx = events_tx.clone();
+                                        tokio::task::spawn_local(async move {
+                                            tokio::time::sleep(duration).await;
+                                            let _ = events_tx.unbounded_send(Event::UnlockRequested);
+                                        });
+                                    }
+                                    None => {
+                                        session_lock.unlock();
+                                        conn.display().sync(qh, ExitSync);
+                                        conn.flush()?;
+                                    }
+                                },
+                                Result::Err(e) => warn!("Authentication failed: {}", e),
+                            };
+                            if matches!(lock_state, LockState::Locked | LockState::Authenticating) {
+                                let new = if auth.is_empty() {
+                                    LockState::Locked
+                                } else {
+                                    LockState::Authenticating
+                                };
+                                set_lock_state(&mut lock_state, new);
+                            }
+                        }
+                        Event::Control(command) => {
+                            let was_frozen = is_frozen(last_keypress_time, idle_requested, manual_freeze);
+                            match command {
+                                ControlCommand::Set(index, value) => control[index] = value,
+                                ControlCommand::Shader(shader) => {
+                                    let result = find_shader(&shader, &shader_glob).and_then(|file| {
+                                        switch_shader(&mut graphics_manager, graphics_by_surface.values_mut(), file)
+                                    });
+                                    match result {
+                                        Result::Ok(file) => {
+                                            current_shader = Some(file);
+                                            shader_start_time = std::time::Instant::now();
+                                        }
+                                        Result::Err(e) => warn!("Failed to switch shader: {:#}", e),
+                                    }
+                                }
+                                ControlCommand::Freeze(freeze) => {
+                                    info!("{} the animation", if freeze { "Freezing" } else { "Unfreezing" });
+                                    manual_freeze = Some(freeze);
+                                }
+                            }
+                            // Frozen surfaces draw one frame to show the change, and animating
+                            // ones pick it up on their next.
+                            if was_frozen {
+                                wake_all_surfaces(&mut state, &lock_surface_by_surface, &graphics_by_surface);
+                            }
+                        }
+                        Event::ExternalIdle(true) => {
+                            idle_requested = true;
+                            // Draw one last, fully faded frame, which then stays frozen.
+                            wake_all_surfaces(&mut state, &lock_surface_by_surface, &graphics_by_surface);
+                        }
+                        Event::ExternalIdle(false) => {
+                            if is_frozen(last_keypress_time, idle_requested, manual_freeze) {
+                                debug!("waking frozen surfaces");
+                                wake_all_surfaces(&mut state, &lock_surface_by_surface, &graphics_by_surface);
+                            }
+                            idle_requested = false;
+                            last_keypress_time = std::time::Instant::now();
+                        }
+                        Event::KeyboardEnter(surface) => {
+                            focused_surface = Some(surface.id());
+                            if is_frozen(last_keypress_time, idle_requested, manual_freeze) {
+                                debug!("waking frozen surfaces");
+                                wake_all_surfaces(&mut state, &lock_surface_by_surface, &graphics_by_surface);
+                            }
+                            idle_requested = false;
+                            last_keypress_time = std::time::Instant::now();
+                        }
+                        Event::KeyboardLeave(surface) => {
+                            // Don't leave a half-typed password around while we can't see keystrokes.
+                            auth.cancel();
+                            if std::mem::take(&mut message_replaced) {
+                                if let Err(e) = show_message(
+                                    &mut graphics_manager,
+                                    graphics_by_surface.values_mut(),
+                                    message.as_deref(),
+                                ) {
+                                    warn!("Failed to restore message: {:#}", e);
+                                }
+                            }
+                            if lock_state == LockState::Authenticating {
+                                set_lock_state(&mut lock_state, LockState::Locked);
+                            }
+                            if focused_surface == Some(surface.id()) {
+                                focused_surface = None;
+                            }
+                            // Frozen surfaces still show focus, so redraw the one that lost it.
+                            if is_frozen(last_keypress_time, idle_requested, manual_freeze)
+                                && graphics_by_surface.contains_key(&surface.id())
+                            {
+                                state.access(|s| s.queue_redraw(surface));
+                            }
+                        }
+                        #[cfg(debug_assertions)]
+                        Event::ModifiersChanged(new_modifiers) => modifiers = new_modifiers,
+                        #[cfg(not(debug_assertions))]
+                        Event::ModifiersChanged(_) => {}
                         Event::KeyPressed(key_event) => {
                             // Sway will only attach input events after the first buffer is committed to a
                             // lock surface, so we will only start getting KeyPressed events after the first render.
+                            if outro_start.is_some() {
+                                continue;
+                            }
+                            if blanked {
+                                // Blanked surfaces stopped drawing, so all of them need waking. The key
+                                // only clears the blank, so it isn't typed into the password unseen.
+                                debug!("clearing blank");
+                                blanked = false;
+                                idle_requested = false;
+                                last_keypress_time = std::time::Instant::now();
+                                wake_all_surfaces(&mut state, &lock_surface_by_surface, &graphics_by_surface);
+                                continue;
+                            }
+                            if is_frozen(last_keypress_time, idle_requested, manual_freeze) {
+                                debug!("waking frozen surfaces");
+                                wake_all_surfaces(&mut state, &lock_surface_by_surface, &graphics_by_surface);
+                            }
+                            idle_requested = false;
                             last_keypress_time = std::time::Instant::now();
+                            if let Some(keypress_sound) = &keypress_sound {
+                                keypress_sound.play();
+                            }
+                            if let Some(watchdog) = &watchdog {
+                                if !graphics_by_surface.is_empty() {
+                                    watchdog.input();
+                                }
+                            }
+                            #[cfg(debug_assertions)]
+                            if dev && modifiers.ctrl && key_event.keysym == Keysym::Escape {
+                                warn!("Developer unlock");
+                                session_lock.unlock();
+                                conn.display().sync(qh, ExitSync);
+                                conn.flush()?;
+                                continue;
+                            }
                             match key_event {
+                                KeyEvent { keysym, .. } if panic_key == Some(keysym) => {
+                                    // Animating surfaces draw black on their next frame, and frozen
+                                    // ones were woken above.
+                                    info!("Panic key pressed, blanking until the next keypress");
+                                    blanked = true;
+                                }
+                                KeyEvent { keysym, .. } if freeze_key == Some(keysym) => {
+                                    // Frozen surfaces were woken above, and animating ones draw one
+                                    // last frame on their next redraw.
+                                    let freeze = manual_freeze != Some(true);
+                                    info!(
+                                        "Freeze key pressed, {} the animation",
+                                        if freeze { "freezing" } else { "unfreezing" }
+                                    );
+                                    manual_freeze = Some(freeze);
+                                }
+                                KeyEvent {
+                                    keysym: Keysym::Next,
+                                    ..
+                                } if cycle_shaders => {
+                                    let result = next_shader(&shader_glob, current_shader.as_deref())
+                                        .and_then(|file| {
+                                            switch_shader(
+                                                &mut graphics_manager,
+                                                graphics_by_surface.values_mut(),
+                                                file,
+                                            )
+                                        });
+                                    match result {
+                                        Result::Ok(file) => {
+                                            current_shader = Some(file);
+                                            shader_start_time = std::time::Instant::now();
+                                        }
+                                        Result::Err(e) => warn!("Failed to switch shader: {:#}", e),
+                                    }
+                                }
+                                // The keyboard composes dead keys and Compose sequences, giving the
+                                // composed text with the last key. Keys it takes into a sequence, or
+                                // that cancel one, come without text, so e.g. Escape abandoning an
+                                // accent doesn't also clear the password.
                                 KeyEvent {
                                     keysym: Keysym::Escape,
+                                    utf8: Some(_),
                                     ..
                                 } => {
-                                    auth.clear();
+                                    // Also backs out of a multi-step PAM conversation.
+                                    auth.cancel();
+                                    if std::mem::take(&mut message_replaced) {
+                                        if let Err(e) = show_message(
+                                            &mut graphics_manager,
+                                            graphics_by_surface.values_mut(),
+                                            message.as_deref(),
+                                        ) {
+                                            warn!("Failed to restore message: {:#}", e);
+                                        }
+                                    }
                                 }
                                 KeyEvent {
                                     keysym: Keysym::BackSpace | Keysym::Delete | Keysym::KP_Delete,
+                                    utf8: Some(_),
                                     ..
                                 } => {
                                     auth.pop();
@@ -224,14 +2460,19 @@ async fn main() -> Result<()> {
                                 } => {
                                     // Note that a Return keypress also has utf8 data "\r", so match this before
                                     // the text arm.
-                                    match auth.authenticate() {
-                                        Result::Ok(_) => {
-                                            session_lock.unlock();
-                                            conn.display().sync(qh, ExitSync);
-                                            conn.flush()?;
-                                        }
-                                        Result::Err(e) => warn!("Authentication failed: {}", e),
-                                    };
+                                    if auth_pending {
+                                        debug!("still checking the last answer");
+                                    } else {
+                                        // PAM can take seconds, so surfaces keep drawing while it
+                                        // checks, and the result arrives as an event.
+                                        auth_pending = true;
+                                        let attempt = auth.authenticate();
+                                        let events_tx = events_tx.clone();
+                                        tokio::task::spawn_local(async move {
+                                            let result = std::sync::Arc::new(attempt.await);
+                                            let _ = events_tx.unbounded_send(Event::AuthFinished(result));
+                                        });
+                                    }
                                 }
                                 KeyEvent {
                                     utf8: Some(text), ..
@@ -241,12 +2482,34 @@ async fn main() -> Result<()> {
                                         auth.push(c);
                                     }
                                 }
-                                KeyEvent { keysym, .. } => {
-                                    debug!("unknown key pressed: {:?}", keysym);
+                                KeyEvent {
+                                    keysym, utf8: None, ..
+                                } => {
+                                    debug!("key taken by compose sequence: {:?}", keysym);
                                 }
                             };
+                            if matches!(lock_state, LockState::Locked | LockState::Authenticating) {
+                                let new = if auth.is_empty() && !auth_pending {
+                                    LockState::Locked
+                                } else {
+                                    LockState::Authenticating
+                                };
+                                set_lock_state(&mut lock_state, new);
+                            }
                         }
                         Event::ExitSync => {
+                            if let Some(path) = &surfaces_file {
+                                let _ = std::fs::remove_file(path);
+                            }
+                            if let Some(path) = &control_socket {
+                                let _ = std::fs::remove_file(path);
+                            }
+                            if let Some(delay) = exit_delay {
+                                // The compositor has processed the unlock, but may still be
+                                // tearing down the lock surfaces.
+                                debug!("waiting {:?} before exiting", delay);
+                                tokio::time::sleep(delay).await;
+                            }
                             info!("exiting");
                             return Ok(());
                         }
@@ -257,3 +2520,39 @@ async fn main() -> Result<()> {
         })
         .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_seconds() {
+        assert_eq!(
+            parse_seconds("1.5").unwrap(),
+            std::time::Duration::from_millis(1500)
+        );
+        assert_eq!(parse_seconds("0").unwrap(), std::time::Duration::ZERO);
+    }
+
+    #[test]
+    fn rejects_out_of_range_seconds() {
+        for s in ["-1", "1e30", "inf", "NaN", "", "soon"] {
+            assert!(parse_seconds(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn test_lock_requires_skip_auth() {
+        assert!(Args::try_parse_from(["shaderlock", "--test-lock", "5"]).is_err());
+        assert!(Args::try_parse_from(["shaderlock", "--test-lock", "5", "--skip-auth"]).is_ok());
+    }
+
+    #[test]
+    fn preview_checks_time() {
+        let preview = |time| Args::try_parse_from(["shaderlock", "preview", "a", "--time", time]);
+        assert!(preview("2.5").is_ok());
+        for time in ["-1", "NaN", "1e30"] {
+            assert!(preview(time).is_err(), "{:?}", time);
+        }
+    }
+}
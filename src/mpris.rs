@@ -0,0 +1,116 @@
+use anyhow::*;
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use tokio::process::Command;
+
+const PLAYERCTL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+const FIELD_SEPARATOR: char = '\t';
+
+/// Track currently playing in an MPRIS media player.
+#[derive(Clone)]
+pub struct NowPlaying {
+    pub title: String,
+    pub artist: String,
+    pub art: Option<image::RgbaImage>,
+}
+
+impl NowPlaying {
+    /// Title and artist as shown on the lock screen, e.g. "Title — Artist".
+    pub fn label(&self) -> String {
+        match (self.title.is_empty(), self.artist.is_empty()) {
+            (false, false) => format!("{} \u{2014} {}", self.title, self.artist),
+            (false, true) => self.title.clone(),
+            (true, _) => self.artist.clone(),
+        }
+    }
+}
+
+// By hand, so logging events doesn't print every pixel of the art.
+impl std::fmt::Debug for NowPlaying {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NowPlaying")
+            .field("title", &self.title)
+            .field("artist", &self.artist)
+            .field("art", &self.art.as_ref().map(|art| art.dimensions()))
+            .finish()
+    }
+}
+
+/// Query the active media player over MPRIS, via `playerctl` which does the D-Bus legwork.
+///
+/// Returns `None` if nothing is playing, including when the player is paused or stopped.
+pub async fn now_playing() -> Result<Option<NowPlaying>> {
+    let format = [
+        "{{status}}",
+        "{{xesam:title}}",
+        "{{xesam:artist}}",
+        "{{mpris:artUrl}}",
+    ]
+    .join(&FIELD_SEPARATOR.to_string());
+    let output = tokio::time::timeout(
+        PLAYERCTL_TIMEOUT,
+        Command::new("playerctl")
+            .args(["metadata", "--format", &format])
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .context("Timed out querying media player")?
+    .context("Failed to run playerctl")?;
+    if !output.status.success() {
+        debug!(
+            "no media player: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Ok(None);
+    }
+
+    let stdout = String::from_utf8(output.stdout).context("Media metadata is not UTF-8")?;
+    let mut fields = stdout.trim_end_matches('\n').split(FIELD_SEPARATOR);
+    // A paused player still has metadata, but its track isn't what's playing.
+    let status = fields.next().unwrap_or_default();
+    if status != "Playing" {
+        debug!("media player is {:?}", status);
+        return Ok(None);
+    }
+    let title = fields.next().unwrap_or_default().to_owned();
+    let artist = fields.next().unwrap_or_default().to_owned();
+    let art = match fields.next().and_then(|url| url.strip_prefix("file://")) {
+        Some(path) => match image::open(percent_decode(path)) {
+            Result::Ok(art) => Some(art.into_rgba8()),
+            Result::Err(e) => {
+                warn!("Failed to read album art: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    Ok(Some(NowPlaying { title, artist, art }))
+}
+
+/// Decode `%XX` escapes in a file URL path.
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let escaped = match tail {
+            [hi, lo, ..] if b == b'%' => std::str::from_utf8(&[*hi, *lo])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
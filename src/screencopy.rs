@@ -1,6 +1,5 @@
 use std::{error::Error, sync::Mutex};
 
-use anyhow::Result;
 #[allow(unused_imports)]
 use log::{debug, error, info, warn};
 
@@ -23,6 +22,27 @@ use sctk::{
     registry::GlobalProxy,
 };
 
+/// Failures capturing an output.
+#[derive(Debug, thiserror::Error)]
+pub enum ScreencopyError {
+    #[error("Compositor does not support screencopy")]
+    Unsupported(#[from] sctk::error::GlobalError),
+    #[error("Compositor failed to copy the output")]
+    Failed,
+    #[error("Failed to create a buffer for the screenshot")]
+    CreateBuffer(#[source] Box<dyn Error + Send + Sync>),
+    #[error("Unsupported screenshot format {0:?}")]
+    UnsupportedFormat(Format),
+    #[error("Screenshot data is {len} bytes, too short for {height} rows of {stride}")]
+    ShortData {
+        len: usize,
+        stride: u32,
+        height: u32,
+    },
+}
+
+type Result<T, E = ScreencopyError> = std::result::Result<T, E>;
+
 pub trait HasWlBuffer {
     fn wl_buffer(&self) -> &WlBuffer;
 }
@@ -35,7 +55,7 @@ impl HasWlBuffer for sctk::shm::slot::Buffer {
 
 pub trait ScreencopyHandler: Sized {
     type ShmBuffer: HasWlBuffer + Send + Sync + std::fmt::Debug;
-    type CreateBufferError: Error;
+    type CreateBufferError: Error + Send + Sync + 'static;
 
     fn screencopy_state(&mut self) -> &mut ScreencopyState;
 
@@ -192,7 +212,22 @@ where
                 let info_guard = data.info.lock().unwrap();
                 let info = info_guard.as_ref().unwrap();
                 debug!("Creating buffer with info {:?}", info);
-                let buffer = state.create_buffer(info).unwrap();
+                let buffer = match state.create_buffer(info) {
+                    Ok(buffer) => buffer,
+                    Err(e) => {
+                        // E.g. out of SHM space. Give up on this capture rather than the locker.
+                        error!("Failed to create screencopy buffer: {}", e);
+                        proxy.destroy();
+                        data.on_done
+                            .lock()
+                            .unwrap()
+                            .take()
+                            .unwrap()
+                            .send(Err(ScreencopyError::CreateBuffer(Box::new(e))))
+                            .unwrap();
+                        return;
+                    }
+                };
                 proxy.copy(buffer.wl_buffer());
                 conn.flush().unwrap();
                 data.buffer.lock().unwrap().replace(buffer);
@@ -224,7 +259,7 @@ where
                     .unwrap()
                     .take()
                     .unwrap()
-                    .send(Err(todo!()))
+                    .send(Err(ScreencopyError::Failed))
                     .unwrap();
             }
             zwlr_screencopy_frame_v1::Event::Damage { .. } => unimplemented!(),
@@ -233,7 +268,7 @@ where
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct BufferInfo {
     pub width: u32,
     pub height: u32,
@@ -249,6 +284,7 @@ pub struct ScreencopyBufferHandle<ShmBuffer: HasWlBuffer> {
     pub y_invert: bool,
 }
 
+#[derive(Clone)]
 pub struct ScreencopyBuffer {
     info: BufferInfo,
     transform: Transform,
@@ -276,6 +312,35 @@ impl ScreencopyBuffer {
         }
     }
 
+    /// Create a buffer from raw pixel data with no Wayland buffer behind it, e.g. to drive the
+    /// graphics pipeline with synthetic images.
+    pub fn from_raw(
+        width: u32,
+        height: u32,
+        stride: u32,
+        format: Format,
+        data: Vec<u8>,
+    ) -> Result<Self> {
+        if data.len() < stride as usize * height as usize {
+            return Err(ScreencopyError::ShortData {
+                len: data.len(),
+                stride,
+                height,
+            });
+        }
+        Ok(Self {
+            info: BufferInfo {
+                width,
+                height,
+                stride,
+                format,
+            },
+            transform: Transform::Normal,
+            y_invert: false,
+            data,
+        })
+    }
+
     pub fn bytes(&self) -> &[u8] {
         &self.data
     }
@@ -316,4 +381,175 @@ impl ScreencopyBuffer {
         Matrix4::from_angle_z(angle)
             * Matrix4::from_nonuniform_scale(if flip { -1.0 } else { 1.0 }, 1.0, 1.0)
     }
+
+    /// Convert to RGBA and apply the transform, giving the output as the user sees it, e.g. to
+    /// check a capture before any shader touches it.
+    pub fn to_image(&self) -> Result<image::RgbaImage> {
+        use cgmath::{Matrix4, Vector3, Vector4};
+        // Byte offsets of red, green, blue and alpha in a little-endian pixel.
+        let (channels, opaque) = match self.info.format {
+            Format::Argb8888 => ([2, 1, 0, 3], false),
+            Format::Xrgb8888 => ([2, 1, 0, 3], true),
+            Format::Abgr8888 => ([0, 1, 2, 3], false),
+            Format::Xbgr8888 => ([0, 1, 2, 3], true),
+            format => return Err(ScreencopyError::UnsupportedFormat(format)),
+        };
+        let (width, height) = (self.info.width, self.info.height);
+        let (out_width, out_height) = match self.transform {
+            Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270 => {
+                (height, width)
+            }
+            _ => (width, height),
+        };
+        // The same mapping as the background pipeline's, from output to buffer coordinates.
+        let m = Matrix4::from_translation(Vector3::new(0.5, 0.5, 0.0))
+            * self.transform_matrix()
+            * Matrix4::from_translation(Vector3::new(-0.5, -0.5, 0.0));
+        Ok(image::RgbaImage::from_fn(out_width, out_height, |x, y| {
+            let uv = m * Vector4::new(
+                (x as f32 + 0.5) / out_width as f32,
+                (y as f32 + 0.5) / out_height as f32,
+                0.0,
+                1.0,
+            );
+            let u = ((uv.x * width as f32) as u32).min(width - 1);
+            let v = ((uv.y * height as f32) as u32).min(height - 1);
+            let offset = v as usize * self.info.stride as usize + u as usize * 4;
+            let pixel = &self.data[offset..offset + 4];
+            let alpha = if opaque { 0xff } else { pixel[channels[3]] };
+            image::Rgba([
+                pixel[channels[0]],
+                pixel[channels[1]],
+                pixel[channels[2]],
+                alpha,
+            ])
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Matrix4, Vector3, Vector4};
+
+    const CORNERS: [(f32, f32); 4] = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)];
+
+    fn buffer(transform: Transform, y_invert: bool) -> ScreencopyBuffer {
+        ScreencopyBuffer {
+            transform,
+            y_invert,
+            ..ScreencopyBuffer::from_raw(1, 1, 4, Format::Argb8888, vec![0; 4]).unwrap()
+        }
+    }
+
+    /// Apply the matrix around the texture centre, the same way the background pipeline does.
+    fn map_uv(buffer: &ScreencopyBuffer, (x, y): (f32, f32)) -> (f32, f32) {
+        let m = Matrix4::from_translation(Vector3::new(0.5, 0.5, 0.0))
+            * buffer.transform_matrix()
+            * Matrix4::from_translation(Vector3::new(-0.5, -0.5, 0.0));
+        let v = m * Vector4::new(x, y, 0.0, 1.0);
+        (v.x / v.w, v.y / v.w)
+    }
+
+    /// Where a UV should end up: flip horizontally first, then rotate counter-clockwise.
+    fn expected_uv(transform: Transform, flip: bool, (x, y): (f32, f32)) -> (f32, f32) {
+        let x = if flip { 1.0 - x } else { x };
+        match transform {
+            Transform::Normal | Transform::Flipped => (x, y),
+            Transform::_90 | Transform::Flipped90 => (1.0 - y, x),
+            Transform::_180 | Transform::Flipped180 => (1.0 - x, 1.0 - y),
+            Transform::_270 | Transform::Flipped270 => (y, 1.0 - x),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn transform_matrix_maps_corners() {
+        let transforms = [
+            (Transform::Normal, false),
+            (Transform::_90, false),
+            (Transform::_180, false),
+            (Transform::_270, false),
+            (Transform::Flipped, true),
+            (Transform::Flipped90, true),
+            (Transform::Flipped180, true),
+            (Transform::Flipped270, true),
+        ];
+        for (transform, flipped) in transforms {
+            for y_invert in [false, true] {
+                let buffer = buffer(transform, y_invert);
+                for corner in CORNERS {
+                    let (x, y) = map_uv(&buffer, corner);
+                    let (ex, ey) = expected_uv(transform, flipped ^ y_invert, corner);
+                    assert!(
+                        (x - ex).abs() < 1e-5 && (y - ey).abs() < 1e-5,
+                        "{:?} y_invert={} mapped {:?} to {:?}, expected {:?}",
+                        transform,
+                        y_invert,
+                        corner,
+                        (x, y),
+                        (ex, ey)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn from_raw_is_untransformed() {
+        let buffer = ScreencopyBuffer::from_raw(2, 3, 12, Format::Xbgr8888, vec![7; 36]).unwrap();
+        assert_eq!(
+            (buffer.width(), buffer.height(), buffer.stride()),
+            (2, 3, 12)
+        );
+        assert_eq!(buffer.format(), Format::Xbgr8888);
+        assert_eq!(buffer.bytes().len(), 36);
+        assert_eq!(buffer.transform_matrix(), Matrix4::from_scale(1.0));
+    }
+
+    #[test]
+    fn to_image_converts_and_rotates() {
+        // Two pixels side by side, red then blue, with junk where X formats ignore alpha.
+        let data = vec![0, 0, 0xff, 0x00, 0xff, 0, 0, 0x80];
+        let buffer = ScreencopyBuffer {
+            transform: Transform::_90,
+            ..ScreencopyBuffer::from_raw(2, 1, 8, Format::Xrgb8888, data).unwrap()
+        };
+        let image = buffer.to_image().unwrap();
+        assert_eq!(image.dimensions(), (1, 2));
+        let pixels: Vec<_> = image.pixels().map(|p| p.0).collect();
+        let red = [0xff, 0, 0, 0xff];
+        let blue = [0, 0, 0xff, 0xff];
+        // Rotating the output a quarter turn counter-clockwise puts the right of the buffer on
+        // top.
+        assert_eq!(pixels, [blue, red]);
+    }
+
+    #[test]
+    fn from_raw_rejects_short_data() {
+        assert!(matches!(
+            ScreencopyBuffer::from_raw(2, 3, 12, Format::Xbgr8888, vec![0; 35]),
+            Err(ScreencopyError::ShortData {
+                len: 35,
+                stride: 12,
+                height: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn y_invert_cancels_flip() {
+        for (flipped, plain) in [
+            (Transform::Flipped, Transform::Normal),
+            (Transform::Flipped90, Transform::_90),
+            (Transform::Flipped180, Transform::_180),
+            (Transform::Flipped270, Transform::_270),
+        ] {
+            for corner in CORNERS {
+                let a = map_uv(&buffer(flipped, true), corner);
+                let b = map_uv(&buffer(plain, false), corner);
+                assert!((a.0 - b.0).abs() < 1e-5 && (a.1 - b.1).abs() < 1e-5);
+            }
+        }
+    }
 }
@@ -0,0 +1,59 @@
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::rc::Rc;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use tokio::process::Command;
+
+/// Plays a short sample on each key press, via `paplay` so no audio stack is linked in.
+///
+/// Playback runs in the background so it never stalls rendering. If the sample can't be played
+/// (no `paplay`, no audio server, unreadable file) this logs once and stays silent from then on.
+pub struct KeypressSound {
+    file: PathBuf,
+    failed: Rc<Cell<bool>>,
+}
+
+impl KeypressSound {
+    pub fn new(file: PathBuf) -> Self {
+        Self {
+            file,
+            failed: Rc::new(Cell::new(false)),
+        }
+    }
+
+    /// Start playing the sample. Must be called within a `LocalSet`.
+    pub fn play(&self) {
+        if self.failed.get() {
+            return;
+        }
+        let spawned = Command::new("paplay")
+            .arg(&self.file)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to play keypress sound, disabling it: {}", e);
+                self.failed.set(true);
+                return;
+            }
+        };
+        let failed = self.failed.clone();
+        tokio::task::spawn_local(async move {
+            match child.wait().await {
+                Ok(status) if status.success() => {}
+                result => {
+                    if !failed.replace(true) {
+                        warn!("Failed to play keypress sound, disabling it: {:?}", result);
+                    }
+                }
+            }
+        });
+    }
+}
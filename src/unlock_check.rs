@@ -0,0 +1,37 @@
+use std::process::Stdio;
+use std::time::Duration;
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+use tokio::process::Command;
+
+/// How long a single check may run before it is killed and counted as a failure.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Run a shell command every `interval` until it exits successfully, e.g. to unlock when a phone
+/// comes into range.
+///
+/// Checks run one at a time, `interval` apart, so a slow command delays the next rather than
+/// piling up.
+pub async fn wait_for_success(command: &str, interval: Duration) {
+    loop {
+        let status = tokio::time::timeout(
+            CHECK_TIMEOUT,
+            Command::new("sh")
+                .args(["-c", command])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .kill_on_drop(true)
+                .status(),
+        )
+        .await;
+        match status {
+            Ok(Ok(status)) if status.success() => return,
+            Ok(Ok(status)) => debug!("unlock command check failed: {}", status),
+            Ok(Err(e)) => warn!("Failed to run unlock command check: {}", e),
+            Err(_) => warn!("Unlock command check timed out after {:?}", CHECK_TIMEOUT),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
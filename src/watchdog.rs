@@ -0,0 +1,85 @@
+use std::os::unix::process::CommandExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[allow(unused_imports)]
+use log::{debug, error, info, warn};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const EXIT_CODE: i32 = 2;
+
+/// Restarts or exits the process if input arrives but no frame is presented for too long.
+///
+/// This runs on its own thread because a stalled render blocks the event loop, and a driver stuck
+/// on that thread can't be recovered from in the same process. Replacing the process with one
+/// that doesn't use the GPU gives back a lock screen that can be unlocked, where the compositor
+/// lets a new client take over the lock. Exiting instead leaves the session locked by the
+/// compositor, which is still better than a lock screen that cannot be unlocked. It also pings
+/// the systemd watchdog while rendering is healthy, if that is enabled.
+pub struct Watchdog {
+    start: Instant,
+    last_present_ms: AtomicU64,
+    last_input_ms: AtomicU64,
+}
+
+impl Watchdog {
+    /// Watch for stalls longer than `timeout`, then run `fallback` in place of this process if
+    /// given, or exit.
+    pub fn spawn(timeout: Duration, mut fallback: Option<std::process::Command>) -> Arc<Self> {
+        let watchdog = Arc::new(Self {
+            start: Instant::now(),
+            last_present_ms: AtomicU64::new(0),
+            last_input_ms: AtomicU64::new(0),
+        });
+        let systemd_watchdog = sd_notify::watchdog_enabled(false, &mut 0);
+
+        let thread_watchdog = watchdog.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(CHECK_INTERVAL);
+            if let Some(stalled) = thread_watchdog.stalled_for() {
+                if stalled > timeout {
+                    error!(
+                        "No frame presented {:?} after input, the graphics driver or shader \
+                         may be stuck.",
+                        stalled
+                    );
+                    if let Some(fallback) = &mut fallback {
+                        error!("Restarting as {:?}", fallback);
+                        // Only returns if it failed.
+                        let e = fallback.exec();
+                        error!("Failed to restart: {}", e);
+                    }
+                    std::process::exit(EXIT_CODE);
+                }
+            } else if systemd_watchdog {
+                let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]);
+            }
+        });
+        watchdog
+    }
+
+    /// Record that a frame was presented.
+    pub fn presented(&self) {
+        self.last_present_ms
+            .store(self.elapsed_ms(), Ordering::Relaxed);
+    }
+
+    /// Record input which should cause a frame to be presented.
+    pub fn input(&self) {
+        self.last_input_ms
+            .store(self.elapsed_ms(), Ordering::Relaxed);
+    }
+
+    /// How long input has gone without a frame being presented.
+    fn stalled_for(&self) -> Option<Duration> {
+        let last_input = self.last_input_ms.load(Ordering::Relaxed);
+        let last_present = self.last_present_ms.load(Ordering::Relaxed);
+        (last_input > last_present)
+            .then(|| Duration::from_millis(self.elapsed_ms().saturating_sub(last_input)))
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+}
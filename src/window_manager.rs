@@ -2,6 +2,8 @@ use std::cell::RefCell;
 use std::future::Future;
 use std::os::fd::AsFd;
 use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use anyhow::*;
 use futures::channel::mpsc;
@@ -17,6 +19,7 @@ use sctk::reexports::client::globals::registry_queue_init;
 use sctk::reexports::client::Proxy;
 use sctk::registry::*;
 use sctk::seat::keyboard::KeyboardHandler;
+use sctk::seat::pointer::{PointerEvent, PointerEventKind, PointerHandler};
 use sctk::seat::SeatHandler;
 use sctk::seat::SeatState;
 use sctk::session_lock::*;
@@ -25,6 +28,8 @@ use tokio::io::unix::AsyncFd;
 use tokio::time::timeout;
 use wgpu::rwh;
 
+use crate::color_management::ColorManagementHandler;
+use crate::color_management::ColorManagementState;
 use crate::screencopy::ScreencopyBuffer;
 use crate::screencopy::ScreencopyHandler;
 use crate::screencopy::ScreencopyState;
@@ -37,6 +42,7 @@ pub struct WindowManagerState {
     pub compositor_state: CompositorState,
 
     pub screencopy_state: ScreencopyState,
+    pub color_management_state: ColorManagementState,
 
     pub registry_state: RegistryState,
 
@@ -49,6 +55,13 @@ pub struct WindowManagerState {
     pub seat_state: SeatState,
 
     pub events: mpsc::UnboundedSender<Event>,
+
+    /// Names of the keyboard's layouts, in group order.
+    layout_names: Vec<String>,
+    /// Active keyboard layout group.
+    layout: u32,
+    /// Name of the active layout last sent to the event loop.
+    layout_name: Option<String>,
 }
 
 impl WindowManagerState {
@@ -57,6 +70,83 @@ impl WindowManagerState {
             .unbounded_send(Event::RedrawRequested(surface))
             .expect("send event");
     }
+
+    /// Tell the event loop about the active keyboard layout, if its name is known and changed.
+    fn send_layout(&mut self) {
+        let name = match self.layout_names.get(self.layout as usize) {
+            Some(name) if !name.is_empty() => name,
+            _ => return,
+        };
+        if self.layout_name.as_ref() != Some(name) {
+            self.layout_name = Some(name.clone());
+            self.events
+                .unbounded_send(Event::LayoutChanged(name.clone()))
+                .expect("send event");
+        }
+    }
+
+    /// Fill a surface with a single ARGB colour using shared memory, without touching the GPU.
+    ///
+    /// The buffer is one pixel per logical pixel, so this resets the surface's buffer scale.
+    ///
+    /// The returned buffer must be kept alive for as long as the surface shows it.
+    pub fn draw_solid(
+        &mut self,
+        surface: &wl::protocol::wl_surface::WlSurface,
+        (width, height): (u32, u32),
+        argb: u32,
+    ) -> Result<slot::Buffer> {
+        let (buffer, canvas) = self.buffer_pool.create_buffer(
+            width as i32,
+            height as i32,
+            width as i32 * 4,
+            wl::protocol::wl_shm::Format::Argb8888,
+        )?;
+        for pixel in canvas.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&argb.to_le_bytes());
+        }
+        buffer.attach_to(surface)?;
+        surface.set_buffer_scale(1);
+        surface.damage_buffer(0, 0, width as i32, height as i32);
+        surface.commit();
+        Ok(buffer)
+    }
+
+    /// Show an image on a surface using shared memory, without touching the GPU.
+    ///
+    /// The image is `scale` pixels per logical pixel, so must be a multiple of it in size.
+    ///
+    /// The returned buffer must be kept alive for as long as the surface shows it.
+    pub fn draw_image(
+        &mut self,
+        surface: &wl::protocol::wl_surface::WlSurface,
+        image: &image::RgbaImage,
+        scale: i32,
+    ) -> Result<slot::Buffer> {
+        let (width, height) = image.dimensions();
+        let (buffer, canvas) = self.buffer_pool.create_buffer(
+            width as i32,
+            height as i32,
+            width as i32 * 4,
+            wl::protocol::wl_shm::Format::Argb8888,
+        )?;
+        // Lock surfaces must hide everything behind them, so alpha is ignored.
+        for (pixel, rgba) in canvas.chunks_exact_mut(4).zip(image.pixels()) {
+            let [r, g, b, _] = rgba.0;
+            pixel.copy_from_slice(&[b, g, r, 0xff]);
+        }
+        buffer.attach_to(surface)?;
+        surface.set_buffer_scale(scale);
+        surface.damage_buffer(0, 0, width as i32, height as i32);
+        surface.commit();
+        Ok(buffer)
+    }
+
+    pub fn queue_all_surfaces_presented(&mut self) {
+        self.events
+            .unbounded_send(Event::AllSurfacesPresented)
+            .expect("send event");
+    }
 }
 
 pub struct WindowManager {
@@ -66,18 +156,53 @@ pub struct WindowManager {
 
     pub state_cell: RefCell<WindowManagerState>,
     pub events: mpsc::UnboundedReceiver<Event>,
+
+    shutdown: Arc<AtomicBool>,
+}
+
+/// Asks a running `WindowManager` to stop, from any thread.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle(Arc<AtomicBool>);
+
+impl ShutdownHandle {
+    /// Make `WindowManager::run` return `Ok` within `RECEIVE_TIMEOUT`, dropping the handler.
+    ///
+    /// This doesn't unlock the session: a lock that is dropped without unlocking leaves the
+    /// session locked, as the protocol requires.
+    pub fn shutdown(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
 }
 
 impl WindowManager {
     pub fn new() -> Result<Self> {
-        let conn = wl::Connection::connect_to_env()?;
+        Self::from_connection(wl::Connection::connect_to_env()?)
+    }
+
+    /// Create a window manager on an existing Wayland connection, rather than the one named by
+    /// the environment.
+    pub fn from_connection(conn: wl::Connection) -> Result<Self> {
         let (globals, event_queue) = registry_queue_init::<WindowManagerState>(&conn)?;
         let qh = event_queue.handle();
 
+        // Without session lock, lock surfaces would be ordinary windows that leave input going to
+        // other apps, looking locked while protecting nothing.
+        let session_lock_supported = globals.contents().with_list(|globals| {
+            globals
+                .iter()
+                .any(|global| global.interface == "ext_session_lock_manager_v1")
+        });
+        ensure!(
+            session_lock_supported,
+            "Compositor does not support ext-session-lock-v1, refusing to start as the session \
+             could not be locked"
+        );
+
         let output_state = OutputState::new(&globals, &qh);
         let compositor_state = CompositorState::bind(&globals, &qh)?;
         let session_lock_state = SessionLockState::new(&globals, &qh);
         let screencopy_state = ScreencopyState::new(&globals, &qh);
+        let color_management_state = ColorManagementState::new(&globals, &qh);
         let shm = Shm::bind(&globals, &qh)?;
         let registry_state = RegistryState::new(&globals);
         let seat_state = SeatState::new(&globals, &qh);
@@ -93,6 +218,8 @@ impl WindowManager {
 
             screencopy_state,
 
+            color_management_state,
+
             registry_state,
 
             shm,
@@ -103,6 +230,10 @@ impl WindowManager {
             seat_state,
 
             events: tx,
+
+            layout_names: vec![],
+            layout: 0,
+            layout_name: None,
         };
 
         let state_cell = RefCell::new(state);
@@ -113,9 +244,15 @@ impl WindowManager {
             event_queue,
             state_cell,
             events: rx,
+            shutdown: Arc::new(AtomicBool::new(false)),
         })
     }
 
+    /// A handle for embedding code to stop `run` without the handler's involvement.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown.clone())
+    }
+
     /// Run the event loop with a provided handler task.
     ///
     /// The funky lifetime annotations declare the references passed to the handler function
@@ -135,10 +272,16 @@ impl WindowManager {
     {
         let event_queue = &mut self.event_queue;
         let state_cell = &self.state_cell;
+        let shutdown = &self.shutdown;
         let fd = AsyncFd::new(self.conn.as_fd()).unwrap();
         let receiver = async move {
             let mut state = WindowManagerStateAccessor::new(state_cell);
             loop {
+                if shutdown.load(Ordering::SeqCst) {
+                    info!("Shutdown requested, stopping event loop");
+                    return Ok(());
+                }
+
                 debug!("flushing event queue");
                 event_queue.flush().unwrap();
 
@@ -217,18 +360,75 @@ pub enum Event {
     NewOutput(wl::protocol::wl_output::WlOutput),
     /// Redraw requested for a surface.
     RedrawRequested(wl::protocol::wl_surface::WlSurface),
+    /// The compositor wants a surface rendered at a different integer scale.
+    ScaleFactorChanged(wl::protocol::wl_surface::WlSurface, i32),
+    /// The outputs a surface is shown on are rotated or flipped differently.
+    TransformChanged(
+        wl::protocol::wl_surface::WlSurface,
+        wl::protocol::wl_output::Transform,
+    ),
+    /// A surface is now shown on an output, e.g. when it is first mapped.
+    SurfaceEntered(
+        wl::protocol::wl_surface::WlSurface,
+        wl::protocol::wl_output::WlOutput,
+    ),
+    /// A surface is no longer shown on an output.
+    SurfaceLeft(
+        wl::protocol::wl_surface::WlSurface,
+        wl::protocol::wl_output::WlOutput,
+    ),
+    /// Every lock surface has presented at least one frame.
+    AllSurfacesPresented,
+    /// The compositor prefers a surface's pixels in a different encoding, e.g. for HDR.
+    TransferFunctionChanged(
+        wl::protocol::wl_surface::WlSurface,
+        crate::color_management::TransferFunction,
+    ),
 
     /// Seat input method added.
     NewSeatCapability(wl::protocol::wl_seat::WlSeat, sctk::seat::Capability),
     /// Seat input method removed.
     RemoveSeatCapability(wl::protocol::wl_seat::WlSeat, sctk::seat::Capability),
+    /// Seat removed, along with any input methods it had.
+    RemoveSeat(wl::protocol::wl_seat::WlSeat),
+    /// Keyboard focus entered a surface.
+    KeyboardEnter(wl::protocol::wl_surface::WlSurface),
+    /// Pointer entered a surface, so its cursor can be set with the serial.
+    PointerEnter(wl::protocol::wl_pointer::WlPointer, u32),
+    /// Pointer entered or moved over a surface, to a position in its logical coordinates.
+    PointerMoved(wl::protocol::wl_surface::WlSurface, (f32, f32)),
+    /// Keyboard focus left a surface.
+    KeyboardLeave(wl::protocol::wl_surface::WlSurface),
+    /// Active keyboard modifiers changed.
+    ModifiersChanged(sctk::seat::keyboard::Modifiers),
+    /// Active keyboard layout changed, e.g. from US to German, by name.
+    LayoutChanged(String),
     /// Key pressed.
     KeyPressed(sctk::seat::keyboard::KeyEvent),
 
+    /// Unlock requested from outside the compositor, e.g. by a signal.
+    UnlockRequested,
+    /// An external idle manager reported the user idle (`true`) or active again (`false`).
+    ExternalIdle(bool),
+    /// The battery charge was read again, or there is no longer a battery.
+    BatteryChanged(Option<crate::battery::Battery>),
+    /// The shader file watched with --watch-shader was modified.
+    ShaderChanged(std::path::PathBuf),
+    /// The media player was queried with --mpris, and this track is playing.
+    NowPlaying(crate::mpris::NowPlaying),
+    /// A command arrived on the control socket.
+    Control(crate::control::Command),
+    /// PAM answered what was typed. Shared, as events are cloned and PAM errors can't be.
+    AuthFinished(
+        std::sync::Arc<Result<crate::authenticator::AuthStep, crate::authenticator::AuthError>>,
+    ),
+
     /// Session locked successfully.
     SessionLocked,
     /// Session lock failed.
     SessionLockFinished,
+    /// The compositor took too long to confirm the session is locked.
+    LockTimeout,
     /// Lock surface ready to be configured.
     ConfigureLockSurface(SessionLockSurface, (u32, u32)),
 
@@ -307,18 +507,29 @@ impl CompositorHandler for WindowManagerState {
         &mut self,
         _conn: &wl::Connection,
         _qh: &wl::QueueHandle<Self>,
-        _surface: &wl::protocol::wl_surface::WlSurface,
-        _new_factor: i32,
+        surface: &wl::protocol::wl_surface::WlSurface,
+        new_factor: i32,
     ) {
+        debug!("surface {:?} scale factor changed: {}", surface, new_factor);
+        self.events
+            .unbounded_send(Event::ScaleFactorChanged(surface.clone(), new_factor))
+            .expect("send event");
     }
 
     fn transform_changed(
         &mut self,
         _conn: &wl::Connection,
         _qh: &wl::QueueHandle<Self>,
-        _surface: &wl::protocol::wl_surface::WlSurface,
-        _new_transform: wl::protocol::wl_output::Transform,
+        surface: &wl::protocol::wl_surface::WlSurface,
+        new_transform: wl::protocol::wl_output::Transform,
     ) {
+        debug!(
+            "surface {:?} transform changed: {:?}",
+            surface, new_transform
+        );
+        self.events
+            .unbounded_send(Event::TransformChanged(surface.clone(), new_transform))
+            .expect("send event");
     }
 
     fn frame(
@@ -338,18 +549,26 @@ impl CompositorHandler for WindowManagerState {
         &mut self,
         _conn: &wl::Connection,
         _qh: &wl::QueueHandle<Self>,
-        _surface: &wl::protocol::wl_surface::WlSurface,
-        _output: &wl::protocol::wl_output::WlOutput,
+        surface: &wl::protocol::wl_surface::WlSurface,
+        output: &wl::protocol::wl_output::WlOutput,
     ) {
+        debug!("surface {:?} entered output {:?}", surface, output);
+        self.events
+            .unbounded_send(Event::SurfaceEntered(surface.clone(), output.clone()))
+            .expect("send event");
     }
 
     fn surface_leave(
         &mut self,
         _conn: &wl::Connection,
         _qh: &wl::QueueHandle<Self>,
-        _surface: &wl::protocol::wl_surface::WlSurface,
-        _output: &wl::protocol::wl_output::WlOutput,
+        surface: &wl::protocol::wl_surface::WlSurface,
+        output: &wl::protocol::wl_output::WlOutput,
     ) {
+        debug!("surface {:?} left output {:?}", surface, output);
+        self.events
+            .unbounded_send(Event::SurfaceLeft(surface.clone(), output.clone()))
+            .expect("send event");
     }
 }
 
@@ -414,9 +633,36 @@ impl ProvidesRegistryState for WindowManagerState {
 
 crate::delegate_screencopy!(WindowManagerState);
 
+crate::delegate_color_management!(WindowManagerState);
+
+impl ColorManagementHandler for WindowManagerState {
+    fn color_management_state(&mut self) -> &mut ColorManagementState {
+        &mut self.color_management_state
+    }
+
+    fn preferred_transfer_function(
+        &mut self,
+        surface: &wl::protocol::wl_surface::WlSurface,
+        tf: crate::color_management::TransferFunction,
+    ) {
+        self.events
+            .unbounded_send(Event::TransferFunctionChanged(surface.clone(), tf))
+            .expect("send event");
+    }
+}
+
+/// Failures making a buffer for a screenshot to be copied into.
+#[derive(Debug, thiserror::Error)]
+pub enum CreateBufferError {
+    #[error("Failed to allocate buffer")]
+    Create(#[from] slot::CreateBufferError),
+    #[error("Failed to activate buffer")]
+    Activate(#[from] slot::ActivateSlotError),
+}
+
 impl ScreencopyHandler for WindowManagerState {
     type ShmBuffer = slot::Buffer;
-    type CreateBufferError = slot::CreateBufferError;
+    type CreateBufferError = CreateBufferError;
 
     fn screencopy_state(&mut self) -> &mut ScreencopyState {
         &mut self.screencopy_state
@@ -433,7 +679,7 @@ impl ScreencopyHandler for WindowManagerState {
             info.stride as i32,
             info.format,
         )?;
-        buffer.activate().unwrap();
+        buffer.activate()?;
         std::result::Result::Ok(buffer)
     }
 
@@ -449,6 +695,38 @@ impl ScreencopyHandler for WindowManagerState {
     }
 }
 
+sctk::delegate_pointer!(WindowManagerState);
+
+impl PointerHandler for WindowManagerState {
+    fn pointer_frame(
+        &mut self,
+        _conn: &wl::Connection,
+        _qh: &wl::QueueHandle<Self>,
+        pointer: &wl::protocol::wl_pointer::WlPointer,
+        events: &[PointerEvent],
+    ) {
+        for event in events {
+            match event.kind {
+                PointerEventKind::Enter { serial } => {
+                    debug!("pointer enter surface: {:?}", event.surface);
+                    self.events
+                        .unbounded_send(Event::PointerEnter(pointer.clone(), serial))
+                        .expect("send event");
+                }
+                PointerEventKind::Motion { .. } => {}
+                _ => continue,
+            }
+            let (x, y) = event.position;
+            self.events
+                .unbounded_send(Event::PointerMoved(
+                    event.surface.clone(),
+                    (x as f32, y as f32),
+                ))
+                .expect("send event");
+        }
+    }
+}
+
 sctk::delegate_keyboard!(WindowManagerState);
 
 impl KeyboardHandler for WindowManagerState {
@@ -457,11 +735,15 @@ impl KeyboardHandler for WindowManagerState {
         _conn: &wl::Connection,
         _qh: &wl::QueueHandle<Self>,
         _keyboard: &wl::protocol::wl_keyboard::WlKeyboard,
-        _surface: &wl::protocol::wl_surface::WlSurface,
+        surface: &wl::protocol::wl_surface::WlSurface,
         _serial: u32,
         _raw: &[u32],
         _keysyms: &[sctk::seat::keyboard::Keysym],
     ) {
+        debug!("keyboard enter surface: {:?}", surface);
+        self.events
+            .unbounded_send(Event::KeyboardEnter(surface.clone()))
+            .expect("send event");
     }
 
     fn leave(
@@ -469,9 +751,13 @@ impl KeyboardHandler for WindowManagerState {
         _conn: &wl::Connection,
         _qh: &wl::QueueHandle<Self>,
         _keyboard: &wl::protocol::wl_keyboard::WlKeyboard,
-        _surface: &wl::protocol::wl_surface::WlSurface,
+        surface: &wl::protocol::wl_surface::WlSurface,
         _serial: u32,
     ) {
+        debug!("keyboard leave surface: {:?}", surface);
+        self.events
+            .unbounded_send(Event::KeyboardLeave(surface.clone()))
+            .expect("send event");
     }
 
     fn press_key(
@@ -504,9 +790,43 @@ impl KeyboardHandler for WindowManagerState {
         _qh: &wl::QueueHandle<Self>,
         _keyboard: &wl::protocol::wl_keyboard::WlKeyboard,
         _serial: u32,
-        _modifiers: sctk::seat::keyboard::Modifiers,
-        _layout: u32,
+        modifiers: sctk::seat::keyboard::Modifiers,
+        layout: u32,
     ) {
+        self.events
+            .unbounded_send(Event::ModifiersChanged(modifiers))
+            .expect("send event");
+        self.layout = layout;
+        self.send_layout();
+    }
+
+    fn update_keymap(
+        &mut self,
+        _conn: &wl::Connection,
+        _qh: &wl::QueueHandle<Self>,
+        _keyboard: &wl::protocol::wl_keyboard::WlKeyboard,
+        keymap: sctk::seat::keyboard::Keymap<'_>,
+    ) {
+        use xkbcommon::xkb;
+        // sctk only hands the keymap out as text, so compile it again to read the layout names.
+        let context = xkb::Context::new(xkb::CONTEXT_NO_FLAGS);
+        let names = match xkb::Keymap::new_from_string(
+            &context,
+            keymap.as_string(),
+            xkb::KEYMAP_FORMAT_TEXT_V1,
+            xkb::KEYMAP_COMPILE_NO_FLAGS,
+        ) {
+            Some(keymap) => (0..keymap.num_layouts())
+                .map(|layout| keymap.layout_get_name(layout).to_owned())
+                .collect(),
+            None => {
+                warn!("Failed to read layout names from keymap");
+                vec![]
+            }
+        };
+        debug!("keymap layouts: {:?}", names);
+        self.layout_names = names;
+        self.send_layout();
     }
 }
 
@@ -555,8 +875,12 @@ impl SeatHandler for WindowManagerState {
         &mut self,
         _conn: &wl::Connection,
         _qh: &wl::QueueHandle<Self>,
-        _seat: wl::protocol::wl_seat::WlSeat,
+        seat: wl::protocol::wl_seat::WlSeat,
     ) {
+        debug!("remove seat: {:?}", seat);
+        self.events
+            .unbounded_send(Event::RemoveSeat(seat))
+            .expect("send event");
     }
 }
 
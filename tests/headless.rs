@@ -0,0 +1,321 @@
+//! Integration tests driving `WindowManager` against a headless wlroots compositor.
+//!
+//! These need `sway` on the `PATH`, since it implements session-lock, screencopy and virtual
+//! keyboards when running headless. They are skipped when it is not installed.
+
+use std::os::fd::AsFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use anyhow::*;
+use futures::StreamExt;
+use sctk::reexports::client as wl;
+use sctk::reexports::client::protocol::wl_shm::Format;
+use sctk::reexports::client::Proxy;
+use shaderlock::screencopy::ScreencopyHandler;
+use shaderlock::window_manager::{Event, ExitSync, WindowManager};
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::{
+    zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1,
+    zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1,
+};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+const TEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Linux input event code for the A key.
+const KEY_A: u32 = 30;
+
+struct HeadlessCompositor {
+    child: Child,
+    runtime_dir: PathBuf,
+    socket: PathBuf,
+}
+
+impl HeadlessCompositor {
+    /// Start a headless sway in its own runtime directory, or `None` if sway is not installed.
+    fn spawn(name: &str) -> Option<Self> {
+        let runtime_dir =
+            std::env::temp_dir().join(format!("shaderlock-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&runtime_dir).unwrap();
+        std::fs::set_permissions(&runtime_dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let config = runtime_dir.join("config");
+        std::fs::write(&config, "").unwrap();
+
+        let child = Command::new("sway")
+            .arg("--config")
+            .arg(&config)
+            .env_clear()
+            .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+            .env("XDG_RUNTIME_DIR", &runtime_dir)
+            .env("WLR_BACKENDS", "headless")
+            .env("WLR_RENDERER", "pixman")
+            .env("WLR_LIBINPUT_NO_DEVICES", "1")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+
+        let mut me = Self {
+            child,
+            runtime_dir,
+            socket: PathBuf::new(),
+        };
+        me.socket = me.wait_for_socket();
+        Some(me)
+    }
+
+    fn wait_for_socket(&self) -> PathBuf {
+        let start = std::time::Instant::now();
+        while start.elapsed() < STARTUP_TIMEOUT {
+            let socket = std::fs::read_dir(&self.runtime_dir)
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .find(|p| is_wayland_socket(p));
+            if let Some(socket) = socket {
+                return socket;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        panic!("compositor did not create a socket");
+    }
+
+    fn connect(&self) -> WindowManager {
+        let stream = UnixStream::connect(&self.socket).expect("connect to compositor");
+        let conn = wl::Connection::from_socket(stream).expect("create connection");
+        WindowManager::from_connection(conn).expect("create window manager")
+    }
+}
+
+impl Drop for HeadlessCompositor {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.runtime_dir);
+    }
+}
+
+/// A second client typing through `zwp_virtual_keyboard_v1`, as a physical keyboard would. The
+/// seat only gains a keyboard once this exists, as the headless backend has no devices.
+struct VirtualKeyboard {
+    conn: wl::Connection,
+    keyboard: ZwpVirtualKeyboardV1,
+    // Kept alive for the objects created on it.
+    _queue: wl::EventQueue<VirtualKeyboardState>,
+}
+
+struct VirtualKeyboardState;
+
+impl VirtualKeyboard {
+    fn connect(compositor: &HeadlessCompositor) -> Result<Self> {
+        let stream = UnixStream::connect(&compositor.socket)?;
+        let conn = wl::Connection::from_socket(stream)?;
+        let (globals, queue) = wl::globals::registry_queue_init::<VirtualKeyboardState>(&conn)?;
+        let qh = queue.handle();
+        let seat: wl::protocol::wl_seat::WlSeat = globals.bind(&qh, 1..=1, ())?;
+        let manager: ZwpVirtualKeyboardManagerV1 = globals.bind(&qh, 1..=1, ())?;
+        let keyboard = manager.create_virtual_keyboard(&seat, &qh, ());
+
+        // Keys mean nothing to the compositor until a keymap says what they are.
+        let context = xkbcommon::xkb::Context::new(xkbcommon::xkb::CONTEXT_NO_FLAGS);
+        let keymap = xkbcommon::xkb::Keymap::new_from_names(
+            &context,
+            "",
+            "",
+            "us",
+            "",
+            None,
+            xkbcommon::xkb::KEYMAP_COMPILE_NO_FLAGS,
+        )
+        .context("failed to compile keymap")?;
+        let mut keymap = keymap.get_as_string(xkbcommon::xkb::KEYMAP_FORMAT_TEXT_V1);
+        keymap.push('\0');
+        let keymap_file = compositor.runtime_dir.join("keymap");
+        std::fs::write(&keymap_file, &keymap)?;
+        keyboard.keymap(
+            xkbcommon::xkb::KEYMAP_FORMAT_TEXT_V1,
+            std::fs::File::open(&keymap_file)?.as_fd(),
+            keymap.len() as u32,
+        );
+        conn.flush()?;
+
+        Ok(Self {
+            conn,
+            keyboard,
+            _queue: queue,
+        })
+    }
+
+    /// Press and release a key, by its Linux input event code.
+    fn tap(&self, key: u32) -> Result<()> {
+        self.keyboard.key(0, key, 1);
+        self.keyboard.key(1, key, 0);
+        self.conn.flush()?;
+        Ok(())
+    }
+}
+
+impl wl::Dispatch<wl::protocol::wl_registry::WlRegistry, wl::globals::GlobalListContents>
+    for VirtualKeyboardState
+{
+    fn event(
+        _: &mut Self,
+        _: &wl::protocol::wl_registry::WlRegistry,
+        _: wl::protocol::wl_registry::Event,
+        _: &wl::globals::GlobalListContents,
+        _: &wl::Connection,
+        _: &wl::QueueHandle<Self>,
+    ) {
+    }
+}
+
+wl::delegate_noop!(VirtualKeyboardState: ignore wl::protocol::wl_seat::WlSeat);
+wl::delegate_noop!(VirtualKeyboardState: ZwpVirtualKeyboardManagerV1);
+wl::delegate_noop!(VirtualKeyboardState: ZwpVirtualKeyboardV1);
+
+fn is_wayland_socket(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with("wayland-") && !n.ends_with(".lock"))
+}
+
+#[tokio::test]
+async fn screencopy_handshake() {
+    let compositor = match HeadlessCompositor::spawn("screencopy") {
+        Some(c) => c,
+        None => return eprintln!("sway not found, skipping"),
+    };
+    let mut wm = compositor.connect();
+
+    let run = wm.run(|conn, qh, mut state, _events| async move {
+        let outputs: Vec<_> = state.access(|s| s.output_state.outputs().collect());
+        let output = outputs.first().context("no outputs")?;
+        let frame_handle = state
+            .access(|s| -> Result<_> {
+                let res = s.screencopy_state().capture_output(output, qh)?;
+                conn.flush()?;
+                Ok(res)
+            })?
+            .await??;
+        let frame = state.access(|s| s.get_buffer_data(frame_handle));
+
+        ensure!(frame.width() > 0 && frame.height() > 0, "empty frame");
+        ensure!(
+            frame.bytes().len() >= (frame.stride() * frame.height()) as usize,
+            "frame smaller than its stride"
+        );
+        Ok(())
+    });
+    tokio::time::timeout(TEST_TIMEOUT, run)
+        .await
+        .expect("timed out")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn lock_configure_type_unlock() {
+    let compositor = match HeadlessCompositor::spawn("lock") {
+        Some(c) => c,
+        None => return eprintln!("sway not found, skipping"),
+    };
+    let mut wm = compositor.connect();
+    let compositor = &compositor;
+
+    let run = wm.run(|conn, qh, mut state, events| async move {
+        let session_lock = state.access(|s| s.session_lock_state.lock(qh))?;
+        let mut lock_surfaces = vec![];
+        let mut keyboards = vec![];
+        let mut virtual_keyboard = None;
+        let mut seen = vec![];
+
+        loop {
+            let event = events.next().await.context("events stream was closed")?;
+            match event {
+                Event::NewOutput(output) => {
+                    seen.push("NewOutput");
+                    lock_surfaces.push(state.access(|s| {
+                        let surface = s.compositor_state.create_surface(qh);
+                        session_lock.create_lock_surface(surface, &output, qh)
+                    }));
+                    conn.flush()?;
+                }
+                Event::ConfigureLockSurface(lock_surface, (width, height)) => {
+                    seen.push("ConfigureLockSurface");
+                    // The compositor only considers the session locked once lock surfaces have content.
+                    state.access(|s| -> Result<()> {
+                        let (buffer, canvas) = s.buffer_pool.create_buffer(
+                            width as i32,
+                            height as i32,
+                            width as i32 * 4,
+                            Format::Argb8888,
+                        )?;
+                        canvas.fill(0);
+                        buffer.attach_to(lock_surface.wl_surface())?;
+                        lock_surface.wl_surface().commit();
+                        Ok(())
+                    })?;
+                    conn.flush()?;
+                }
+                Event::SessionLocked => {
+                    seen.push("SessionLocked");
+                    virtual_keyboard = Some(VirtualKeyboard::connect(compositor)?);
+                }
+                Event::NewSeatCapability(seat, sctk::seat::Capability::Keyboard) => {
+                    seen.push("NewSeatCapability");
+                    keyboards.push(state.access(|s| s.seat_state.get_keyboard(qh, &seat, None))?);
+                    conn.flush()?;
+                }
+                // Typed only once focused, else the keys would go nowhere.
+                Event::KeyboardEnter(_) => {
+                    seen.push("KeyboardEnter");
+                    virtual_keyboard
+                        .as_ref()
+                        .context("keyboard focus before the virtual keyboard")?
+                        .tap(KEY_A)?;
+                }
+                Event::KeyPressed(key_event) => {
+                    seen.push("KeyPressed");
+                    ensure!(
+                        key_event.utf8.as_deref() == Some("a"),
+                        "typed the wrong key: {:?}",
+                        key_event
+                    );
+                    session_lock.unlock();
+                    conn.display().sync(qh, ExitSync);
+                    conn.flush()?;
+                }
+                Event::SessionLockFinished => bail!("session lock failed"),
+                Event::ExitSync => {
+                    seen.push("ExitSync");
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        ensure!(
+            seen.first() == Some(&"NewOutput"),
+            "outputs were not announced first: {:?}",
+            seen
+        );
+        for expected in [
+            "ConfigureLockSurface",
+            "SessionLocked",
+            "NewSeatCapability",
+            "KeyboardEnter",
+            "KeyPressed",
+        ] {
+            ensure!(seen.contains(&expected), "missing {}: {:?}", expected, seen);
+        }
+        ensure!(
+            lock_surfaces.iter().all(|s| s.wl_surface().is_alive()),
+            "lock surface destroyed before unlock"
+        );
+        Ok(())
+    });
+    tokio::time::timeout(TEST_TIMEOUT, run)
+        .await
+        .expect("timed out")
+        .unwrap();
+}